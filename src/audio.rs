@@ -1,173 +1,474 @@
-use rodio::{Decoder, OutputStream, Sink, Source};
-use std::fs::File;
-use std::io::BufReader;
+use crate::chapters::{self, Chapter};
+use crate::decoder::SymphoniaSource;
+use crate::eq::{EqControl, Equalizer};
+use crate::meter::{Meter, MeterLevels};
+use crate::replaygain::{ReplayGain, ReplayGainMode};
+use crate::stretch::{Stretcher, MAX_SPEED, MIN_SPEED};
+use crate::output::{Output, RodioOutput};
+use crate::visualizer::{SampleTap, Tap};
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
+use std::time::Duration;
+
+/// How often (in frames) the audio thread looks at the crossfade settings.
+const CHECK_FRAMES: u64 = 1024;
+
+/// Going back a chapter within this long of its start skips to the one before.
+const CHAPTER_RESTART: Duration = Duration::from_secs(3);
+
+/// Shortest A-B loop allowed.
+const MIN_LOOP: Duration = Duration::from_millis(100);
+
+/// A decoded track, either playing or waiting to be spliced onto the end
+/// of the current one.
+struct LoadedTrack {
+    path: String,
+    duration: Option<Duration>,
+    replaygain: ReplayGain,
+    /// Linear ReplayGain factor for the active mode.
+    gain: f32,
+    source: SymphoniaSource,
+    stretch: Stretcher,
+    chapters: Arc<Vec<Chapter>>,
+}
+
+impl LoadedTrack {
+    /// Next sample at the given playback speed.
+    fn next_sample(&mut self, speed: f32, keep_pitch: bool) -> Option<f32> {
+        self.stretch.next(&mut self.source, speed, keep_pitch)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    EqualPower,
+}
+
+impl FadeCurve {
+    /// Gains for the outgoing and incoming track at `t` (0.0..=1.0) into the fade.
+    fn gains(self, t: f32) -> (f32, f32) {
+        match self {
+            FadeCurve::Linear => (1.0 - t, t),
+            FadeCurve::EqualPower => {
+                let angle = t * std::f32::consts::FRAC_PI_2;
+                (angle.cos(), angle.sin())
+            }
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FadeCurve::Linear => "Linear",
+            FadeCurve::EqualPower => "Equal power",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CrossfadeSettings {
+    length: Duration,
+    curve: FadeCurve,
+}
 
 pub struct AudioEngine {
-    _stream: OutputStream,
-    sink: Arc<Mutex<Sink>>,
-    start_time: Arc<Mutex<Option<Instant>>>,
+    output: Box<dyn Output>,
     duration: Arc<Mutex<Option<Duration>>>,
-    paused_elapsed: Arc<Mutex<Duration>>,
     current_file: Arc<Mutex<Option<String>>>,
-    seek_offset: Arc<Mutex<Duration>>,
+    chapters: Arc<Mutex<Arc<Vec<Chapter>>>>,
+    clock: Arc<PlaybackClock>,
+    seek_to: Arc<Mutex<Option<Duration>>>,
+    seek_requested: Arc<AtomicBool>,
+    next_track: Arc<Mutex<Option<LoadedTrack>>>,
+    next_path: Arc<Mutex<Option<String>>>,
+    track_changed: Arc<Mutex<Option<(String, Duration)>>>,
+    crossfade: Arc<Mutex<CrossfadeSettings>>,
+    fade_to: Arc<Mutex<Option<LoadedTrack>>>,
+    fade_requested: Arc<AtomicBool>,
+    replaygain: Arc<Mutex<ReplayGainMode>>,
+    eq: Arc<EqControl>,
+    /// Playback speed as `f32` bits, so the audio thread can read it cheaply.
+    speed: Arc<AtomicU32>,
+    keep_pitch: Arc<AtomicBool>,
+    ab_loop: Arc<Mutex<Option<(Duration, Duration)>>>,
+    tap: Arc<SampleTap>,
+    /// Volume as `f32` bits; applied in the engine so the meters see it.
+    volume: Arc<AtomicU32>,
+    limiter: Arc<AtomicBool>,
+    levels: Arc<MeterLevels>,
+    /// Pausing is done in the engine, so it can fade rather than cut.
+    paused: Arc<AtomicBool>,
+    /// Length of the pause, resume and stop fades, in milliseconds.
+    transport_fade_ms: Arc<AtomicU32>,
+    /// Set to fade out and end the queue that is playing now.
+    stopping: Mutex<Arc<AtomicBool>>,
 }
 
 impl AudioEngine {
-    pub fn new() -> Result<Self, String> {
-        let (stream, handle) = OutputStream::try_default()
-            .map_err(|e| format!("Failed to create audio stream: {}", e))?;
-        let sink = Sink::try_new(&handle)
-            .map_err(|e| format!("Failed to create sink: {}", e))?;
-        
-        Ok(Self {
-            _stream: stream,
-            sink: Arc::new(Mutex::new(sink)),
-            start_time: Arc::new(Mutex::new(None)),
+    /// Opens the named output device, or the system default when `None`.
+    pub fn new(device: Option<&str>) -> Result<Self, String> {
+        Ok(Self::with_output(Box::new(RodioOutput::open(device)?)))
+    }
+
+    /// Plays through any output, such as a null or WAV file output.
+    pub fn with_output(output: Box<dyn Output>) -> Self {
+        Self {
+            output,
             duration: Arc::new(Mutex::new(None)),
-            paused_elapsed: Arc::new(Mutex::new(Duration::ZERO)),
             current_file: Arc::new(Mutex::new(None)),
-            seek_offset: Arc::new(Mutex::new(Duration::ZERO)),
+            chapters: Arc::new(Mutex::new(Arc::new(Vec::new()))),
+            clock: Arc::new(PlaybackClock::new()),
+            seek_to: Arc::new(Mutex::new(None)),
+            seek_requested: Arc::new(AtomicBool::new(false)),
+            next_track: Arc::new(Mutex::new(None)),
+            next_path: Arc::new(Mutex::new(None)),
+            track_changed: Arc::new(Mutex::new(None)),
+            crossfade: Arc::new(Mutex::new(CrossfadeSettings {
+                length: Duration::ZERO,
+                curve: FadeCurve::Linear,
+            })),
+            fade_to: Arc::new(Mutex::new(None)),
+            fade_requested: Arc::new(AtomicBool::new(false)),
+            replaygain: Arc::new(Mutex::new(ReplayGainMode::Off)),
+            eq: Arc::new(EqControl::default()),
+            speed: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            keep_pitch: Arc::new(AtomicBool::new(true)),
+            ab_loop: Arc::new(Mutex::new(None)),
+            tap: Arc::new(SampleTap::default()),
+            volume: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            limiter: Arc::new(AtomicBool::new(false)),
+            levels: Arc::new(MeterLevels::default()),
+            paused: Arc::new(AtomicBool::new(false)),
+            transport_fade_ms: Arc::new(AtomicU32::new(0)),
+            stopping: Mutex::new(Arc::new(AtomicBool::new(false))),
+        }
+    }
+
+    /// Moves playback to another output device. The current track is
+    /// reopened at the same position, keeping its pause state and volume.
+    pub fn set_output_device(&mut self, device: Option<&str>) -> Result<(), String> {
+        self.set_output(Box::new(RodioOutput::open(device)?))
+    }
+
+    /// Moves playback to another output, see `set_output_device`.
+    pub fn set_output(&mut self, output: Box<dyn Output>) -> Result<(), String> {
+        let position = self.get_position();
+        let listened = self.clock.listened.load(Ordering::SeqCst);
+        // A track fading out on stop is as good as finished
+        let finished = self.output.empty() || self.stopping.lock().unwrap().load(Ordering::SeqCst);
+
+        // Dropping the old output stops it and everything queued on it
+        self.output = output;
+
+        let path = self.current_file.lock().unwrap().clone();
+        let Some(path) = path.filter(|_| !finished) else {
+            return Ok(());
+        };
+        let mut track = self.open_track(&path)?;
+        let resumed = track.source.seek(position).unwrap_or(Duration::ZERO);
+        self.seek_requested.store(false, Ordering::SeqCst);
+        *self.fade_to.lock().unwrap() = None;
+
+        let queue = self.track_queue(track);
+        self.clock.seeked(resumed);
+        self.clock.listened.store(listened, Ordering::SeqCst);
+        self.output.append(Box::new(queue));
+        Ok(())
+    }
+
+    fn open_track(&self, path: &str) -> Result<LoadedTrack, String> {
+        let source = SymphoniaSource::open(path)?;
+        let replaygain = ReplayGain::read(path);
+        Ok(LoadedTrack {
+            path: path.to_string(),
+            duration: source.duration(),
+            gain: replaygain.factor(*self.replaygain.lock().unwrap()),
+            replaygain,
+            source,
+            stretch: Stretcher::new(),
+            chapters: Arc::new(chapters::read(path)),
         })
     }
 
+    /// Wraps a source so that any queued track is played straight after it,
+    /// runs it through the equalizer, taps it for the visualizer, counts
+    /// every sample the output pulls, fading on pause, resume and stop, and
+    /// last applies the volume and limiter while metering, so the meters
+    /// show the fades too.
+    fn track_queue(&self, track: LoadedTrack) -> Meter<Transport<Tap<Equalizer<TrackQueue>>>> {
+        self.clock.start_track(track.source.sample_rate(), track.source.channels());
+        let stopping = Arc::new(AtomicBool::new(false));
+        *self.stopping.lock().unwrap() = stopping.clone();
+        let queue = TrackQueue {
+            current: track,
+            since_check: 0,
+            fade: None,
+            active_loop: None,
+            loop_restarted: false,
+            speed: f32::from_bits(self.speed.load(Ordering::SeqCst)),
+            keep_pitch: self.keep_pitch.load(Ordering::SeqCst),
+            clock: self.clock.clone(),
+            seek_to: self.seek_to.clone(),
+            seek_requested: self.seek_requested.clone(),
+            crossfade: self.crossfade.clone(),
+            fade_to: self.fade_to.clone(),
+            fade_requested: self.fade_requested.clone(),
+            replaygain: self.replaygain.clone(),
+            speed_control: self.speed.clone(),
+            keep_pitch_control: self.keep_pitch.clone(),
+            ab_loop: self.ab_loop.clone(),
+            next_track: self.next_track.clone(),
+            next_path: self.next_path.clone(),
+            track_changed: self.track_changed.clone(),
+            current_file: self.current_file.clone(),
+            chapters: self.chapters.clone(),
+            duration: self.duration.clone(),
+            stopping: stopping.clone(),
+        };
+        let transport = Transport {
+            inner: Tap::new(Equalizer::new(queue, self.eq.clone()), self.tap.clone()),
+            clock: self.clock.clone(),
+            paused: self.paused.clone(),
+            stopping,
+            fade_ms: self.transport_fade_ms.clone(),
+            gain: 0.0,
+            frame_pos: 0,
+            silent: false,
+        };
+        Meter::new(transport, self.volume.clone(), self.limiter.clone(), self.levels.clone())
+    }
+
     pub fn play(&self, path: &str) -> Result<(), String> {
-        let duration = Self::get_file_duration(path);
+        let track = self.open_track(path)?;
         
         *self.current_file.lock().unwrap() = Some(path.to_string());
-        *self.seek_offset.lock().unwrap() = Duration::ZERO;
+        *self.duration.lock().unwrap() = track.duration;
+        *self.chapters.lock().unwrap() = track.chapters.clone();
+        *self.ab_loop.lock().unwrap() = None;
+        self.seek_requested.store(false, Ordering::SeqCst);
         
-        let file = File::open(path)
-            .map_err(|e| format!("Failed to open file: {}", e))?;
-        let source = Decoder::new(BufReader::new(file))
-            .map_err(|e| format!("Failed to decode audio: {}", e))?;
-        
-        let sink = self.sink.lock().unwrap();
-        sink.append(source);
-        sink.play();
-        drop(sink);
-        
-        *self.start_time.lock().unwrap() = Some(Instant::now());
-        *self.duration.lock().unwrap() = duration;
-        *self.paused_elapsed.lock().unwrap() = Duration::ZERO;
+        self.output.append(Box::new(self.track_queue(track)));
+        self.paused.store(false, Ordering::SeqCst);
         Ok(())
     }
 
     pub fn seek_forward(&self, seconds: u64) {
-        let seek_amount = Duration::from_secs(seconds);
         let current_pos = self.get_position();
-        let duration = self.duration.lock().unwrap();
-        
-        if let Some(dur) = *duration {
-            let new_offset = (current_pos + seek_amount).min(dur);
-            *self.seek_offset.lock().unwrap() = new_offset;
-            drop(duration);
-            self.restart_at_offset();
+        if let Some(dur) = self.get_duration() {
+            self.seek(current_pos + Duration::from_secs(seconds), dur);
         }
     }
 
     pub fn seek_backward(&self, seconds: u64) {
-        let seek_amount = Duration::from_secs(seconds);
         let current_pos = self.get_position();
-        let new_offset = current_pos.saturating_sub(seek_amount);
-        *self.seek_offset.lock().unwrap() = new_offset;
-        self.restart_at_offset();
+        let target = current_pos.saturating_sub(Duration::from_secs(seconds));
+        self.seek(target, target);
     }
 
-    fn restart_at_offset(&self) {
-        let current_file = self.current_file.lock().unwrap().clone();
-        let offset = *self.seek_offset.lock().unwrap();
-        
-        if let Some(path) = current_file {
-            // Stop current playback
-            self.sink.lock().unwrap().stop();
-            
-            // Restart from offset
-            if let Ok(file) = File::open(&path) {
-                if let Ok(decoder) = Decoder::new(BufReader::new(file)) {
-                    let sink = self.sink.lock().unwrap();
-                    
-                    if offset == Duration::ZERO {
-                        sink.append(decoder);
-                        *self.paused_elapsed.lock().unwrap() = Duration::ZERO;
-                    } else {
-                        // Skip to offset using Source trait
-                        let source = decoder.skip_duration(offset);
-                        sink.append(source);
-                        *self.paused_elapsed.lock().unwrap() = offset;
-                    }
-                    
-                    sink.play();
-                    drop(sink);
-                    
-                    *self.start_time.lock().unwrap() = Some(Instant::now());
-                }
-            }
+    /// Jumps to `position` in the current track.
+    pub fn seek_to(&self, position: Duration) {
+        self.seek(position, self.get_duration().unwrap_or(position));
+    }
+
+    /// Asks the audio thread to seek the current track. The reported
+    /// position jumps straight away, and is corrected to the exact spot
+    /// the decoder lands on once the seek is done.
+    fn seek(&self, target: Duration, limit: Duration) {
+        let target = target.min(limit);
+        *self.seek_to.lock().unwrap() = Some(target);
+        self.clock.seeked(target);
+        self.seek_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Loops the current track between `a` and `b` until cleared or the
+    /// track changes. The jump back is sample-accurate, with a fade of a few
+    /// milliseconds either side so it doesn't click.
+    pub fn set_ab_loop(&self, a: Duration, b: Duration) -> Result<(), String> {
+        let (a, b) = (a.min(b), a.max(b));
+        if b - a < MIN_LOOP {
+            return Err("Loop is too short".to_string());
         }
+        *self.ab_loop.lock().unwrap() = Some((a, b));
+        Ok(())
     }
 
-    fn get_file_duration(path: &str) -> Option<Duration> {
-        let file = File::open(path).ok()?;
-        let mss = MediaSourceStream::new(Box::new(file), Default::default());
-        
-        let mut hint = Hint::new();
-        if let Some(ext) = std::path::Path::new(path).extension() {
-            hint.with_extension(ext.to_str()?);
+    pub fn clear_ab_loop(&self) {
+        *self.ab_loop.lock().unwrap() = None;
+    }
+
+    pub fn ab_loop(&self) -> Option<(Duration, Duration)> {
+        *self.ab_loop.lock().unwrap()
+    }
+
+    /// Chapter markers of the playing track, sorted by start.
+    pub fn chapters(&self) -> Arc<Vec<Chapter>> {
+        self.chapters.lock().unwrap().clone()
+    }
+
+    /// Index of the chapter playing now, if the track has chapters.
+    pub fn current_chapter(&self) -> Option<usize> {
+        let position = self.get_position();
+        let chapters = self.chapters();
+        match chapters.iter().rposition(|c| c.start <= position) {
+            None if !chapters.is_empty() => Some(0),
+            index => index,
         }
-        
-        let format_opts = FormatOptions::default();
-        let metadata_opts = MetadataOptions::default();
-        
-        let probed = symphonia::default::get_probe()
-            .format(&hint, mss, &format_opts, &metadata_opts)
-            .ok()?;
-        
-        let track = probed.format.default_track()?;
-        let time_base = track.codec_params.time_base?;
-        let n_frames = track.codec_params.n_frames?;
-        
-        let seconds = time_base.calc_time(n_frames).seconds;
-        Some(Duration::from_secs(seconds))
     }
 
+    /// Jumps to the start of the next chapter. Returns false if there is none.
+    pub fn next_chapter(&self) -> bool {
+        let chapters = self.chapters();
+        let Some(next) = self.current_chapter().and_then(|i| chapters.get(i + 1)) else {
+            return false;
+        };
+        self.seek(next.start, next.start);
+        true
+    }
+
+    /// Jumps back to the start of the current chapter, or to the previous
+    /// chapter when already near the start. Returns false without chapters.
+    pub fn previous_chapter(&self) -> bool {
+        let chapters = self.chapters();
+        let Some(current) = self.current_chapter() else {
+            return false;
+        };
+        let into_chapter = self.get_position().saturating_sub(chapters[current].start);
+        let index = if into_chapter < CHAPTER_RESTART { current.saturating_sub(1) } else { current };
+        let start = chapters[index].start;
+        self.seek(start, start);
+        true
+    }
+
+    /// Pre-decodes `path` so it starts on the exact sample the current track
+    /// ends. Re-queueing the track that is already waiting is a no-op.
+    pub fn queue_next(&self, path: &str) -> Result<(), String> {
+        if self.next_path.lock().unwrap().as_deref() == Some(path) {
+            return Ok(());
+        }
+        *self.next_track.lock().unwrap() = Some(self.open_track(path)?);
+        *self.next_path.lock().unwrap() = Some(path.to_string());
+        Ok(())
+    }
+
+    /// Sets the overlap between consecutive tracks. A zero length keeps
+    /// playback gapless without any fade.
+    pub fn set_crossfade(&self, length: Duration, curve: FadeCurve) {
+        *self.crossfade.lock().unwrap() = CrossfadeSettings { length, curve };
+    }
+
+    /// Sets the ReplayGain mode; `Auto` must already be resolved against
+    /// the shuffle state. Takes effect on the playing track as well.
+    pub fn set_replaygain(&self, mode: ReplayGainMode) {
+        *self.replaygain.lock().unwrap() = mode;
+    }
+
+    /// Sets the playback speed, clamped to 0.5x-3x. With `keep_pitch` the
+    /// audio is time-stretched, otherwise it is simply played faster or
+    /// slower. Position keeps counting in track time either way.
+    pub fn set_speed(&self, speed: f32, keep_pitch: bool) {
+        let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.speed.store(speed.to_bits(), Ordering::SeqCst);
+        self.keep_pitch.store(keep_pitch, Ordering::SeqCst);
+    }
+
+    pub fn get_speed(&self) -> f32 {
+        f32::from_bits(self.speed.load(Ordering::SeqCst))
+    }
+
+    /// Keeps peaks under full scale by turning loud passages down, so
+    /// volumes over 100% don't clip.
+    pub fn set_limiter(&self, enabled: bool) {
+        self.limiter.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Output levels after volume and limiter, for the meters.
+    pub fn levels(&self) -> Arc<MeterLevels> {
+        self.levels.clone()
+    }
+
+    /// What is being played, for the visualizer.
+    pub fn sample_tap(&self) -> Arc<SampleTap> {
+        self.tap.clone()
+    }
+
+    /// Updates the equalizer; changes are heard within one frame.
+    pub fn set_equalizer(&self, enabled: bool, gains: &[f32]) {
+        self.eq.set(enabled, gains);
+    }
+
+    pub fn crossfade_enabled(&self) -> bool {
+        !self.crossfade.lock().unwrap().length.is_zero()
+    }
+
+    /// Fades from whatever is playing into `path` right away, using the
+    /// crossfade settings. Unlike the queued track this is not reported
+    /// through `take_track_change`, since the caller chose the track.
+    pub fn crossfade_to(&self, path: &str) -> Result<(), String> {
+        *self.fade_to.lock().unwrap() = Some(self.open_track(path)?);
+        self.fade_requested.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn clear_next(&self) {
+        *self.next_track.lock().unwrap() = None;
+        *self.next_path.lock().unwrap() = None;
+    }
+
+    /// Returns the path of the queued track if playback has moved on to it
+    /// since the last call, along with how long the previous track was
+    /// actually listened to.
+    pub fn take_track_change(&self) -> Option<(String, Duration)> {
+        self.track_changed.lock().unwrap().take()
+    }
+
+    /// Fades out and holds; the call itself returns straight away.
     pub fn pause(&self) {
-        let elapsed = self.get_position();
-        *self.paused_elapsed.lock().unwrap() = elapsed;
-        *self.start_time.lock().unwrap() = None;
-        self.sink.lock().unwrap().pause();
+        self.paused.store(true, Ordering::SeqCst);
     }
 
     pub fn resume(&self) {
-        *self.start_time.lock().unwrap() = Some(Instant::now());
-        self.sink.lock().unwrap().play();
+        self.paused.store(false, Ordering::SeqCst);
     }
 
     pub fn is_paused(&self) -> bool {
-        self.sink.lock().unwrap().is_paused()
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Length of the fades on pause, resume and stop (and so on skipping
+    /// to another track); zero cuts straight away.
+    pub fn set_transport_fade(&self, length: Duration) {
+        self.transport_fade_ms.store(length.as_millis() as u32, Ordering::SeqCst);
     }
 
+    /// Fades the playing track out and drops it. Anything played straight
+    /// after starts once the fade is over.
     pub fn stop(&self) {
-        self.sink.lock().unwrap().stop();
-        *self.start_time.lock().unwrap() = None;
+        self.clear_next();
+        *self.fade_to.lock().unwrap() = None;
+        self.fade_requested.store(false, Ordering::SeqCst);
+        self.seek_requested.store(false, Ordering::SeqCst);
+        self.stopping.lock().unwrap().store(true, Ordering::SeqCst);
         *self.duration.lock().unwrap() = None;
-        *self.paused_elapsed.lock().unwrap() = Duration::ZERO;
+        *self.chapters.lock().unwrap() = Arc::new(Vec::new());
+        *self.ab_loop.lock().unwrap() = None;
+        self.clock.start_track(0, 0);
     }
 
     pub fn set_volume(&self, volume: f32) {
-        self.sink.lock().unwrap().set_volume(volume);
+        self.volume.store(volume.to_bits(), Ordering::SeqCst);
     }
 
     pub fn get_position(&self) -> Duration {
-        if let Some(start) = *self.start_time.lock().unwrap() {
-            *self.paused_elapsed.lock().unwrap() + start.elapsed()
-        } else {
-            *self.paused_elapsed.lock().unwrap()
-        }
+        self.clock.position()
+    }
+
+    /// Time the current track has actually been heard for, not counting
+    /// pauses or the stretches skipped over by seeking.
+    pub fn get_listened(&self) -> Duration {
+        self.clock.listened()
     }
 
     pub fn get_duration(&self) -> Option<Duration> {
@@ -175,6 +476,481 @@ impl AudioEngine {
     }
 
     pub fn is_finished(&self) -> bool {
-        self.sink.lock().unwrap().empty()
+        self.output.empty()
+    }
+}
+
+/// The incoming side of a crossfade, already converted to the outgoing
+/// track's sample rate and channel count.
+struct Fade {
+    incoming: LoadedTrack,
+    curve: FadeCurve,
+    position: u64,
+    length: u64,
+}
+
+/// Plays the current source and, once it runs dry, carries straight on with
+/// the queued track. Position bookkeeping is switched over from inside the
+/// audio thread so it lines up with the first sample of the new track.
+/// With crossfading enabled the queued track is started early and mixed
+/// over the tail of the current one. Each track's ReplayGain is applied
+/// here, before the volume.
+struct TrackQueue {
+    current: LoadedTrack,
+    /// Samples handed out since the last housekeeping check.
+    since_check: u64,
+    fade: Option<Fade>,
+    /// The A-B loop the current source has been set up for.
+    active_loop: Option<(Duration, Duration)>,
+    /// Set on jumping back to A, cleared once that yields a sample, so a
+    /// loop that produces nothing can't spin forever.
+    loop_restarted: bool,
+    /// Speed settings as of the last housekeeping check.
+    speed: f32,
+    keep_pitch: bool,
+    clock: Arc<PlaybackClock>,
+    seek_to: Arc<Mutex<Option<Duration>>>,
+    seek_requested: Arc<AtomicBool>,
+    crossfade: Arc<Mutex<CrossfadeSettings>>,
+    fade_to: Arc<Mutex<Option<LoadedTrack>>>,
+    fade_requested: Arc<AtomicBool>,
+    replaygain: Arc<Mutex<ReplayGainMode>>,
+    speed_control: Arc<AtomicU32>,
+    keep_pitch_control: Arc<AtomicBool>,
+    ab_loop: Arc<Mutex<Option<(Duration, Duration)>>>,
+    next_track: Arc<Mutex<Option<LoadedTrack>>>,
+    next_path: Arc<Mutex<Option<String>>>,
+    track_changed: Arc<Mutex<Option<(String, Duration)>>>,
+    current_file: Arc<Mutex<Option<String>>>,
+    chapters: Arc<Mutex<Arc<Vec<Chapter>>>>,
+    duration: Arc<Mutex<Option<Duration>>>,
+    /// Once set, this queue is only fading out; the engine's shared state
+    /// belongs to whatever plays next, so it is left alone.
+    stopping: Arc<AtomicBool>,
+}
+
+impl TrackQueue {
+    /// Takes the track that should follow the current one: a manual
+    /// crossfade target first, otherwise the gapless queue.
+    fn take_next(&mut self, manual: bool) -> Option<LoadedTrack> {
+        if manual {
+            return self.fade_to.lock().unwrap().take();
+        }
+        let next = self.next_track.lock().unwrap().take()?;
+        *self.next_path.lock().unwrap() = None;
+        Some(next)
+    }
+
+    fn switch_bookkeeping(&mut self, path: String, duration: Option<Duration>, chapters: Arc<Vec<Chapter>>, manual: bool) {
+        *self.current_file.lock().unwrap() = Some(path.clone());
+        *self.duration.lock().unwrap() = duration;
+        *self.chapters.lock().unwrap() = chapters;
+        // A loop belongs to the track it was set on
+        *self.ab_loop.lock().unwrap() = None;
+        self.active_loop = None;
+        let listened = self.clock.start_track(self.sample_rate(), self.channels());
+        if !manual {
+            *self.track_changed.lock().unwrap() = Some((path, listened));
+        }
+    }
+
+    fn advance(&mut self) -> bool {
+        let Some(next) = self.take_next(false) else {
+            return false;
+        };
+        let (path, duration, chapters) = (next.path.clone(), next.duration, next.chapters.clone());
+        self.current = next;
+        self.since_check = 0;
+        self.switch_bookkeeping(path, duration, chapters, false);
+        true
+    }
+
+    /// Picks up the current track going back to its own format after a
+    /// crossfade, once the stretcher has played out what it had buffered.
+    fn restored_format(&mut self) -> bool {
+        if !self.current.source.take_format_change() {
+            return false;
+        }
+        self.current.stretch.reset();
+        self.clock.set_format(self.current.source.sample_rate(), self.current.source.channels());
+        self.since_check = 0;
+        true
+    }
+
+    /// Ends a running fade early, leaving only the incoming track.
+    fn finish_fade(&mut self) {
+        if let Some(fade) = self.fade.take() {
+            self.current = fade.incoming;
+        }
+    }
+
+    fn apply_seek(&mut self) {
+        let Some(target) = self.seek_to.lock().unwrap().take() else {
+            return;
+        };
+        self.finish_fade();
+        self.current.stretch.reset();
+        if let Ok(actual) = self.current.source.seek(target) {
+            self.clock.seeked(actual);
+        }
+    }
+
+    /// Picks up speed changes, and tells the clock how much track time each
+    /// played sample now stands for.
+    fn update_speed(&mut self) {
+        self.speed = f32::from_bits(self.speed_control.load(Ordering::Relaxed));
+        self.keep_pitch = self.keep_pitch_control.load(Ordering::Relaxed);
+        self.clock.set_speed(self.speed);
+    }
+
+    /// Picks up A-B loop changes.
+    fn update_loop(&mut self) {
+        let wanted = *self.ab_loop.lock().unwrap();
+        if wanted != self.active_loop {
+            self.active_loop = wanted;
+            self.current.source.set_loop_end(wanted.map(|(_, b)| b));
+        }
+    }
+
+    /// Jumps back to A when the current track has stopped at the loop's B.
+    fn restart_loop(&mut self) -> bool {
+        let Some((a, _)) = self.active_loop else {
+            return false;
+        };
+        if self.loop_restarted {
+            return false;
+        }
+        self.current.stretch.reset();
+        match self.current.source.loop_to(a) {
+            Ok(actual) => {
+                self.clock.seeked(actual);
+                self.loop_restarted = true;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Picks up ReplayGain mode changes for the tracks that are playing.
+    fn update_gains(&mut self) {
+        let mode = *self.replaygain.lock().unwrap();
+        self.current.gain = self.current.replaygain.factor(mode);
+        if let Some(fade) = self.fade.as_mut() {
+            fade.incoming.gain = fade.incoming.replaygain.factor(mode);
+        }
+    }
+
+    /// Called on frame boundaries; starts a crossfade if one was requested or
+    /// the current track has entered its fade-out window.
+    fn maybe_start_fade(&mut self) {
+        let manual = self.fade_requested.swap(false, Ordering::SeqCst);
+        let settings = *self.crossfade.lock().unwrap();
+        if !manual && (settings.length.is_zero() || self.fade.is_some() || self.active_loop.is_some()) {
+            return;
+        }
+
+        // The outgoing track has to stay in the format the fade is mixed in
+        self.current.source.keep_format();
+        let rate = self.current.source.sample_rate();
+        let channels = self.current.source.channels();
+        let to_samples = |d: Duration| (d.as_secs_f64() * rate as f64) as u64 * channels as u64;
+
+        let length = if manual {
+            to_samples(settings.length)
+        } else {
+            let Some(total) = self.current.duration else {
+                return;
+            };
+            // Remaining track time, played back at the current speed
+            let remaining = total
+                .saturating_sub(self.current.source.current_position())
+                .div_f32(self.speed);
+            if remaining > settings.length {
+                return;
+            }
+            to_samples(remaining)
+        };
+
+        let Some(mut next) = self.take_next(manual) else {
+            return;
+        };
+
+        // A fade that is still running is cut short by a new one
+        self.finish_fade();
+
+        next.source.set_output_format(channels, rate);
+        next.stretch.reset();
+        self.switch_bookkeeping(next.path.clone(), next.duration, next.chapters.clone(), manual);
+
+        if length == 0 {
+            self.current = next;
+        } else {
+            self.fade = Some(Fade {
+                incoming: next,
+                curve: settings.curve,
+                position: 0,
+                length,
+            });
+        }
+    }
+
+    fn next_faded(&mut self) -> Option<f32> {
+        let fade = self.fade.as_mut()?;
+        let t = fade.position as f32 / fade.length as f32;
+        let (out_gain, in_gain) = fade.curve.gains(t);
+        let outgoing = self.current.next_sample(self.speed, self.keep_pitch).unwrap_or(0.0) * self.current.gain;
+        let incoming = fade.incoming.next_sample(self.speed, self.keep_pitch);
+        fade.position += 1;
+
+        let sample = outgoing * out_gain + incoming.unwrap_or(0.0) * fade.incoming.gain * in_gain;
+        if fade.position >= fade.length || incoming.is_none() {
+            self.finish_fade();
+        }
+        Some(sample)
+    }
+}
+
+impl Iterator for TrackQueue {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.stopping.load(Ordering::Relaxed) {
+            return self.current.next_sample(self.speed, self.keep_pitch).map(|s| s * self.current.gain);
+        }
+        let channels = self.channels().max(1) as u64;
+        let on_frame = match &self.fade {
+            Some(fade) => fade.position.is_multiple_of(channels),
+            None => self.since_check.is_multiple_of(channels),
+        };
+        if on_frame {
+            if self.seek_requested.swap(false, Ordering::SeqCst) {
+                self.apply_seek();
+                self.since_check = 0;
+            }
+            // Only a manual skip can interrupt a running fade
+            if self.fade.is_some() && self.fade_requested.load(Ordering::Relaxed) {
+                self.maybe_start_fade();
+            }
+        }
+        if on_frame && self.since_check >= CHECK_FRAMES * channels {
+            self.since_check = 0;
+            self.update_gains();
+            self.update_speed();
+            self.update_loop();
+            self.maybe_start_fade();
+            // A track converted for a crossfade goes back to its own format
+            if self.fade.is_none() {
+                self.current.source.restore_format();
+            }
+        }
+        self.since_check += 1;
+
+        if self.fade.is_some() {
+            return self.next_faded();
+        }
+
+        loop {
+            if let Some(sample) = self.current.next_sample(self.speed, self.keep_pitch) {
+                self.loop_restarted = false;
+                return Some(sample * self.current.gain);
+            }
+            if self.restored_format() {
+                continue;
+            }
+            if !self.restart_loop() && !self.advance() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Source for TrackQueue {
+    fn current_frame_len(&self) -> Option<usize> {
+        // Report a frame boundary where the current track ends so the mixer
+        // picks up the sample rate and channel count of the next one.
+        if let Some(len) = self.current.stretch.frame_len() {
+            return Some(len);
+        }
+        match self.current.source.current_frame_len() {
+            Some(len) if len > 0 => Some(len),
+            _ => Some(512),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        match self.current.stretch.format() {
+            Some((channels, _)) => channels,
+            None => self.current.source.channels(),
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match self.current.stretch.format() {
+            Some((_, rate)) => rate,
+            None => self.current.source.sample_rate(),
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Counts the samples the output pulls out of the pipeline. Position and
+/// listening time come from here rather than a wall clock, so they stay
+/// right when the device buffers, the system sleeps or decoding stalls.
+/// Samples are weighted by the playback speed, so both are in track time.
+struct PlaybackClock {
+    /// Position at the last track start or seek, in microseconds.
+    base_us: AtomicU64,
+    /// Samples pulled since `base_us`, in thousandths of a sample.
+    samples: AtomicU64,
+    /// Samples pulled since the track started, regardless of seeks.
+    listened: AtomicU64,
+    /// Samples per second of playback (sample rate times channels).
+    rate: AtomicU64,
+    /// Track time each pulled sample stands for, in thousandths.
+    speed_milli: AtomicU64,
+}
+
+impl PlaybackClock {
+    fn new() -> Self {
+        Self {
+            base_us: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+            listened: AtomicU64::new(0),
+            rate: AtomicU64::new(0),
+            speed_milli: AtomicU64::new(1000),
+        }
+    }
+
+    /// Restarts counting for a new track and returns how long the previous
+    /// one was listened to.
+    fn start_track(&self, sample_rate: u32, channels: u16) -> Duration {
+        let listened = self.listened();
+        self.rate.store(sample_rate as u64 * channels as u64, Ordering::SeqCst);
+        self.base_us.store(0, Ordering::SeqCst);
+        self.samples.store(0, Ordering::SeqCst);
+        self.listened.store(0, Ordering::SeqCst);
+        listened
+    }
+
+    /// Carries on counting in another sample format, keeping the position
+    /// and the time listened.
+    fn set_format(&self, sample_rate: u32, channels: u16) {
+        let position = self.position();
+        let listened = self.listened();
+        let rate = sample_rate as u64 * channels as u64;
+        self.rate.store(rate, Ordering::SeqCst);
+        self.base_us.store(position.as_micros() as u64, Ordering::SeqCst);
+        self.samples.store(0, Ordering::SeqCst);
+        self.listened.store((listened.as_micros() * rate as u128 / 1000) as u64, Ordering::SeqCst);
+    }
+
+    fn seeked(&self, position: Duration) {
+        self.base_us.store(position.as_micros() as u64, Ordering::SeqCst);
+        self.samples.store(0, Ordering::SeqCst);
+    }
+
+    fn set_speed(&self, speed: f32) {
+        self.speed_milli.store((speed * 1000.0).round() as u64, Ordering::Relaxed);
+    }
+
+    fn count(&self) {
+        let weight = self.speed_milli.load(Ordering::Relaxed);
+        self.samples.fetch_add(weight, Ordering::Relaxed);
+        self.listened.fetch_add(weight, Ordering::Relaxed);
+    }
+
+    fn to_duration(&self, samples_milli: u64) -> Duration {
+        match self.rate.load(Ordering::SeqCst) {
+            0 => Duration::ZERO,
+            rate => Duration::from_micros((samples_milli as u128 * 1000 / rate as u128) as u64),
+        }
+    }
+
+    fn position(&self) -> Duration {
+        Duration::from_micros(self.base_us.load(Ordering::SeqCst))
+            + self.to_duration(self.samples.load(Ordering::SeqCst))
+    }
+
+    fn listened(&self) -> Duration {
+        self.to_duration(self.listened.load(Ordering::SeqCst))
+    }
+}
+
+/// Ticks the playback clock for each sample the output pulls, and ramps
+/// the gain on pause, resume and stop. While paused it hands out silence
+/// without pulling (or counting) anything; once stopped and faded out it
+/// ends. The fade-out after a stop isn't counted, as the clock has already
+/// moved on to the next track.
+struct Transport<S> {
+    inner: S,
+    clock: Arc<PlaybackClock>,
+    paused: Arc<AtomicBool>,
+    stopping: Arc<AtomicBool>,
+    fade_ms: Arc<AtomicU32>,
+    gain: f32,
+    /// Sample index within the current frame, so silence starts and ends on
+    /// frame boundaries and the channels stay in order.
+    frame_pos: u16,
+    /// Whether the current frame is silence rather than pulled samples.
+    silent: bool,
+}
+
+impl<S: Source<Item = f32>> Iterator for Transport<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let channels = self.inner.channels().max(1);
+        let stopping = self.stopping.load(Ordering::Relaxed);
+        let target = if stopping || self.paused.load(Ordering::Relaxed) { 0.0 } else { 1.0 };
+        if self.frame_pos == 0 {
+            self.silent = self.gain == 0.0 && target == 0.0;
+            if self.silent && stopping {
+                return None;
+            }
+        }
+
+        let sample = if self.silent {
+            0.0
+        } else {
+            let sample = self.inner.next()?;
+            if !stopping {
+                self.clock.count();
+            }
+            sample * self.gain
+        };
+
+        let fade_samples = self.fade_ms.load(Ordering::Relaxed) as f32 / 1000.0
+            * self.inner.sample_rate() as f32
+            * channels as f32;
+        self.gain = if fade_samples < 1.0 {
+            target
+        } else if target > self.gain {
+            (self.gain + 1.0 / fade_samples).min(target)
+        } else {
+            (self.gain - 1.0 / fade_samples).max(target)
+        };
+        self.frame_pos = (self.frame_pos + 1) % channels;
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Transport<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
     }
 }
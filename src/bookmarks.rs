@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// What to do when a file with a saved position is played again.
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum ResumeMode {
+    Off,
+    #[default]
+    Ask,
+    Auto,
+}
+
+impl ResumeMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            ResumeMode::Off => "Off",
+            ResumeMode::Ask => "Ask",
+            ResumeMode::Auto => "Automatically",
+        }
+    }
+
+    pub fn cycle(self, forward: bool) -> Self {
+        const ORDER: [ResumeMode; 3] = [ResumeMode::Off, ResumeMode::Ask, ResumeMode::Auto];
+        let i = ORDER.iter().position(|m| *m == self).unwrap_or(0);
+        let next = if forward { i + 1 } else { i + ORDER.len() - 1 };
+        ORDER[next % ORDER.len()]
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub position_ms: u64,
+}
+
+impl Bookmark {
+    pub fn position(&self) -> Duration {
+        Duration::from_millis(self.position_ms)
+    }
+}
+
+/// Resume positions of long files and the user's named bookmarks, both by
+/// path. Kept in `bookmarks.json` next to the config, since it changes far
+/// more often.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Bookmarks {
+    /// Where each long file was left off, in milliseconds.
+    positions: HashMap<String, u64>,
+    marks: HashMap<String, Vec<Bookmark>>,
+}
+
+impl Bookmarks {
+    pub fn load() -> Self {
+        if let Ok(content) = fs::read_to_string(Self::bookmarks_path()) {
+            serde_json::from_str(&content).unwrap_or_default()
+        } else {
+            Self::default()
+        }
+    }
+
+    pub fn save(&self) {
+        if let Ok(content) = serde_json::to_string_pretty(self) {
+            if let Some(parent) = Self::bookmarks_path().parent() {
+                fs::create_dir_all(parent).ok();
+            }
+            fs::write(Self::bookmarks_path(), content).ok();
+        }
+    }
+
+    fn bookmarks_path() -> PathBuf {
+        let mut path = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+        path.push("rustplayer");
+        path.push("bookmarks.json");
+        path
+    }
+
+    pub fn position(&self, path: &str) -> Option<Duration> {
+        self.positions.get(path).map(|ms| Duration::from_millis(*ms))
+    }
+
+    pub fn set_position(&mut self, path: &str, position: Duration) {
+        self.positions.insert(path.to_string(), position.as_millis() as u64);
+    }
+
+    /// Returns true if there was a position to forget.
+    pub fn forget_position(&mut self, path: &str) -> bool {
+        self.positions.remove(path).is_some()
+    }
+
+    /// Adds a bookmark, keeping each file's bookmarks in position order.
+    pub fn add(&mut self, path: &str, name: String, position: Duration) {
+        let marks = self.marks.entry(path.to_string()).or_default();
+        marks.push(Bookmark { name, position_ms: position.as_millis() as u64 });
+        marks.sort_by_key(|m| m.position_ms);
+    }
+
+    pub fn remove(&mut self, path: &str, position_ms: u64) {
+        if let Some(marks) = self.marks.get_mut(path) {
+            marks.retain(|m| m.position_ms != position_ms);
+            if marks.is_empty() {
+                self.marks.remove(path);
+            }
+        }
+    }
+
+    /// Every bookmark with its file: those in `current` first, then the
+    /// rest by path.
+    pub fn list(&self, current: Option<&str>) -> Vec<(String, Bookmark)> {
+        let mut paths: Vec<&String> = self.marks.keys().collect();
+        paths.sort_by_key(|p| (Some(p.as_str()) != current, p.to_lowercase()));
+        paths
+            .into_iter()
+            .flat_map(|path| self.marks[path].iter().map(move |m| (path.clone(), m.clone())))
+            .collect()
+    }
+}
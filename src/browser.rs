@@ -1,6 +1,10 @@
+use crate::cue;
+use crate::playlist;
+use crate::formats;
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::Sender;
 
 #[derive(Clone)]
 pub struct FileEntry {
@@ -17,6 +21,12 @@ pub struct FileBrowser {
     selected: usize,
 }
 
+impl Default for FileBrowser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FileBrowser {
     pub fn new() -> Self {
         let current_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
@@ -77,10 +87,8 @@ impl FileBrowser {
                     });
                 }
                 
-                // Check file extension
-                let extension = path.extension()?.to_str()?.to_lowercase();
-                let is_audio = matches!(extension.as_str(), "mp3" | "flac" | "wav" | "ogg");
-                let is_playlist = extension == "m3u";
+                let is_audio = formats::is_audio_file(&path);
+                let is_playlist = playlist::is_playlist_file(&path);
 
                 if is_audio || is_playlist {
                     Some(FileEntry {
@@ -143,11 +151,13 @@ impl FileBrowser {
         }
     }
 
-    pub fn scan_audio_files_streaming(dir: PathBuf, sender: Sender<PathBuf>) {
-        Self::collect_audio_files_streaming(&dir, 0, &sender, 0);
+    /// Sends every audio file under `dir`. With `expand_cue`, a file that a
+    /// CUE sheet next to it splits up is sent as the sheet's tracks instead.
+    pub fn scan_audio_files_streaming(dir: PathBuf, sender: Sender<PathBuf>, expand_cue: bool) {
+        Self::collect_audio_files_streaming(&dir, 0, &sender, 0, expand_cue);
     }
 
-    fn collect_audio_files_streaming(dir: &Path, depth: usize, sender: &Sender<PathBuf>, file_count: usize) -> usize {
+    fn collect_audio_files_streaming(dir: &Path, depth: usize, sender: &Sender<PathBuf>, file_count: usize, expand_cue: bool) -> usize {
         // Limit recursion depth and total files
         if depth > 8 || file_count > 5000 {
             return file_count;
@@ -158,10 +168,26 @@ impl FileBrowser {
         let Ok(entries) = fs::read_dir(dir) else {
             return count;
         };
+        let paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
 
-        for entry in entries.filter_map(|e| e.ok()) {
-            let path = entry.path();
-            
+        let mut covered = HashSet::new();
+        if expand_cue {
+            for path in paths.iter().filter(|p| cue::is_cue_file(p)) {
+                let cue_path = path.to_string_lossy();
+                let Ok(sheet) = cue::load(&cue_path) else {
+                    continue;
+                };
+                covered.extend(sheet.tracks.iter().map(|t| PathBuf::from(&t.file)));
+                for track in &sheet.tracks {
+                    if sender.send(PathBuf::from(cue::track_ref(&cue_path, track.number))).is_err() {
+                        return count;
+                    }
+                    count += 1;
+                }
+            }
+        }
+
+        for path in paths {
             // Skip hidden files/folders
             if let Some(name) = path.file_name() {
                 if name.to_string_lossy().starts_with('.') {
@@ -170,14 +196,12 @@ impl FileBrowser {
             }
             
             if path.is_dir() {
-                count = Self::collect_audio_files_streaming(&path, depth + 1, sender, count);
-            } else if let Some(ext) = path.extension() {
-                if matches!(ext.to_str(), Some("mp3" | "flac" | "wav" | "ogg")) {
-                    if sender.send(path).is_err() {
-                        return count; // Channel closed, stop scanning
-                    }
-                    count += 1;
+                count = Self::collect_audio_files_streaming(&path, depth + 1, sender, count, expand_cue);
+            } else if formats::is_audio_file(&path) && !covered.contains(&path) {
+                if sender.send(path).is_err() {
+                    return count; // Channel closed, stop scanning
                 }
+                count += 1;
             }
             
             // Safety limit
@@ -0,0 +1,565 @@
+use crate::cue;
+use lofty::prelude::ItemKey;
+use lofty::{file::TaggedFileExt, probe::Probe, tag::TagType};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+/// Largest `moov` box read into memory when looking for MP4 chapters.
+const MAX_MOOV: u64 = 64 * 1024 * 1024;
+/// Upper bound on chapters per file, against corrupt sample tables.
+const MAX_CHAPTERS: usize = 10_000;
+
+#[derive(Clone)]
+pub struct Chapter {
+    pub start: Duration,
+    pub title: String,
+}
+
+/// Chapter markers of a file, sorted by start. Reads MP4/M4B chapters
+/// (QuickTime chapter tracks or Nero `chpl`), ID3 CHAP frames and Vorbis
+/// CHAPTERxx comments; files without any give an empty list.
+pub fn read(path: &str) -> Vec<Chapter> {
+    // A CUE entry is already a single track of its file
+    if cue::split_ref(path).is_some() {
+        return Vec::new();
+    }
+    let Ok(mut file) = File::open(path) else {
+        return Vec::new();
+    };
+    let mut head = [0u8; 8];
+    if file.read_exact(&mut head).is_err() {
+        return Vec::new();
+    }
+
+    let chapters = match &head {
+        [_, _, _, _, b'f', b't', b'y', b'p'] => read_mp4(&mut file),
+        [b'I', b'D', b'3', ..] => read_id3(&mut file),
+        [b'O', b'g', b'g', b'S', ..] | [b'f', b'L', b'a', b'C', ..] => read_vorbis(path),
+        _ => None,
+    };
+    let mut chapters = chapters.unwrap_or_default();
+    chapters.truncate(MAX_CHAPTERS);
+    chapters.sort_by_key(|c| c.start);
+    chapters.dedup_by_key(|c| c.start);
+    for (i, chapter) in chapters.iter_mut().enumerate() {
+        if chapter.title.trim().is_empty() {
+            chapter.title = format!("Chapter {}", i + 1);
+        }
+    }
+    chapters
+}
+
+fn read_mp4(file: &mut File) -> Option<Vec<Chapter>> {
+    let moov = find_top_level(file, b"moov")?;
+    quicktime_chapters(file, &moov)
+        .filter(|c| !c.is_empty())
+        .or_else(|| nero_chapters(&moov))
+}
+
+/// Reads the body of a top-level MP4 box, skipping over the others.
+fn find_top_level(file: &mut File, kind: &[u8; 4]) -> Option<Vec<u8>> {
+    file.seek(SeekFrom::Start(0)).ok()?;
+    loop {
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header).ok()?;
+        let mut size = u32::from_be_bytes(header[..4].try_into().ok()?) as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            let mut large = [0u8; 8];
+            file.read_exact(&mut large).ok()?;
+            size = u64::from_be_bytes(large);
+            header_len = 16;
+        }
+        if &header[4..8] == kind {
+            let len = if size == 0 { MAX_MOOV } else { size.checked_sub(header_len)? };
+            if len > MAX_MOOV {
+                return None;
+            }
+            let mut body = Vec::new();
+            file.take(len).read_to_end(&mut body).ok()?;
+            return Some(body);
+        }
+        if size < header_len {
+            return None;
+        }
+        file.seek(SeekFrom::Current((size - header_len) as i64)).ok()?;
+    }
+}
+
+/// Iterates over the boxes packed into an MP4 box body.
+struct Atoms<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Atoms<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let size = be_u32(self.data, 0)? as usize;
+        let kind = self.data.get(4..8)?;
+        let (start, size) = match size {
+            0 => (8, self.data.len()),
+            1 => (16, usize::try_from(be_u64(self.data, 8)?).ok()?),
+            size => (8, size),
+        };
+        let body = self.data.get(start..size)?;
+        self.data = &self.data[size..];
+        Some((kind, body))
+    }
+}
+
+fn atoms(data: &[u8]) -> Atoms<'_> {
+    Atoms { data }
+}
+
+/// Follows a path of box types down from `data`.
+fn child<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| {
+        atoms(data).find(|(k, _)| k == kind).map(|(_, body)| body)
+    })
+}
+
+/// Chapters stored as a text track that the audio track points at through
+/// `tref/chap`, as iTunes and most audiobook tools write them.
+fn quicktime_chapters(file: &mut File, moov: &[u8]) -> Option<Vec<Chapter>> {
+    let traks: Vec<&[u8]> = atoms(moov).filter(|(k, _)| k == b"trak").map(|(_, b)| b).collect();
+    let chapter_id = traks.iter().find_map(|trak| be_u32(child(trak, &[b"tref", b"chap"])?, 0))?;
+    let trak = traks.iter().find(|trak| {
+        child(trak, &[b"tkhd"]).and_then(|tkhd| {
+            // Version 1 headers have 64-bit creation and modification times
+            be_u32(tkhd, if tkhd.first() == Some(&1) { 20 } else { 12 })
+        }) == Some(chapter_id)
+    })?;
+
+    let mdhd = child(trak, &[b"mdia", b"mdhd"])?;
+    let timescale = be_u32(mdhd, if mdhd.first() == Some(&1) { 20 } else { 12 })? as u64;
+    let stbl = child(trak, &[b"mdia", b"minf", b"stbl"])?;
+    if timescale == 0 {
+        return None;
+    }
+
+    // Start time of each sample
+    let stts = child(stbl, &[b"stts"])?;
+    let mut starts = Vec::new();
+    let mut time = 0u64;
+    for i in 0..be_u32(stts, 4)? as usize {
+        let count = be_u32(stts, 8 + i * 8)?;
+        let delta = be_u32(stts, 12 + i * 8)? as u64;
+        for _ in 0..count {
+            if starts.len() >= MAX_CHAPTERS {
+                break;
+            }
+            starts.push(time);
+            time += delta;
+        }
+    }
+
+    let stsz = child(stbl, &[b"stsz"])?;
+    let fixed_size = be_u32(stsz, 4)?;
+    let sizes: Vec<u32> = (0..(be_u32(stsz, 8)? as usize).min(starts.len()))
+        .map(|i| if fixed_size != 0 { Some(fixed_size) } else { be_u32(stsz, 12 + i * 4) })
+        .collect::<Option<_>>()?;
+
+    let chunk_offsets: Vec<u64> = match (child(stbl, &[b"stco"]), child(stbl, &[b"co64"])) {
+        (Some(stco), _) => (0..be_u32(stco, 4)? as usize)
+            .map(|i| be_u32(stco, 8 + i * 4).map(u64::from))
+            .collect::<Option<_>>()?,
+        (None, Some(co64)) => (0..be_u32(co64, 4)? as usize)
+            .map(|i| be_u64(co64, 8 + i * 8))
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+
+    // Runs of chunks sharing a samples-per-chunk count, by first chunk
+    let stsc = child(stbl, &[b"stsc"])?;
+    let runs: Vec<(u32, u32)> = (0..be_u32(stsc, 4)? as usize)
+        .map(|i| Some((be_u32(stsc, 8 + i * 12)?, be_u32(stsc, 12 + i * 12)?)))
+        .collect::<Option<_>>()?;
+
+    let mut sample_offsets = Vec::with_capacity(sizes.len());
+    for (index, chunk_offset) in chunk_offsets.iter().enumerate() {
+        let chunk = index as u32 + 1;
+        let per_chunk = runs.iter().rev().find(|(first, _)| *first <= chunk).map_or(1, |r| r.1);
+        let mut offset = *chunk_offset;
+        for _ in 0..per_chunk {
+            let Some(size) = sizes.get(sample_offsets.len()) else {
+                break;
+            };
+            sample_offsets.push(offset);
+            offset += *size as u64;
+        }
+    }
+
+    let mut chapters = Vec::new();
+    for ((start, offset), size) in starts.iter().zip(&sample_offsets).zip(&sizes) {
+        let Ok(micros) = u64::try_from(*start as u128 * 1_000_000 / timescale as u128) else {
+            continue;
+        };
+        chapters.push(Chapter {
+            start: Duration::from_micros(micros),
+            // An unreadable title still leaves the chapter's start
+            title: sample_text(file, *offset, *size).unwrap_or_default(),
+        });
+    }
+    Some(chapters)
+}
+
+/// Reads a text track sample: a length-prefixed string, possibly followed
+/// by style boxes.
+fn sample_text(file: &mut File, offset: u64, size: u32) -> Option<String> {
+    let mut sample = vec![0u8; (size as usize).min(4096)];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut sample).ok()?;
+    let len = u16::from_be_bytes([*sample.first()?, *sample.get(1)?]) as usize;
+    let text = sample.get(2..2 + len).unwrap_or(&sample[2..]);
+    Some(match text {
+        [0xfe, 0xff, rest @ ..] => decode_utf16(rest, u16::from_be_bytes),
+        _ => String::from_utf8_lossy(text).to_string(),
+    })
+}
+
+/// Nero's chapter list, in `moov/udta/chpl`.
+fn nero_chapters(moov: &[u8]) -> Option<Vec<Chapter>> {
+    let chpl = child(moov, &[b"udta", b"chpl"])?;
+    let mut pos = if chpl.first()? == &1 { 8 } else { 4 };
+    let count = *chpl.get(pos)?;
+    pos += 1;
+
+    let mut chapters = Vec::new();
+    for _ in 0..count {
+        // Start in 100 ns units, then a length-prefixed title
+        let start = be_u64(chpl, pos)?;
+        let len = *chpl.get(pos + 8)? as usize;
+        let title = chpl.get(pos + 9..pos + 9 + len)?;
+        chapters.push(Chapter {
+            start: Duration::from_nanos(start.saturating_mul(100)),
+            title: String::from_utf8_lossy(title).to_string(),
+        });
+        pos += 9 + len;
+    }
+    Some(chapters)
+}
+
+/// CHAP frames in an ID3v2.3 or v2.4 tag, titled by their TIT2 sub-frame.
+fn read_id3(file: &mut File) -> Option<Vec<Chapter>> {
+    file.seek(SeekFrom::Start(0)).ok()?;
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header).ok()?;
+    let major = header[3];
+    if major < 3 {
+        return None;
+    }
+    let mut tag = Vec::new();
+    file.take(syncsafe(&header[6..10]) as u64).read_to_end(&mut tag).ok()?;
+
+    let mut pos = 0;
+    if header[5] & 0x40 != 0 {
+        // Extended header; only v2.4 counts its own size field
+        pos = match major {
+            3 => be_u32(&tag, 0)? as usize + 4,
+            _ => syncsafe(tag.get(..4)?) as usize,
+        };
+    }
+
+    let mut chapters = Vec::new();
+    for (id, body) in id3_frames(tag.get(pos..)?, major) {
+        if id != b"CHAP" {
+            continue;
+        }
+        let element_end = body.iter().position(|b| *b == 0)?;
+        let start_ms = be_u32(body, element_end + 1)?;
+        let sub_frames = body.get(element_end + 17..).unwrap_or_default();
+        let title = id3_frames(sub_frames, major)
+            .find(|(id, _)| id == b"TIT2")
+            .map(|(_, text)| id3_text(text))
+            .unwrap_or_default();
+        chapters.push(Chapter { start: Duration::from_millis(start_ms as u64), title });
+    }
+    Some(chapters)
+}
+
+fn id3_frames(data: &[u8], major: u8) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let id = data.get(pos..pos + 4)?;
+        // Padding
+        if id[0] == 0 {
+            return None;
+        }
+        let size_bytes = data.get(pos + 4..pos + 8)?;
+        let size = match major {
+            3 => be_u32(size_bytes, 0)?,
+            _ => syncsafe(size_bytes),
+        } as usize;
+        let body = data.get(pos + 10..pos + 10 + size)?;
+        pos += 10 + size;
+        Some((id, body))
+    })
+}
+
+/// Decodes an ID3 text frame body.
+fn id3_text(body: &[u8]) -> String {
+    let Some((encoding, text)) = body.split_first() else {
+        return String::new();
+    };
+    let text = match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 => match text {
+            [0xff, 0xfe, rest @ ..] => decode_utf16(rest, u16::from_le_bytes),
+            [0xfe, 0xff, rest @ ..] => decode_utf16(rest, u16::from_be_bytes),
+            _ => decode_utf16(text, u16::from_le_bytes),
+        },
+        2 => decode_utf16(text, u16::from_be_bytes),
+        _ => String::from_utf8_lossy(text).to_string(),
+    };
+    text.trim_end_matches('\0').to_string()
+}
+
+/// CHAPTER001=00:00:00.000 / CHAPTER001NAME=... comments in Ogg and FLAC.
+fn read_vorbis(path: &str) -> Option<Vec<Chapter>> {
+    let tagged_file = Probe::open(path).ok()?.read().ok()?;
+    let tag = tagged_file.tag(TagType::VorbisComments)?;
+
+    let mut starts = HashMap::new();
+    let mut titles = HashMap::new();
+    for item in tag.items() {
+        let (ItemKey::Unknown(key), Some(value)) = (item.key(), item.value().text()) else {
+            continue;
+        };
+        let Some(number) = key.to_ascii_uppercase().strip_prefix("CHAPTER").map(str::to_string) else {
+            continue;
+        };
+        match number.strip_suffix("NAME") {
+            Some(number) => {
+                titles.insert(number.to_string(), value.to_string());
+            }
+            None => {
+                if let Some(start) = parse_timestamp(value) {
+                    starts.insert(number, start);
+                }
+            }
+        }
+    }
+
+    let chapters = starts
+        .into_iter()
+        .map(|(number, start)| Chapter {
+            start,
+            title: titles.remove(&number).unwrap_or_default(),
+        })
+        .collect();
+    Some(chapters)
+}
+
+/// Parses HH:MM:SS.mmm.
+fn parse_timestamp(s: &str) -> Option<Duration> {
+    let mut parts = s.trim().rsplitn(3, ':');
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next().map_or(Some(0), |m| m.parse().ok())?;
+    let hours: u64 = parts.next().map_or(Some(0), |h| h.parse().ok())?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs(hours * 3600 + minutes * 60) + Duration::from_secs_f64(seconds))
+}
+
+fn decode_utf16(bytes: &[u8], read: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|c| read([c[0], c[1]])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, b| (acc << 7) | (*b & 0x7f) as u32)
+}
+
+fn be_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32 + 8).to_be_bytes()[..], kind, body].concat()
+    }
+
+    /// Big-endian u32s, for the fixed-width box bodies.
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// An MP4 whose audio track points at a text track holding `samples`,
+    /// all in one chunk of the `mdat` after the `moov`.
+    fn mp4(timescale: u32, stts: &[(u32, u32)], samples: &[Vec<u8>]) -> Vec<u8> {
+        let moov = |offset: u32| {
+            let mut stts_body = words(&[0, stts.len() as u32]);
+            stts_body.extend(stts.iter().flat_map(|&(count, delta)| words(&[count, delta])));
+            let mut stsz = words(&[0, 0, samples.len() as u32]);
+            stsz.extend(samples.iter().flat_map(|s| words(&[s.len() as u32])));
+            let stbl = [
+                mp4_box(b"stts", &stts_body),
+                mp4_box(b"stsz", &stsz),
+                mp4_box(b"stco", &words(&[0, 1, offset])),
+                mp4_box(b"stsc", &words(&[0, 1, 1, samples.len() as u32, 1])),
+            ]
+            .concat();
+            let mdia = [
+                mp4_box(b"mdhd", &words(&[0, 0, 0, timescale, 0])),
+                mp4_box(b"minf", &mp4_box(b"stbl", &stbl)),
+            ]
+            .concat();
+            let audio = [
+                mp4_box(b"tkhd", &words(&[0, 0, 0, 1, 0])),
+                mp4_box(b"tref", &mp4_box(b"chap", &words(&[2]))),
+            ]
+            .concat();
+            let text = [mp4_box(b"tkhd", &words(&[0, 0, 0, 2, 0])), mp4_box(b"mdia", &mdia)].concat();
+            mp4_box(b"moov", &[mp4_box(b"trak", &audio), mp4_box(b"trak", &text)].concat())
+        };
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0");
+        // The offsets are fixed-width, so the length doesn't depend on them
+        let offset = ftyp.len() + moov(0).len() + 8;
+        [ftyp, moov(offset as u32), mp4_box(b"mdat", &samples.concat())].concat()
+    }
+
+    fn text_sample(text: &[u8]) -> Vec<u8> {
+        [&(text.len() as u16).to_be_bytes()[..], text].concat()
+    }
+
+    fn titles(chapters: &[Chapter]) -> Vec<(u64, &str)> {
+        chapters.iter().map(|c| (c.start.as_millis() as u64, c.title.as_str())).collect()
+    }
+
+    #[test]
+    fn reads_quicktime_chapter_tracks() {
+        let dir = TempDir::new("chapters-quicktime");
+        let utf16 = [&[0xfe, 0xff][..], &words(&[0x0043_0061, 0x0066_00e9])].concat();
+        let samples = [text_sample(b"Intro"), text_sample(&utf16), text_sample(b"")];
+        let path = dir.write("book.m4b", mp4(600, &[(2, 36_900), (1, 300)], &samples));
+        assert_eq!(
+            titles(&read(&path)),
+            [(0, "Intro"), (61_500, "Caf\u{e9}"), (123_000, "Chapter 3")]
+        );
+    }
+
+    #[test]
+    fn keeps_chapters_whose_title_cannot_be_read() {
+        let dir = TempDir::new("chapters-truncated");
+        let samples = [text_sample(b"One"), text_sample(b"Two"), text_sample(b"Three")];
+        let mut file = mp4(1000, &[(3, 1000)], &samples);
+        file.truncate(file.len() - 2);
+        let path = dir.write("book.m4b", file);
+        assert_eq!(titles(&read(&path)), [(0, "One"), (1000, "Two"), (2000, "Chapter 3")]);
+    }
+
+    #[test]
+    fn skips_chapters_past_the_longest_duration() {
+        let dir = TempDir::new("chapters-overflow");
+        let samples = vec![text_sample(b""); 5000];
+        let path = dir.write("book.m4b", mp4(1, &[(5000, u32::MAX)], &samples));
+        let chapters = read(&path);
+        // u64::MAX microseconds is just past 4294 of these
+        assert_eq!(chapters.len(), 4295);
+        assert_eq!(chapters[4294].start, Duration::from_secs(4294 * u32::MAX as u64));
+    }
+
+    fn id3_frame(major: u8, id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let size = match major {
+            3 => body.len() as u32,
+            _ => (0..4).fold(0, |acc, i| acc | ((body.len() as u32 >> (7 * i)) & 0x7f) << (8 * i)),
+        };
+        [&id[..], &size.to_be_bytes(), &[0, 0], body].concat()
+    }
+
+    fn chap(major: u8, element: &str, start_ms: u32, title: Option<&[u8]>) -> Vec<u8> {
+        let mut body = [element.as_bytes(), &[0], &words(&[start_ms, 0, u32::MAX, u32::MAX])].concat();
+        if let Some(title) = title {
+            body.extend(id3_frame(major, b"TIT2", title));
+        }
+        id3_frame(major, b"CHAP", &body)
+    }
+
+    fn id3(major: u8, frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut tag = frames.concat();
+        // Padding, then the audio
+        tag.extend([0; 16]);
+        let size = (0..4).fold(0u32, |acc, i| acc | ((tag.len() as u32 >> (7 * i)) & 0x7f) << (8 * i));
+        [&b"ID3"[..], &[major, 0, 0], &size.to_be_bytes(), &tag, &[0xff, 0xfb, 0x90, 0x00]].concat()
+    }
+
+    #[test]
+    fn reads_id3_chap_frames() {
+        let dir = TempDir::new("chapters-id3");
+        for major in [3, 4] {
+            let frames = [
+                id3_frame(major, b"TIT2", b"\0Whole book"),
+                chap(major, "ch2", 90_000, Some(b"\x01\xff\xfeT\0w\0o\0")),
+                chap(major, "ch1", 0, Some(b"\0Caf\xe9")),
+                chap(major, "ch3", 180_500, None),
+            ];
+            let path = dir.write(&format!("v2{}.mp3", major), id3(major, &frames));
+            assert_eq!(
+                titles(&read(&path)),
+                [(0, "Caf\u{e9}"), (90_000, "Two"), (180_500, "Chapter 3")],
+                "ID3v2.{}",
+                major
+            );
+        }
+    }
+
+    /// A FLAC holding only its stream info and a Vorbis comment block.
+    fn flac(comments: &[&str]) -> Vec<u8> {
+        let mut info = vec![0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0];
+        info.extend((44_100u64 << 44 | 1 << 41 | 15 << 36).to_be_bytes());
+        info.extend([0; 16]);
+        let mut block = Vec::new();
+        block.extend(3u32.to_le_bytes());
+        block.extend(b"tap");
+        block.extend((comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend((comment.len() as u32).to_le_bytes());
+            block.extend(comment.as_bytes());
+        }
+        let header = |last: u8, kind: u8, len: usize| {
+            [last << 7 | kind, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+        };
+        [&b"fLaC"[..], &header(0, 0, info.len()), &info, &header(1, 4, block.len()), &block].concat()
+    }
+
+    #[test]
+    fn reads_vorbis_chapter_comments() {
+        let dir = TempDir::new("chapters-vorbis");
+        let path = dir.write(
+            "book.flac",
+            flac(&[
+                "TITLE=Whole book",
+                "CHAPTER002=00:01:30.250",
+                "CHAPTER002NAME=Two",
+                "chapter001=00:00:00.000",
+                "chapter001name=One",
+                "CHAPTER003=1:00:00",
+                "CHAPTER004=soon",
+                "CHAPTER004NAME=Never",
+            ]),
+        );
+        assert_eq!(titles(&read(&path)), [(0, "One"), (90_250, "Two"), (3_600_000, "Chapter 3")]);
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("01:02:03.500"), Some(Duration::from_millis(3_723_500)));
+        assert_eq!(parse_timestamp(" 2:03.25 "), Some(Duration::from_millis(123_250)));
+        assert_eq!(parse_timestamp("42"), Some(Duration::from_secs(42)));
+        for bad in ["", "soon", "1:-2:00", "00:00:-1", "00:00:inf", "00:00:NaN", "1:2:3:4"] {
+            assert_eq!(parse_timestamp(bad), None, "{:?}", bad);
+        }
+    }
+}
@@ -1,17 +1,65 @@
+use crate::audio::FadeCurve;
+use crate::bookmarks::ResumeMode;
+use crate::eq::EqPreset;
+use crate::replaygain::ReplayGainMode;
+use crate::visualizer::VisualizerMode;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
 #[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Config {
     pub last_directory: Option<String>,
     pub last_playlist: Option<String>,
     pub default_music_dir: Option<String>,
     pub default_playlist_dir: Option<String>,
     pub current_playlist_tracks: Vec<String>,
+    /// Crossfade length in seconds, 0 for plain gapless playback
+    pub crossfade_secs: u32,
+    pub crossfade_curve: FadeCurve,
+    /// Also crossfade when skipping with `.` / `,`
+    pub crossfade_manual_skips: bool,
+    pub replaygain_mode: ReplayGainMode,
+    pub eq_enabled: bool,
+    /// Current band gains in dB, lowest band first
+    pub eq_gains: Vec<f32>,
+    /// Name of the preset `eq_gains` was loaded from, if unchanged since
+    pub eq_preset: Option<String>,
+    /// User-defined presets, shown after the built-in ones
+    pub eq_presets: Vec<EqPreset>,
+    /// Output device name, `None` for the system default
+    pub output_device: Option<String>,
+    /// Playback speed for ordinary tracks
+    pub playback_speed: f32,
+    /// Let speed changes shift the pitch instead of time-stretching
+    pub speed_shifts_pitch: bool,
+    /// Remembered speeds of long files (audiobooks, lectures), by path
+    pub track_speeds: HashMap<String, f32>,
+    /// Whether to pick long files up where they were left off
+    pub resume_mode: ResumeMode,
+    /// Files at least this many minutes long get a resume position
+    pub resume_threshold_mins: u32,
+    /// Spectrum bars or oscilloscope in the visualizer pane
+    pub visualizer_mode: VisualizerMode,
+    /// Turn loud passages down rather than let volumes over 100% clip
+    pub soft_limiter: bool,
+    /// Fade length on pause, resume and skip in milliseconds, `None` for
+    /// the default
+    pub pause_fade_ms: Option<u32>,
+    /// Save playlists with paths relative to the playlist file
+    pub relative_playlist_paths: bool,
 }
 
+/// Fade on pause, resume and skip unless set otherwise.
+pub const DEFAULT_PAUSE_FADE_MS: u32 = 50;
+
 impl Config {
+    pub fn pause_fade_ms(&self) -> u32 {
+        self.pause_fade_ms.unwrap_or(DEFAULT_PAUSE_FADE_MS)
+    }
+
     pub fn load() -> Self {
         let config_path = Self::config_path();
         if let Ok(content) = fs::read_to_string(&config_path) {
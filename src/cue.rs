@@ -0,0 +1,334 @@
+use crate::formats;
+use crate::replaygain::ReplayGain;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// CUE times count in CD frames: 75 to the second.
+const FRAMES_PER_SEC: u64 = 75;
+
+/// One track of a CUE sheet: a stretch of a (usually much longer) audio file.
+#[derive(Clone)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// The audio file this track is cut from.
+    pub file: String,
+    /// Offset of INDEX 01 into `file`.
+    pub start: Duration,
+    /// Where the next track in the same file starts, or `None` to play to
+    /// the end of the file. Pregaps belong to the track before them, so
+    /// consecutive tracks still join up without a gap.
+    pub end: Option<Duration>,
+    pub replaygain: ReplayGain,
+}
+
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub date: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Failed to read CUE sheet: {}", e))?;
+        let base_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+
+        let mut sheet = CueSheet { title: None, performer: None, date: None, tracks: Vec::new() };
+        let mut album_gain = ReplayGain::default();
+        let mut file: Option<String> = None;
+
+        for line in decode_text(&bytes).lines() {
+            let (command, rest) = split_word(line.trim());
+            let in_track = sheet.tracks.last_mut();
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    // The format word (WAVE, MP3, ...) follows the name
+                    let name = match rest.rsplit_once(' ') {
+                        Some((name, _)) => unquote(name),
+                        None => unquote(rest),
+                    };
+                    file = Some(resolve_file(base_dir, &name).to_string_lossy().to_string());
+                }
+                "TRACK" => {
+                    let Some(file) = file.clone() else {
+                        return Err("Failed to parse CUE sheet: TRACK before FILE".to_string());
+                    };
+                    let (number, _) = split_word(rest);
+                    sheet.tracks.push(CueTrack {
+                        number: number.parse().unwrap_or(sheet.tracks.len() as u32 + 1),
+                        title: None,
+                        performer: None,
+                        file,
+                        start: Duration::ZERO,
+                        end: None,
+                        replaygain: ReplayGain::default(),
+                    });
+                }
+                "TITLE" => match in_track {
+                    Some(track) => track.title = Some(unquote(rest)),
+                    None => sheet.title = Some(unquote(rest)),
+                },
+                "PERFORMER" => match in_track {
+                    Some(track) => track.performer = Some(unquote(rest)),
+                    None => sheet.performer = Some(unquote(rest)),
+                },
+                "INDEX" => {
+                    let (number, time) = split_word(rest);
+                    if let (Some(track), Ok(1)) = (in_track, number.parse::<u32>()) {
+                        // A pregap may sit at the end of the previous file
+                        if let Some(file) = &file {
+                            track.file = file.clone();
+                        }
+                        track.start = parse_time(time)
+                            .ok_or_else(|| format!("Failed to parse CUE sheet: bad INDEX time {}", time))?;
+                    }
+                }
+                "REM" => {
+                    let (key, value) = split_word(rest);
+                    let value = unquote(value);
+                    let number = value.split_whitespace().next().and_then(|v| v.parse::<f32>().ok());
+                    match (key.to_ascii_uppercase().as_str(), in_track) {
+                        ("DATE", _) => sheet.date = Some(value),
+                        ("REPLAYGAIN_ALBUM_GAIN", _) => album_gain.album_gain = number,
+                        ("REPLAYGAIN_ALBUM_PEAK", _) => album_gain.album_peak = number,
+                        ("REPLAYGAIN_TRACK_GAIN", Some(track)) => track.replaygain.track_gain = number,
+                        ("REPLAYGAIN_TRACK_PEAK", Some(track)) => track.replaygain.track_peak = number,
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if sheet.tracks.is_empty() {
+            return Err("Failed to parse CUE sheet: no tracks".to_string());
+        }
+        for i in 0..sheet.tracks.len() {
+            let next = sheet.tracks.get(i + 1).filter(|n| n.file == sheet.tracks[i].file).map(|n| n.start);
+            let track = &mut sheet.tracks[i];
+            track.end = next.filter(|end| *end > track.start);
+            if track.performer.is_none() {
+                track.performer = sheet.performer.clone();
+            }
+            track.replaygain.album_gain = album_gain.album_gain;
+            track.replaygain.album_peak = album_gain.album_peak;
+        }
+        Ok(sheet)
+    }
+}
+
+pub fn is_cue_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// Playlist entry for track `number` of a CUE sheet.
+pub fn track_ref(cue_path: &str, number: u32) -> String {
+    format!("{}#{}", cue_path, number)
+}
+
+/// Splits a playlist entry made by `track_ref` back into sheet and number.
+pub fn split_ref(entry: &str) -> Option<(&str, u32)> {
+    let (cue_path, number) = entry.rsplit_once('#')?;
+    if !is_cue_file(Path::new(cue_path)) {
+        return None;
+    }
+    Some((cue_path, number.parse().ok()?))
+}
+
+/// Playlist entries for every track of a CUE sheet.
+pub fn expand(cue_path: &str) -> Result<Vec<String>, String> {
+    let sheet = load(cue_path)?;
+    Ok(sheet.tracks.iter().map(|t| track_ref(cue_path, t.number)).collect())
+}
+
+/// Parses a sheet once and keeps it until the file changes, since entries
+/// are looked up on every redraw.
+pub fn load(cue_path: &str) -> Result<Arc<CueSheet>, String> {
+    type Cached = (Option<SystemTime>, Arc<CueSheet>);
+    static SHEETS: OnceLock<Mutex<HashMap<String, Cached>>> = OnceLock::new();
+    let sheets = SHEETS.get_or_init(|| Mutex::new(HashMap::new()));
+    let modified = fs::metadata(cue_path).and_then(|m| m.modified()).ok();
+    if let Some((parsed_at, sheet)) = sheets.lock().unwrap().get(cue_path) {
+        if *parsed_at == modified {
+            return Ok(sheet.clone());
+        }
+    }
+    let sheet = Arc::new(CueSheet::parse(cue_path)?);
+    sheets.lock().unwrap().insert(cue_path.to_string(), (modified, sheet.clone()));
+    Ok(sheet)
+}
+
+/// The sheet and track a playlist entry points at, if it is a CUE entry.
+pub fn lookup(entry: &str) -> Option<(Arc<CueSheet>, CueTrack)> {
+    let (cue_path, number) = split_ref(entry)?;
+    let sheet = load(cue_path).ok()?;
+    let track = sheet.tracks.iter().find(|t| t.number == number)?.clone();
+    Some((sheet, track))
+}
+
+/// "03. Performer - Title" for CUE entries.
+pub fn display_name(entry: &str) -> Option<String> {
+    let (_, track) = lookup(entry)?;
+    let title = track.title.unwrap_or_else(|| format!("Track {}", track.number));
+    Some(match track.performer {
+        Some(performer) => format!("{:02}. {} - {}", track.number, performer, title),
+        None => format!("{:02}. {}", track.number, title),
+    })
+}
+
+/// Finds the audio file a FILE line names. Rippers often write the sheet
+/// for a WAV and encode afterwards, so a missing file is also looked for
+/// under the other audio extensions.
+fn resolve_file(base_dir: &Path, name: &str) -> PathBuf {
+    let path = base_dir.join(name);
+    if path.exists() {
+        return path;
+    }
+    formats::FORMATS
+        .iter()
+        .flat_map(|f| f.extensions.iter())
+        .map(|ext| path.with_extension(ext))
+        .find(|p| p.exists())
+        .unwrap_or(path)
+}
+
+/// Sheets from older rippers are often Latin-1 rather than UTF-8.
+pub fn decode_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    match s.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (s, ""),
+    }
+}
+
+fn unquote(s: &str) -> String {
+    let s = s.trim();
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+        .to_string()
+}
+
+/// Parses MM:SS:FF.
+fn parse_time(s: &str) -> Option<Duration> {
+    let mut parts = s.split(':').map(|p| p.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    let total_frames = (minutes * 60 + seconds) * FRAMES_PER_SEC + frames;
+    Some(Duration::from_micros(total_frames * 1_000_000 / FRAMES_PER_SEC))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs::File;
+
+    const SHEET: &str = r#"REM DATE 1999
+REM REPLAYGAIN_ALBUM_GAIN -7.50 dB
+PERFORMER "Band"
+TITLE "Album"
+FILE "album.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "Intro"
+    REM REPLAYGAIN_TRACK_GAIN -6.00 dB
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Song"
+    PERFORMER "Guest"
+    INDEX 00 03:58:00
+    INDEX 01 04:00:37
+FILE "bonus.wav" WAVE
+  TRACK 03 AUDIO
+    INDEX 01 00:00:00
+"#;
+
+    #[test]
+    fn parses_tracks_and_their_offsets() {
+        let dir = TempDir::new("cue-parse");
+        let path = dir.write("album.cue", SHEET.as_bytes());
+        let sheet = CueSheet::parse(&path).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("Band"));
+        assert_eq!(sheet.date.as_deref(), Some("1999"));
+        assert_eq!(sheet.tracks.len(), 3);
+
+        let [intro, song, bonus] = &sheet.tracks[..] else { unreachable!() };
+        assert_eq!((intro.number, intro.title.as_deref()), (1, Some("Intro")));
+        assert_eq!(intro.performer.as_deref(), Some("Band"));
+        assert!(intro.file.ends_with("album.wav"));
+        assert_eq!(intro.start, Duration::ZERO);
+        // The pregap (INDEX 00) stays with the track before
+        assert_eq!(intro.end, Some(song.start));
+        assert_eq!(song.start, Duration::from_micros(240_493_333));
+        assert_eq!(song.performer.as_deref(), Some("Guest"));
+        // Last track of its file plays to the end of it
+        assert_eq!(song.end, None);
+        assert!(bonus.file.ends_with("bonus.wav"));
+        assert_eq!(bonus.title, None);
+
+        assert_eq!(intro.replaygain.track_gain, Some(-6.0));
+        assert_eq!(bonus.replaygain.album_gain, Some(-7.5));
+    }
+
+    #[test]
+    fn finds_the_file_under_another_extension() {
+        let dir = TempDir::new("cue-extension");
+        let path = dir.write("rip.cue", b"FILE \"rip.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n");
+        let flac = dir.write("rip.flac", b"");
+        let sheet = CueSheet::parse(&path).unwrap();
+        assert_eq!(sheet.tracks[0].file, flac);
+    }
+
+    #[test]
+    fn reads_latin1_sheets() {
+        let dir = TempDir::new("cue-latin1");
+        let path = dir.write("album.cue", b"FILE a.wav WAVE\nTRACK 1 AUDIO\nTITLE \"Caf\xe9\"\n");
+        let sheet = CueSheet::parse(&path).unwrap();
+        assert_eq!(sheet.tracks[0].title.as_deref(), Some("Caf\u{e9}"));
+    }
+
+    #[test]
+    fn rejects_sheets_without_tracks() {
+        let dir = TempDir::new("cue-bad");
+        let no_file = dir.write("no-file.cue", b"TRACK 01 AUDIO\n");
+        assert!(CueSheet::parse(&no_file).is_err());
+        let no_tracks = dir.write("no-tracks.cue", b"TITLE \"Album\"\nFILE a.wav WAVE\n");
+        assert!(CueSheet::parse(&no_tracks).is_err());
+        let bad_time = dir.write("bad-time.cue", b"FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 1:2\n");
+        assert!(CueSheet::parse(&bad_time).is_err());
+    }
+
+    #[test]
+    fn track_refs_round_trip() {
+        let entry = track_ref("/music/album.cue", 12);
+        assert_eq!(split_ref(&entry), Some(("/music/album.cue", 12)));
+        assert_eq!(split_ref("/music/song #1.mp3"), None);
+        assert_eq!(split_ref("/music/album.cue#x"), None);
+    }
+
+    #[test]
+    fn reloads_a_sheet_that_changed() {
+        let dir = TempDir::new("cue-reload");
+        let path = dir.write("album.cue", b"FILE a.wav WAVE\nTRACK 01 AUDIO\nTITLE One\n");
+        assert_eq!(load(&path).unwrap().tracks[0].title.as_deref(), Some("One"));
+        assert!(Arc::ptr_eq(&load(&path).unwrap(), &load(&path).unwrap()));
+
+        fs::write(&path, b"FILE a.wav WAVE\nTRACK 01 AUDIO\nTITLE Two\n").unwrap();
+        // Make sure the change shows even on coarse file system clocks
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert_eq!(load(&path).unwrap().tracks[0].title.as_deref(), Some("Two"));
+    }
+}
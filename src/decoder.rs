@@ -0,0 +1,457 @@
+use crate::cue;
+use crate::formats;
+use rodio::Source;
+use std::fs::File;
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+/// Length of the fades either side of an A-B loop's jump back.
+const LOOP_DECLICK_SECS: f64 = 0.003;
+
+/// A rodio `Source` that keeps the symphonia reader and decoder open for the
+/// whole track, so seeking is a container seek instead of decoding everything
+/// up to the target.
+pub struct SymphoniaSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    duration: Option<Duration>,
+    channels: u16,
+    sample_rate: u32,
+    /// Interleaved samples of the current packet, already converted.
+    buffer: Vec<f32>,
+    buffer_pos: usize,
+    /// Frame (at the source sample rate) of the next sample handed out.
+    frame: u64,
+    /// After an accurate seek, frames before this one are decoded and dropped.
+    skip_until: u64,
+    /// Where in the file the track starts; positions are relative to it.
+    start: Duration,
+    /// Frame the track stops at, for tracks that end before the file does.
+    end_frame: Option<u64>,
+    /// Frame an A-B loop's B point falls on; the track stops there too.
+    loop_end: Option<u64>,
+    /// Fade the next decoded samples in, after jumping back to a loop's A.
+    fade_in: bool,
+    converter: Option<Converter>,
+    /// Drop the converter once the samples already converted have played.
+    restore_pending: bool,
+    /// Set when that has happened, until `take_format_change`.
+    format_changed: bool,
+}
+
+impl SymphoniaSource {
+    /// Opens an audio file, or the stretch of one a CUE sheet entry names.
+    pub fn open(path: &str) -> Result<Self, String> {
+        match cue::lookup(path) {
+            Some((_, track)) => Self::open_range(&track.file, track.start, track.end),
+            None => Self::open_range(path, Duration::ZERO, None),
+        }
+    }
+
+    fn open_range(path: &str, start: Duration, end: Option<Duration>) -> Result<Self, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        // Go by the contents, so a mis-named file still gets the right demuxer
+        let mut hint = Hint::new();
+        if let Some(format) = formats::detect(std::path::Path::new(path)) {
+            hint.with_extension(format.extensions[0]);
+        }
+
+        // Gapless trims encoder delay and padding so queued tracks butt up cleanly
+        let format_opts = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe()
+            .format(&hint, mss, &format_opts, &MetadataOptions::default())
+            .map_err(|e| format!("Failed to decode audio: {}", e))?;
+
+        let format = probed.format;
+        let track = format.default_track()
+            .ok_or_else(|| "Failed to decode audio: no audio track".to_string())?;
+        let decoder = formats::codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|e| format!("Failed to decode audio: {}", e))?;
+
+        let params = &track.codec_params;
+        let time_base = params.time_base;
+        let file_duration = match (time_base, params.n_frames) {
+            (Some(tb), Some(n_frames)) => Some(time_to_duration(tb.calc_time(n_frames))),
+            _ => None,
+        };
+        let duration = end.or(file_duration).map(|end| end.saturating_sub(start));
+
+        let mut source = Self {
+            track_id: track.id,
+            time_base,
+            duration,
+            channels: params.channels.map(|c| c.count() as u16).unwrap_or(2),
+            sample_rate: params.sample_rate.unwrap_or(44100),
+            format,
+            decoder,
+            buffer: Vec::new(),
+            buffer_pos: 0,
+            frame: 0,
+            skip_until: 0,
+            start,
+            end_frame: None,
+            loop_end: None,
+            fade_in: false,
+            converter: None,
+            restore_pending: false,
+            format_changed: false,
+        };
+        // Decode the first packet up front so the real signal spec is known
+        // and the track can start the moment it is needed.
+        source.decode_next();
+        source.end_frame = end.map(|end| (end.as_secs_f64() * source.sample_rate as f64).round() as u64);
+        if !start.is_zero() {
+            source.seek(Duration::ZERO)?;
+        }
+        Ok(source)
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    /// Position of the next sample to be played. `frame` counts frames of
+    /// the file itself, so converted samples still buffered are counted
+    /// back in the file's own frames.
+    pub fn current_position(&self) -> Duration {
+        let buffered = self.buffer.len() - self.buffer_pos;
+        let frames_ahead = match &self.converter {
+            Some(c) => c.pending_input_frames(buffered),
+            None => (buffered / self.channels.max(1) as usize) as f64,
+        };
+        let frame = (self.frame as f64 - frames_ahead).max(0.0);
+        Duration::from_secs_f64(frame / self.sample_rate.max(1) as f64).saturating_sub(self.start)
+    }
+
+    /// Resamples and remixes the output so it can be mixed with, or played
+    /// in place of, a source with the given format.
+    pub fn set_output_format(&mut self, channels: u16, sample_rate: u32) {
+        if channels == self.channels && sample_rate == self.sample_rate {
+            self.converter = None;
+            return;
+        }
+        let mut converter = Converter::new(self.channels, channels, self.sample_rate, sample_rate);
+        let mut converted = Vec::new();
+        converter.process(&self.buffer[self.buffer_pos..], &mut converted);
+        self.buffer = converted;
+        self.buffer_pos = 0;
+        self.converter = Some(converter);
+    }
+
+    /// Goes back to the track's own format once the samples already
+    /// converted have played, after the crossfade that needed them. The
+    /// switch shows up as a single `None` from `next`, so a reader that
+    /// buffers ahead can flush first; see `take_format_change`.
+    pub fn restore_format(&mut self) {
+        self.restore_pending = self.converter.is_some();
+    }
+
+    /// Stays converted after all, for a track that is about to be faded
+    /// out. Too late once the converted samples have run out.
+    pub fn keep_format(&mut self) {
+        if self.buffer_pos < self.buffer.len() {
+            self.restore_pending = false;
+        }
+    }
+
+    /// Whether the last `None` from `next` was a switch back to the
+    /// track's own format rather than its end.
+    pub fn take_format_change(&mut self) -> bool {
+        std::mem::take(&mut self.format_changed)
+    }
+
+    /// The converter the next samples handed out go through, if any.
+    fn output_converter(&self) -> Option<&Converter> {
+        let switching = self.restore_pending && self.buffer_pos >= self.buffer.len();
+        self.converter.as_ref().filter(|_| !switching)
+    }
+
+    /// Seeks to `pos` and returns the position that playback resumes from.
+    pub fn seek(&mut self, pos: Duration) -> Result<Duration, String> {
+        let seeked = self.format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time { time: Time::from((self.start + pos).as_secs_f64()), track_id: Some(self.track_id) },
+            )
+            .map_err(|e| format!("Seek failed: {}", e))?;
+
+        self.decoder.reset();
+        self.buffer.clear();
+        self.buffer_pos = 0;
+        if let Some(converter) = self.converter.as_mut() {
+            converter.reset();
+        }
+        self.frame = self.ts_to_frame(seeked.actual_ts);
+        self.skip_until = self.ts_to_frame(seeked.required_ts);
+        self.decode_next();
+        Ok(self.current_position())
+    }
+
+    /// Makes the track stop early at `end`, the B point of an A-B loop.
+    pub fn set_loop_end(&mut self, end: Option<Duration>) {
+        self.loop_end = end.map(|end| ((self.start + end).as_secs_f64() * self.sample_rate as f64).round() as u64);
+    }
+
+    /// Seeks back to a loop's A point, fading in so the jump doesn't click.
+    pub fn loop_to(&mut self, pos: Duration) -> Result<Duration, String> {
+        self.fade_in = true;
+        self.seek(pos)
+    }
+
+    fn ts_to_frame(&self, ts: u64) -> u64 {
+        match self.time_base {
+            // Most codecs count in frames already
+            Some(tb) if tb.numer == 1 && tb.denom == self.sample_rate => ts,
+            Some(tb) => {
+                let time = tb.calc_time(ts);
+                ((time.seconds as f64 + time.frac) * self.sample_rate as f64).round() as u64
+            }
+            None => ts,
+        }
+    }
+
+    /// Decodes packets until one yields samples. Returns false at end of stream.
+    fn decode_next(&mut self) -> bool {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => return false,
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // Corrupt packets are skipped rather than ending the track
+                Err(Error::DecodeError(_)) => continue,
+                Err(_) => return false,
+            };
+
+            let spec = *decoded.spec();
+            let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            samples.copy_interleaved_ref(decoded);
+
+            let channels = spec.channels.count().max(1);
+            if self.converter.is_none() {
+                self.channels = channels as u16;
+                self.sample_rate = spec.rate;
+            }
+
+            let packet_frame = self.ts_to_frame(packet.ts());
+            let mut data = samples.samples();
+            let mut frames = data.len() / channels;
+
+            // Stop where the next CUE track begins, or at a loop's B point
+            let end = match (self.end_frame, self.loop_end) {
+                (Some(end), Some(loop_end)) => Some(end.min(loop_end)),
+                (end, loop_end) => end.or(loop_end),
+            };
+            let mut at_loop_end = false;
+            if let Some(end) = end {
+                if packet_frame >= end {
+                    return false;
+                }
+                at_loop_end = self.loop_end == Some(end) && packet_frame + frames as u64 >= end;
+                frames = frames.min((end - packet_frame) as usize);
+                data = &data[..frames * channels];
+            }
+            self.frame = packet_frame + frames as u64;
+
+            // Drop whatever lies before an accurate seek target
+            if self.skip_until > packet_frame {
+                let skip = ((self.skip_until - packet_frame) as usize).min(frames);
+                data = &data[skip * channels..];
+                if data.is_empty() {
+                    continue;
+                }
+            }
+            self.skip_until = 0;
+
+            self.buffer.clear();
+            self.buffer_pos = 0;
+            match self.converter.as_mut() {
+                Some(converter) => converter.process(data, &mut self.buffer),
+                None => self.buffer.extend_from_slice(data),
+            }
+
+            let out_channels = Source::channels(self) as usize;
+            let ramp = (Source::sample_rate(self) as f64 * LOOP_DECLICK_SECS) as usize * out_channels;
+            if self.fade_in && !self.buffer.is_empty() {
+                self.fade_in = false;
+                let len = ramp.min(self.buffer.len());
+                apply_ramp(&mut self.buffer[..len], out_channels, true);
+            }
+            if at_loop_end {
+                let start = self.buffer.len().saturating_sub(ramp);
+                apply_ramp(&mut self.buffer[start..], out_channels, false);
+            }
+            if !self.buffer.is_empty() {
+                return true;
+            }
+        }
+    }
+}
+
+impl Iterator for SymphoniaSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.buffer_pos >= self.buffer.len() {
+            if self.restore_pending {
+                self.restore_pending = false;
+                if let Some(converter) = self.converter.take() {
+                    // The last input frame was only interpolated towards so far
+                    self.buffer = converter.prev.unwrap_or_default();
+                    self.buffer_pos = 0;
+                    self.format_changed = true;
+                    return None;
+                }
+            }
+            if !self.decode_next() {
+                return None;
+            }
+        }
+        let sample = self.buffer[self.buffer_pos];
+        self.buffer_pos += 1;
+        Some(sample)
+    }
+}
+
+impl Source for SymphoniaSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.buffer.len() - self.buffer_pos)
+    }
+
+    fn channels(&self) -> u16 {
+        match self.output_converter() {
+            Some(c) => c.channels_out,
+            None => self.channels,
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match self.output_converter() {
+            Some(c) => c.rate_out,
+            None => self.sample_rate,
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.duration
+    }
+}
+
+/// Fades whole frames linearly in (`rising`) or out.
+fn apply_ramp(samples: &mut [f32], channels: usize, rising: bool) {
+    let frames = samples.len() / channels.max(1);
+    for (i, frame) in samples.chunks_mut(channels.max(1)).enumerate() {
+        let t = (i + 1) as f32 / (frames + 1) as f32;
+        let gain = if rising { t } else { 1.0 - t };
+        frame.iter_mut().for_each(|s| *s *= gain);
+    }
+}
+
+fn time_to_duration(time: Time) -> Duration {
+    Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+}
+
+/// Linear-interpolating resampler plus a simple channel mapper. Good enough
+/// for the few seconds a crossfade overlaps two tracks of different formats;
+/// the incoming track drops it again once the fade is over.
+struct Converter {
+    channels_in: u16,
+    channels_out: u16,
+    rate_out: u32,
+    /// Input frames advanced per output frame.
+    step: f64,
+    /// Fractional read position, relative to `prev`.
+    pos: f64,
+    prev: Option<Vec<f32>>,
+}
+
+impl Converter {
+    fn new(channels_in: u16, channels_out: u16, rate_in: u32, rate_out: u32) -> Self {
+        Self {
+            channels_in,
+            channels_out,
+            rate_out,
+            step: rate_in as f64 / rate_out.max(1) as f64,
+            pos: 0.0,
+            prev: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pos = 0.0;
+        self.prev = None;
+    }
+
+    /// How many input frames before the end of the input so far the first
+    /// of `output_samples` converted samples stands for.
+    fn pending_input_frames(&self, output_samples: usize) -> f64 {
+        let frames = output_samples / self.channels_out.max(1) as usize;
+        // The next output frame would be `pos` past the last input frame
+        (frames as f64 * self.step + 1.0 - self.pos).max(0.0)
+    }
+
+    fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let ch_in = self.channels_in.max(1) as usize;
+        let frames: Vec<&[f32]> = input.chunks_exact(ch_in).collect();
+        if frames.is_empty() {
+            return;
+        }
+
+        let mut start = 0;
+        let prev = match self.prev.take() {
+            Some(prev) => prev,
+            None => {
+                start = 1;
+                frames[0].to_vec()
+            }
+        };
+
+        // Index 0 is `prev`, followed by the new frames
+        let available = frames.len() - start + 1;
+        let frame_at = |i: usize| -> &[f32] {
+            if i == 0 { &prev } else { frames[start + i - 1] }
+        };
+
+        while self.pos + 1.0 < available as f64 {
+            let i = self.pos as usize;
+            let t = (self.pos - i as f64) as f32;
+            let (a, b) = (frame_at(i), frame_at(i + 1));
+            for c in 0..self.channels_out as usize {
+                let sample = |frame: &[f32]| -> f32 {
+                    if self.channels_out == 1 {
+                        frame.iter().sum::<f32>() / frame.len() as f32
+                    } else {
+                        frame[c % frame.len()]
+                    }
+                };
+                let (sa, sb) = (sample(a), sample(b));
+                out.push(sa + (sb - sa) * t);
+            }
+            self.pos += self.step;
+        }
+
+        self.pos -= (available - 1) as f64;
+        self.prev = Some(frame_at(available - 1).to_vec());
+    }
+}
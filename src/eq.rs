@@ -0,0 +1,303 @@
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Band gains are limited to this many dB either way.
+pub const MAX_GAIN_DB: f32 = 12.0;
+
+/// Default number of bands; presets may carry any other count.
+pub const DEFAULT_BANDS: usize = 10;
+
+const LOWEST_BAND_HZ: f32 = 31.25;
+const HIGHEST_BAND_HZ: f32 = 16000.0;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EqPreset {
+    pub name: String,
+    pub gains: Vec<f32>,
+}
+
+pub fn builtin_presets() -> Vec<EqPreset> {
+    let preset = |name: &str, gains: [f32; DEFAULT_BANDS]| EqPreset {
+        name: name.to_string(),
+        gains: gains.to_vec(),
+    };
+    vec![
+        preset("Flat", [0.0; DEFAULT_BANDS]),
+        preset("Bass Boost", [6.0, 5.0, 4.0, 2.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0]),
+        preset("Treble Boost", [0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 2.0, 4.0, 5.0, 6.0]),
+        preset("Vocal", [-2.0, -2.0, -1.0, 1.0, 3.0, 3.5, 3.0, 1.5, 0.0, -1.0]),
+        preset("Rock", [4.0, 3.0, 1.5, -0.5, -1.5, -0.5, 1.5, 3.0, 3.5, 4.0]),
+        preset("Loudness", [5.0, 4.0, 1.5, 0.0, -1.0, 0.0, 0.0, 1.5, 4.0, 5.0]),
+        preset("Spoken Word", [-6.0, -4.0, -1.0, 1.0, 3.0, 4.0, 3.0, 1.0, -2.0, -4.0]),
+    ]
+}
+
+/// Centre frequencies for `bands` bands, spaced evenly on a log scale from
+/// 31 Hz to 16 kHz. Ten bands gives the usual octave graphic EQ.
+pub fn band_frequencies(bands: usize) -> Vec<f32> {
+    if bands <= 1 {
+        return vec![1000.0; bands];
+    }
+    let ratio = (HIGHEST_BAND_HZ / LOWEST_BAND_HZ).powf(1.0 / (bands - 1) as f32);
+    (0..bands).map(|i| LOWEST_BAND_HZ * ratio.powi(i as i32)).collect()
+}
+
+pub fn format_frequency(hz: f32) -> String {
+    if hz >= 1000.0 {
+        format!("{}k", (hz / 1000.0).round())
+    } else {
+        format!("{}", hz.round())
+    }
+}
+
+/// EQ state shared between the UI and the audio thread. The audio thread
+/// rebuilds its filters whenever `version` moves.
+#[derive(Default)]
+pub struct EqControl {
+    gains: Mutex<Vec<f32>>,
+    enabled: AtomicBool,
+    version: AtomicU64,
+}
+
+impl EqControl {
+    pub fn set(&self, enabled: bool, gains: &[f32]) {
+        *self.gains.lock().unwrap() = gains.to_vec();
+        self.enabled.store(enabled, Ordering::SeqCst);
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// RBJ peaking filter, direct form I, with state for one channel.
+#[derive(Clone, Copy)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    fn peaking(freq: f32, gain_db: f32, sample_rate: u32) -> Self {
+        // Roughly one octave wide, matching the default band spacing
+        const Q: f32 = std::f32::consts::SQRT_2;
+        let a = 10f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f32::consts::PI * freq / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * Q);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha / a;
+        Self {
+            b0: (1.0 + alpha * a) / a0,
+            b1: (-2.0 * cos_w0) / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: (-2.0 * cos_w0) / a0,
+            a2: (1.0 - alpha / a) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// N-band graphic equalizer stage between the decoded tracks and the sink.
+pub struct Equalizer<S> {
+    inner: S,
+    control: Arc<EqControl>,
+    /// One filter chain per channel; flat bands are left out.
+    filters: Vec<Vec<Biquad>>,
+    version: u64,
+    sample_rate: u32,
+    channels: u16,
+    channel: usize,
+}
+
+impl<S: Source<Item = f32>> Equalizer<S> {
+    pub fn new(inner: S, control: Arc<EqControl>) -> Self {
+        let mut eq = Self {
+            sample_rate: inner.sample_rate(),
+            channels: inner.channels(),
+            inner,
+            control,
+            filters: Vec::new(),
+            version: 0,
+            channel: 0,
+        };
+        eq.rebuild();
+        eq
+    }
+
+    fn rebuild(&mut self) {
+        self.version = self.control.version.load(Ordering::SeqCst);
+        self.filters.clear();
+        if !self.control.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let gains = self.control.gains.lock().unwrap().clone();
+        let nyquist = self.sample_rate as f32 / 2.0;
+        let chain: Vec<Biquad> = band_frequencies(gains.len())
+            .into_iter()
+            .zip(gains)
+            .filter(|(freq, gain)| gain.abs() > 0.01 && *freq < nyquist * 0.9)
+            .map(|(freq, gain)| Biquad::peaking(freq, gain, self.sample_rate))
+            .collect();
+        if !chain.is_empty() {
+            self.filters = vec![chain; self.channels.max(1) as usize];
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Equalizer<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            // Only change filters on frame boundaries so channels stay lined up
+            let rate = self.inner.sample_rate();
+            let channels = self.inner.channels();
+            if rate != self.sample_rate
+                || channels != self.channels
+                || self.control.version.load(Ordering::Relaxed) != self.version
+            {
+                self.sample_rate = rate;
+                self.channels = channels;
+                self.rebuild();
+            }
+        }
+
+        let sample = self.inner.next()?;
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.channels.max(1) as usize;
+
+        match self.filters.get_mut(channel) {
+            Some(chain) => Some(chain.iter_mut().fold(sample, |x, filter| filter.process(x))),
+            None => Some(sample),
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Equalizer<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const RATE: u32 = 48000;
+
+    /// A second of a full-scale sine at `freq`, on every channel.
+    fn sine(freq: f32, channels: u16) -> SamplesBuffer<f32> {
+        let samples = (0..RATE)
+            .flat_map(|i| {
+                let sample = (i as f32 / RATE as f32 * freq * std::f32::consts::TAU).sin();
+                std::iter::repeat_n(sample, channels as usize)
+            })
+            .collect::<Vec<f32>>();
+        SamplesBuffer::new(channels, RATE, samples)
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// Level change in dB of a sine at `freq` through the EQ, once the
+    /// filters have settled.
+    fn gain_db(freq: f32, enabled: bool, gains: &[f32]) -> f32 {
+        let control = Arc::new(EqControl::default());
+        control.set(enabled, gains);
+        let output: Vec<f32> = Equalizer::new(sine(freq, 1), control).collect();
+        let input: Vec<f32> = sine(freq, 1).collect();
+        let half = output.len() / 2;
+        20.0 * (rms(&output[half..]) / rms(&input[half..])).log10()
+    }
+
+    fn band(index: usize, gain: f32) -> Vec<f32> {
+        let mut gains = vec![0.0; DEFAULT_BANDS];
+        gains[index] = gain;
+        gains
+    }
+
+    #[test]
+    fn bands_span_the_audible_range() {
+        let bands = band_frequencies(DEFAULT_BANDS);
+        assert_eq!(bands.len(), DEFAULT_BANDS);
+        assert!((bands[0] - 31.25).abs() < 0.01);
+        assert!((bands[5] - 1000.0).abs() < 1.0);
+        assert!((bands[9] - 16000.0).abs() < 1.0);
+        assert_eq!(band_frequencies(1), [1000.0]);
+    }
+
+    #[test]
+    fn flat_or_disabled_leaves_the_signal_alone() {
+        let flat = Arc::new(EqControl::default());
+        flat.set(true, &[0.0; DEFAULT_BANDS]);
+        assert!(Equalizer::new(sine(1000.0, 2), flat).eq(sine(1000.0, 2)));
+
+        let disabled = Arc::new(EqControl::default());
+        disabled.set(false, &[MAX_GAIN_DB; DEFAULT_BANDS]);
+        assert!(Equalizer::new(sine(1000.0, 2), disabled).eq(sine(1000.0, 2)));
+    }
+
+    #[test]
+    fn bands_boost_and_cut_at_their_centre() {
+        assert!((gain_db(1000.0, true, &band(5, 6.0)) - 6.0).abs() < 0.2);
+        assert!((gain_db(1000.0, true, &band(5, -12.0)) + 12.0).abs() < 0.2);
+        assert!((gain_db(125.0, true, &band(2, 9.0)) - 9.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn bands_leave_distant_frequencies_alone() {
+        // Five octaves away from a 1 kHz boost
+        assert!(gain_db(31.25, true, &band(5, 12.0)).abs() < 0.2);
+        assert!(gain_db(16000.0, true, &band(0, 12.0)).abs() < 0.2);
+        // Half an octave off still gets part of it
+        let near = gain_db(1414.0, true, &band(5, 12.0));
+        assert!(near > 3.0 && near < 12.0, "{} dB", near);
+    }
+
+    #[test]
+    fn channels_are_filtered_apart() {
+        let control = Arc::new(EqControl::default());
+        control.set(true, &band(5, 12.0));
+        // Left silent, right a tone: nothing may leak across
+        let samples: Vec<f32> = sine(1000.0, 1).flat_map(|s| [0.0, s]).collect();
+        let output: Vec<f32> = Equalizer::new(SamplesBuffer::new(2, RATE, samples), control).collect();
+        assert!(output.iter().step_by(2).all(|s| *s == 0.0));
+        assert!(rms(&output[1..].iter().step_by(2).copied().collect::<Vec<_>>()) > 1.0);
+    }
+}
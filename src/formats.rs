@@ -0,0 +1,104 @@
+use crate::opus::OpusCodec;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::OnceLock;
+use symphonia::core::codecs::CodecRegistry;
+
+/// An audio format TAP recognises. This table is the one place that decides
+/// what counts as an audio file, for the browser, the directory scanner and
+/// the decoder alike.
+pub struct AudioFormat {
+    pub name: &'static str,
+    /// Lowercase file extensions, the usual one first.
+    pub extensions: &'static [&'static str],
+}
+
+pub const MP3: AudioFormat = AudioFormat { name: "MP3", extensions: &["mp3"] };
+pub const FLAC: AudioFormat = AudioFormat { name: "FLAC", extensions: &["flac"] };
+pub const WAV: AudioFormat = AudioFormat { name: "WAV", extensions: &["wav", "wave"] };
+pub const VORBIS: AudioFormat = AudioFormat { name: "Ogg Vorbis", extensions: &["ogg", "oga"] };
+pub const OPUS: AudioFormat = AudioFormat { name: "Opus", extensions: &["opus"] };
+pub const MP4: AudioFormat = AudioFormat { name: "AAC/ALAC (MP4)", extensions: &["m4a", "m4b", "mp4"] };
+pub const AAC: AudioFormat = AudioFormat { name: "AAC (ADTS)", extensions: &["aac"] };
+pub const AIFF: AudioFormat = AudioFormat { name: "AIFF", extensions: &["aiff", "aif", "aifc"] };
+
+// WavPack (.wv) is not supported: no pure-Rust decoder for it works with
+// symphonia 0.5, so .wv files are left out rather than listed and failing.
+pub const FORMATS: &[&AudioFormat] = &[&MP3, &FLAC, &WAV, &VORBIS, &OPUS, &MP4, &AAC, &AIFF];
+
+/// Looks a file up by its extension alone.
+pub fn from_extension(path: &Path) -> Option<&'static AudioFormat> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    FORMATS.iter().copied().find(|f| f.extensions.contains(&ext.as_str()))
+}
+
+/// Whether the browser and scanner should list this file.
+pub fn is_audio_file(path: &Path) -> bool {
+    from_extension(path).is_some()
+}
+
+/// Works out the format from the file's contents, so mis-named files are
+/// still played with the right demuxer.
+pub fn sniff(path: &Path) -> Option<&'static AudioFormat> {
+    let mut file = File::open(path).ok()?;
+    let mut head = [0u8; 64];
+    let mut len = read_up_to(&mut file, &mut head)?;
+
+    // Skip an ID3v2 tag; FLAC and AAC files sometimes carry one too
+    if len >= 10 && &head[..3] == b"ID3" {
+        let size = head[6..10].iter().fold(0u64, |acc, b| (acc << 7) | (*b & 0x7f) as u64);
+        let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+        file.seek(SeekFrom::Start(10 + size + footer)).ok()?;
+        len = read_up_to(&mut file, &mut head)?;
+        if len < 2 {
+            return Some(&MP3);
+        }
+    }
+    let head = &head[..len];
+
+    let format: &AudioFormat = match head {
+        [b'f', b'L', b'a', b'C', ..] => &FLAC,
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => &WAV,
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'F' | b'C', ..] => &AIFF,
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => &MP4,
+        // The first Ogg page holds the codec's identification header
+        [b'O', b'g', b'g', b'S', ..] if head.len() >= 36 => match &head[28..36] {
+            b"OpusHead" => &OPUS,
+            [0x7f, b'F', b'L', b'A', b'C', ..] => &FLAC,
+            _ => &VORBIS,
+        },
+        // Frame sync; layer bits of zero mean ADTS rather than MPEG audio
+        [0xff, b, ..] if b & 0xf6 == 0xf0 => &AAC,
+        [0xff, b, ..] if b & 0xe0 == 0xe0 => &MP3,
+        _ => return None,
+    };
+    Some(format)
+}
+
+/// Content first, then the extension.
+pub fn detect(path: &Path) -> Option<&'static AudioFormat> {
+    sniff(path).or_else(|| from_extension(path))
+}
+
+/// Symphonia's built-in codecs plus the ones TAP adds (Opus).
+pub fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<OpusCodec>();
+        registry
+    })
+}
+
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]).ok()? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Some(len)
+}
@@ -0,0 +1,23 @@
+//! The audio engine and the file handling it builds on, shared by the
+//! `tap` binary and the integration tests, which drive the engine through
+//! the null output.
+
+pub mod audio;
+pub mod browser;
+pub mod chapters;
+pub mod cue;
+pub mod decoder;
+pub mod eq;
+pub mod formats;
+pub mod m3u;
+pub mod meter;
+pub mod opus;
+pub mod output;
+pub mod playlist;
+pub mod pls;
+pub mod replaygain;
+pub mod stretch;
+#[cfg(test)]
+mod testing;
+pub mod visualizer;
+pub mod xspf;
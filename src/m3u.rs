@@ -0,0 +1,231 @@
+use crate::cue;
+use crate::playlist::{self, EntryInfo, Locator, PlaylistFile};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+pub fn is_m3u_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u") || ext.eq_ignore_ascii_case("m3u8"))
+}
+
+/// Reads a plain or extended M3U. Entries are resolved against the
+/// playlist's directory. `#EXTALB` and `#EXTGRP` hold until the next one.
+pub fn parse(path: &str) -> Result<PlaylistFile, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read M3U: {}", e))?;
+    // .m3u8 is UTF-8 by definition; plain .m3u may be Latin-1
+    let is_utf8 = Path::new(path).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("m3u8"));
+    let content = if is_utf8 {
+        let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&bytes);
+        String::from_utf8_lossy(bytes).to_string()
+    } else {
+        cue::decode_text(&bytes)
+    };
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    let mut locator = Locator::new(base_dir);
+
+    let mut m3u = PlaylistFile::default();
+    let mut album = None;
+    let mut group = None;
+    let mut info: Option<EntryInfo> = None;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(directive) = line.strip_prefix('#') {
+            let (key, value) = directive.split_once(':').unwrap_or((directive, ""));
+            let value = value.trim();
+            let text = Some(value.to_string()).filter(|v| !v.is_empty());
+            match key.to_ascii_uppercase().as_str() {
+                "EXTINF" => info = Some(parse_extinf(value)),
+                "PLAYLIST" => m3u.name = text,
+                "EXTALB" => album = text,
+                "EXTGRP" => group = text,
+                _ => {}
+            }
+            continue;
+        }
+        let mut entry = info.take().unwrap_or_default();
+        entry.album = album.clone();
+        entry.group = group.clone();
+        m3u.entries.push((locator.resolve(line), entry));
+    }
+    Ok(m3u)
+}
+
+/// `#EXTINF:<seconds> [attributes],<Artist - Title>`, -1 for an unknown
+/// length.
+fn parse_extinf(value: &str) -> EntryInfo {
+    // The title starts after the first comma outside quoted attributes
+    let mut quoted = false;
+    let comma = value.char_indices().find(|&(_, c)| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        c == ',' && !quoted
+    });
+    let (head, text) = match comma {
+        Some((i, _)) => (&value[..i], &value[i + 1..]),
+        None => (value, ""),
+    };
+    let duration = head
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse().ok())
+        .and_then(|s| Duration::try_from_secs_f64(s).ok());
+    EntryInfo::from_text(duration, text)
+}
+
+/// An extended M3U, UTF-8, with `#EXTALB` and `#EXTGRP` written wherever
+/// they change and `#EXTINF` for entries there is anything known about.
+/// With `relative_to`, paths are written relative to that directory where
+/// they can be.
+pub fn write(playlist: &PlaylistFile, relative_to: Option<&Path>) -> String {
+    let mut content = String::from("#EXTM3U\n");
+    if let Some(name) = &playlist.name {
+        content.push_str(&format!("#PLAYLIST:{}\n", name));
+    }
+    let mut album = None;
+    let mut group = None;
+    for (path, info) in &playlist.entries {
+        if info.album != album {
+            album = info.album.clone();
+            content.push_str(&format!("#EXTALB:{}\n", album.as_deref().unwrap_or("")));
+        }
+        if info.group != group {
+            group = info.group.clone();
+            content.push_str(&format!("#EXTGRP:{}\n", group.as_deref().unwrap_or("")));
+        }
+        // Nothing to say rather than the file name, which would read back as
+        // "Artist - Title"
+        let name = info.display_name();
+        if name.is_some() || info.duration.is_some() {
+            let seconds = info.duration.map(|d| d.as_secs_f64().round() as i64).unwrap_or(-1);
+            content.push_str(&format!("#EXTINF:{},{}\n", seconds, name.unwrap_or_default()));
+        }
+        content.push_str(&playlist::written_path(path, relative_to));
+        content.push('\n');
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn entry(artist: Option<&str>, title: Option<&str>, secs: Option<u64>) -> EntryInfo {
+        EntryInfo {
+            duration: secs.map(Duration::from_secs),
+            artist: artist.map(str::to_string),
+            title: title.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_extended_entries() {
+        let dir = TempDir::new("m3u-extended");
+        let path = dir.write(
+            "list.m3u8",
+            "\u{feff}#EXTM3U\n#PLAYLIST:Mix\n#EXTALB:Album\n#EXTGRP:Side A\n\
+             #EXTINF:215 tvg-name=\"a, b\",Artist - Song - Live\nsong.mp3\n\n\
+             #EXTINF:-1,Bare title\n/abs/other.flac\nplain.ogg\n"
+                .as_bytes(),
+        );
+        let m3u = parse(&path).unwrap();
+        assert_eq!(m3u.name.as_deref(), Some("Mix"));
+        assert_eq!(m3u.entries.len(), 3);
+
+        let (song, info) = &m3u.entries[0];
+        assert_eq!(Path::new(song), dir.path().join("song.mp3"));
+        assert_eq!(info.duration, Some(Duration::from_secs(215)));
+        assert_eq!(info.artist.as_deref(), Some("Artist"));
+        assert_eq!(info.title.as_deref(), Some("Song - Live"));
+        assert_eq!(info.album.as_deref(), Some("Album"));
+        assert_eq!(info.group.as_deref(), Some("Side A"));
+
+        let (other, info) = &m3u.entries[1];
+        assert_eq!(other, "/abs/other.flac");
+        assert_eq!((info.duration, info.artist.as_deref()), (None, None));
+        assert_eq!(info.title.as_deref(), Some("Bare title"));
+        // Album and group carry on, #EXTINF does not
+        let (_, info) = &m3u.entries[2];
+        assert_eq!(info.album.as_deref(), Some("Album"));
+        assert_eq!((info.title.as_deref(), info.duration), (None, None));
+    }
+
+    #[test]
+    fn ignores_durations_out_of_range() {
+        for line in ["inf,Title", "1e400,x", "1e30,x", "NaN,x"] {
+            assert_eq!(parse_extinf(line).duration, None, "{}", line);
+        }
+        assert_eq!(parse_extinf("inf,Title").title.as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn reads_latin1_m3u() {
+        let dir = TempDir::new("m3u-latin1");
+        let path = dir.write("list.m3u", b"#EXTINF:1,Caf\xe9\ncafe.mp3\n");
+        let m3u = parse(&path).unwrap();
+        assert_eq!(m3u.entries[0].1.title.as_deref(), Some("Caf\u{e9}"));
+    }
+
+    #[test]
+    fn writes_album_and_group_where_they_change() {
+        let mut first = entry(Some("A"), Some("One"), Some(61));
+        first.album = Some("Album".to_string());
+        let mut second = entry(None, Some("Two"), None);
+        second.album = Some("Album".to_string());
+        second.group = Some("Side B".to_string());
+        let playlist = PlaylistFile {
+            name: Some("Mix".to_string()),
+            entries: vec![("/music/one.mp3".to_string(), first), ("/music/two.mp3".to_string(), second)],
+        };
+        assert_eq!(
+            write(&playlist, None),
+            "#EXTM3U\n#PLAYLIST:Mix\n#EXTALB:Album\n#EXTINF:61,A - One\n/music/one.mp3\n\
+             #EXTGRP:Side B\n#EXTINF:-1,Two\n/music/two.mp3\n"
+        );
+    }
+
+    #[test]
+    fn leaves_out_what_is_not_known() {
+        let playlist = PlaylistFile {
+            name: None,
+            entries: vec![
+                ("/music/01 - Song.mp3".to_string(), EntryInfo::default()),
+                ("/music/02 - Song.mp3".to_string(), entry(None, None, Some(30))),
+            ],
+        };
+        let content = write(&playlist, None);
+        assert_eq!(content, "#EXTM3U\n/music/01 - Song.mp3\n#EXTINF:30,\n/music/02 - Song.mp3\n");
+
+        let dir = TempDir::new("m3u-unknown");
+        let path = dir.write("list.m3u8", content.as_bytes());
+        let m3u = parse(&path).unwrap();
+        for (_, info) in &m3u.entries {
+            assert_eq!((info.artist.as_deref(), info.title.as_deref()), (None, None));
+        }
+        assert_eq!(m3u.entries[1].1.duration, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn round_trips_relative_paths() {
+        let dir = TempDir::new("m3u-relative");
+        let path = dir.write("list.m3u8", b"");
+        let song = dir.path().join("sub").join("song.mp3").to_string_lossy().to_string();
+        let playlist = PlaylistFile {
+            name: None,
+            entries: vec![(song.clone(), entry(Some("A"), Some("B"), Some(5)))],
+        };
+        let content = write(&playlist, Some(dir.path()));
+        assert!(content.contains("\nsub/song.mp3\n"), "{}", content);
+
+        fs::write(&path, content).unwrap();
+        let m3u = parse(&path).unwrap();
+        assert_eq!(Path::new(&m3u.entries[0].0), Path::new(&song));
+        assert_eq!(m3u.entries[0].1.display_name().as_deref(), Some("A - B"));
+    }
+}
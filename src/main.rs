@@ -1,19 +1,27 @@
-mod audio;
-mod playlist;
-mod browser;
+mod bookmarks;
+mod sleep;
 mod config;
+mod waveform;
 
-use audio::AudioEngine;
-use playlist::{Playlist, RepeatMode};
+use tap::{audio, browser, cue, decoder, eq, m3u, output, playlist, pls, replaygain, stretch, visualizer, xspf};
+
+use audio::{AudioEngine, FadeCurve};
+use bookmarks::{Bookmarks, ResumeMode};
+use playlist::{EntryInfo, Playlist, PlaylistFile, RepeatMode};
 use browser::FileBrowser;
 use config::Config;
+use sleep::SleepTimer;
+use visualizer::Visualizer;
+use waveform::WaveformLoader;
 use lofty::{probe::Probe, prelude::Accessor, file::TaggedFileExt};
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout, Alignment, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, Paragraph, Gauge, ListState, Clear, Wrap},
+    widgets::{Block, Borders, List, ListItem, Paragraph, ListState, Clear, Wrap, Sparkline},
+    widgets::canvas::{Canvas, Line as CanvasLine},
+    symbols::Marker,
     Terminal,
 };
 use crossterm::{
@@ -22,19 +30,79 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use std::io;
-use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver};
 use std::thread;
 
+/// Where a playlist was saved and how that went, from the thread saving it.
+type SaveOutcome = (String, Result<(), String>);
+
 enum Modal {
     None,
     Help,
     Settings,
     SavePlaylist,
+    Equalizer,
+    /// Offering to pick the track up at the saved position
+    Resume(std::time::Duration),
+    /// Naming a bookmark at the given position
+    AddBookmark(std::time::Duration),
+    Bookmarks,
+    /// Rewriting a path prefix across the playlist: asking for the old
+    /// prefix, then (with it given) for the new one
+    RebasePlaylist(Option<String>),
+}
+
+/// Adjustable rows of the Settings modal, in display order.
+#[derive(Clone, Copy, PartialEq)]
+enum SettingsItem {
+    Crossfade,
+    CrossfadeCurve,
+    CrossfadeManualSkips,
+    ReplayGain,
+    OutputDevice,
+    SpeedPitch,
+    ResumeMode,
+    ResumeThreshold,
+    SoftLimiter,
+    PauseFade,
+    RelativePaths,
 }
 
+impl SettingsItem {
+    const ALL: [SettingsItem; 11] = [
+        SettingsItem::Crossfade,
+        SettingsItem::CrossfadeCurve,
+        SettingsItem::CrossfadeManualSkips,
+        SettingsItem::ReplayGain,
+        SettingsItem::OutputDevice,
+        SettingsItem::SpeedPitch,
+        SettingsItem::ResumeMode,
+        SettingsItem::ResumeThreshold,
+        SettingsItem::SoftLimiter,
+        SettingsItem::PauseFade,
+        SettingsItem::RelativePaths,
+    ];
+}
+
+/// Choices for the pause, resume and skip fade, in milliseconds.
+const PAUSE_FADE_STEPS: [u32; 7] = [0, 10, 25, 50, 100, 200, 500];
+
+/// Tracks at least this long get their own remembered playback speed.
+const LONG_TRACK: std::time::Duration = std::time::Duration::from_secs(20 * 60);
+
+/// Resume positions this close to either end of a file are not worth keeping.
+const RESUME_MARGIN: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Level meters start at this many dBFS.
+const METER_FLOOR_DB: f32 = -48.0;
+
+/// Seek bar cells from quietest to loudest.
+const WAVEFORM_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
 enum FocusPane {
     Playlist,
+    /// The play-next queue
+    Queue,
     History,
     Browser,
 }
@@ -49,6 +117,10 @@ struct App {
     is_playing: bool,
     show_browser: bool,
     show_info: bool,
+    show_visualizer: bool,
+    visualizer: Visualizer,
+    show_meters: bool,
+    waveforms: WaveformLoader,
     playlist_state: ListState,
     browser_state: ListState,
     history_state: ListState,
@@ -58,26 +130,85 @@ struct App {
     is_muted: bool,
     volume_before_mute: f32,
     last_prev_press: Option<std::time::Instant>,
-    current_track_start: Option<std::time::Instant>,
     current_track_path: Option<String>,
     help_scroll: u16,
     save_path_input: String,
     save_path_cursor: usize,
+    settings_selected: usize,
+    eq_selected_band: usize,
+    bookmarks: Bookmarks,
+    bookmark_name_input: String,
+    bookmark_selected: usize,
+    rebase_input: String,
+    queue_selected: usize,
+    /// Point A of an A-B loop waiting for its B.
+    loop_a: Option<std::time::Duration>,
+    sleep: Option<SleepTimer>,
+    /// The sleep timer paused playback faded out; the volume goes back up
+    /// once playback is heard again.
+    sleep_faded: bool,
 }
 
 impl App {
-    fn new() -> Result<Self, String> {
-        let config = Config::load();
+    /// `device` overrides the saved output device for this session, and
+    /// `output` ("null" or a WAV file path) replaces the sound card entirely.
+    fn new(device: Option<&str>, output: Option<&str>) -> Result<Self, String> {
+        let mut config = Config::load();
+        if config.eq_gains.is_empty() {
+            config.eq_gains = vec![0.0; eq::DEFAULT_BANDS];
+        }
+        if !(stretch::MIN_SPEED..=stretch::MAX_SPEED).contains(&config.playback_speed) {
+            config.playback_speed = 1.0;
+        }
+        if config.resume_threshold_mins == 0 {
+            config.resume_threshold_mins = 20;
+        }
+        let mut status = "Ready".to_string();
+        let audio = match output {
+            Some("null") => AudioEngine::with_output(Box::new(output::null_output())),
+            Some(path) => AudioEngine::with_output(Box::new(output::wav_output(path)?)),
+            None => {
+                let wanted = device.or(config.output_device.as_deref());
+                match AudioEngine::new(wanted) {
+                    Ok(audio) => audio,
+                    Err(e) => match wanted.and_then(|_| AudioEngine::new(None).ok()) {
+                        Some(audio) => {
+                            status = format!("{}, using the default device", e);
+                            audio
+                        }
+                        // No sound card at all; keep the player usable
+                        None => {
+                            status = format!("No audio output ({}), playing silently", e);
+                            AudioEngine::with_output(Box::new(output::null_output()))
+                        }
+                    },
+                }
+            }
+        };
+        audio.set_crossfade(
+            std::time::Duration::from_secs(config.crossfade_secs as u64),
+            config.crossfade_curve,
+        );
+        audio.set_replaygain(config.replaygain_mode.resolve(false));
+        audio.set_equalizer(config.eq_enabled, &config.eq_gains);
+        audio.set_speed(config.playback_speed, !config.speed_shifts_pitch);
+        audio.set_limiter(config.soft_limiter);
+        audio.set_transport_fade(std::time::Duration::from_millis(config.pause_fade_ms() as u64));
+        let visualizer = Visualizer::new(audio.sample_tap());
         Ok(Self {
-            audio: AudioEngine::new()?,
+            audio,
             playlist: Playlist::new(),
             browser: FileBrowser::new(),
             config,
             volume: 1.0,
-            status: "Ready".to_string(),
+            status,
             is_playing: false,
             show_browser: false,
             show_info: false,
+            show_visualizer: false,
+            visualizer,
+            show_meters: false,
+            waveforms: WaveformLoader::new(),
             playlist_state: ListState::default(),
             browser_state: ListState::default(),
             history_state: ListState::default(),
@@ -87,18 +218,27 @@ impl App {
             is_muted: false,
             volume_before_mute: 1.0,
             last_prev_press: None,
-            current_track_start: None,
             current_track_path: None,
             help_scroll: 0,
             save_path_input: String::new(),
             save_path_cursor: 0,
+            settings_selected: 0,
+            eq_selected_band: 0,
+            bookmarks: Bookmarks::load(),
+            bookmark_name_input: String::new(),
+            rebase_input: String::new(),
+            queue_selected: 0,
+            bookmark_selected: 0,
+            loop_a: None,
+            sleep: None,
+            sleep_faded: false,
         })
     }
 
-    fn add_to_history_if_played_enough(&mut self) {
-        if let (Some(start), Some(ref path)) = (self.current_track_start, &self.current_track_path) {
-            let elapsed = start.elapsed().as_secs();
-            if elapsed >= 15 && (self.history.is_empty() || self.history[0] != *path) {
+    /// `listened` is how long the outgoing track was actually heard for.
+    fn add_to_history_if_played_enough(&mut self, listened: std::time::Duration) {
+        if let Some(ref path) = self.current_track_path {
+            if listened.as_secs() >= 15 && (self.history.is_empty() || self.history[0] != *path) {
                 self.history.insert(0, path.clone());
                 if self.history.len() > 50 {
                     self.history.truncate(50);
@@ -109,39 +249,670 @@ impl App {
 
     fn play_current(&mut self) {
         // Add previous track to history if it was played long enough
-        self.add_to_history_if_played_enough();
+        self.add_to_history_if_played_enough(self.audio.get_listened());
+        self.remember_position();
+        self.loop_a = None;
         
         if let Some(track) = self.playlist.current() {
             self.audio.stop();
+            self.audio.set_speed(self.speed_for(track), !self.config.speed_shifts_pitch);
             match self.audio.play(track) {
                 Ok(_) => {
                     self.status = format!("Playing: {}", Self::get_filename(track));
                     self.is_playing = true;
-                    // Track when this song started
-                    self.current_track_start = Some(std::time::Instant::now());
                     self.current_track_path = Some(track.to_string());
+                    self.offer_resume();
                 }
                 Err(e) => self.status = format!("Error: {}", e),
             }
         }
+        self.queue_next_track();
+    }
+
+    /// Switches to the playlist's current track after a manual skip,
+    /// crossfading into it when that is enabled.
+    fn skip_to_current(&mut self) {
+        let can_fade = self.config.crossfade_manual_skips
+            && self.audio.crossfade_enabled()
+            && self.is_playing
+            && !self.audio.is_paused()
+            && !self.audio.is_finished();
+        if !can_fade {
+            self.play_current();
+            return;
+        }
+
+        self.add_to_history_if_played_enough(self.audio.get_listened());
+        self.remember_position();
+        self.loop_a = None;
+        if let Some(track) = self.playlist.current() {
+            self.audio.set_speed(self.speed_for(track), !self.config.speed_shifts_pitch);
+            match self.audio.crossfade_to(track) {
+                Ok(_) => {
+                    self.status = format!("Playing: {}", Self::get_filename(track));
+                    self.current_track_path = Some(track.to_string());
+                    self.offer_resume();
+                }
+                Err(e) => self.status = format!("Error: {}", e),
+            }
+        }
+        self.queue_next_track();
+    }
+
+    fn settings_value(&self, item: SettingsItem) -> String {
+        match item {
+            SettingsItem::Crossfade => {
+                if self.config.crossfade_secs == 0 {
+                    "Off (gapless)".to_string()
+                } else {
+                    format!("{}s", self.config.crossfade_secs)
+                }
+            }
+            SettingsItem::CrossfadeCurve => self.config.crossfade_curve.label().to_string(),
+            SettingsItem::CrossfadeManualSkips => {
+                if self.config.crossfade_manual_skips { "On" } else { "Off" }.to_string()
+            }
+            SettingsItem::ReplayGain => self.config.replaygain_mode.label().to_string(),
+            SettingsItem::OutputDevice => {
+                self.config.output_device.clone().unwrap_or_else(|| "System default".to_string())
+            }
+            SettingsItem::SpeedPitch => {
+                if self.config.speed_shifts_pitch { "Shifts with speed" } else { "Preserved" }.to_string()
+            }
+            SettingsItem::ResumeMode => self.config.resume_mode.label().to_string(),
+            SettingsItem::ResumeThreshold => format!("Files over {} min", self.config.resume_threshold_mins),
+            SettingsItem::SoftLimiter => if self.config.soft_limiter { "On" } else { "Off" }.to_string(),
+            SettingsItem::PauseFade => match self.config.pause_fade_ms() {
+                0 => "Off".to_string(),
+                ms => format!("{} ms", ms),
+            },
+            SettingsItem::RelativePaths => {
+                if self.config.relative_playlist_paths { "Relative" } else { "Absolute" }.to_string()
+            }
+        }
+    }
+
+    fn settings_label(item: SettingsItem) -> &'static str {
+        match item {
+            SettingsItem::Crossfade => "Crossfade",
+            SettingsItem::CrossfadeCurve => "Fade curve",
+            SettingsItem::CrossfadeManualSkips => "Crossfade on skip",
+            SettingsItem::ReplayGain => "ReplayGain",
+            SettingsItem::OutputDevice => "Output device",
+            SettingsItem::SpeedPitch => "Pitch at speed",
+            SettingsItem::ResumeMode => "Resume position",
+            SettingsItem::ResumeThreshold => "Remember position",
+            SettingsItem::SoftLimiter => "Soft limiter",
+            SettingsItem::PauseFade => "Pause/skip fade",
+            SettingsItem::RelativePaths => "Saved playlist paths",
+        }
+    }
+
+    /// Steps the selected setting up or down and saves the config.
+    fn adjust_setting(&mut self, forward: bool) {
+        match SettingsItem::ALL[self.settings_selected] {
+            SettingsItem::Crossfade => {
+                self.config.crossfade_secs = if forward {
+                    (self.config.crossfade_secs + 1).min(12)
+                } else {
+                    self.config.crossfade_secs.saturating_sub(1)
+                };
+            }
+            SettingsItem::CrossfadeCurve => {
+                self.config.crossfade_curve = match self.config.crossfade_curve {
+                    FadeCurve::Linear => FadeCurve::EqualPower,
+                    FadeCurve::EqualPower => FadeCurve::Linear,
+                };
+            }
+            SettingsItem::CrossfadeManualSkips => {
+                self.config.crossfade_manual_skips = !self.config.crossfade_manual_skips;
+            }
+            SettingsItem::ReplayGain => {
+                self.config.replaygain_mode = self.config.replaygain_mode.cycle(forward);
+            }
+            SettingsItem::OutputDevice => self.cycle_output_device(forward),
+            SettingsItem::SpeedPitch => {
+                self.config.speed_shifts_pitch = !self.config.speed_shifts_pitch;
+                self.audio.set_speed(self.audio.get_speed(), !self.config.speed_shifts_pitch);
+            }
+            SettingsItem::ResumeMode => {
+                self.config.resume_mode = self.config.resume_mode.cycle(forward);
+            }
+            SettingsItem::ResumeThreshold => {
+                let mins = self.config.resume_threshold_mins;
+                self.config.resume_threshold_mins = if forward {
+                    (mins + 5).min(180)
+                } else {
+                    mins.saturating_sub(5).max(5)
+                };
+            }
+            SettingsItem::SoftLimiter => {
+                self.config.soft_limiter = !self.config.soft_limiter;
+                self.audio.set_limiter(self.config.soft_limiter);
+            }
+            SettingsItem::PauseFade => {
+                let current = self.config.pause_fade_ms();
+                let steps = PAUSE_FADE_STEPS.iter().copied();
+                let next = if forward {
+                    steps.filter(|ms| *ms > current).min()
+                } else {
+                    steps.filter(|ms| *ms < current).max()
+                };
+                let ms = next.unwrap_or(current);
+                self.config.pause_fade_ms = Some(ms);
+                self.audio.set_transport_fade(std::time::Duration::from_millis(ms as u64));
+            }
+            SettingsItem::RelativePaths => {
+                self.config.relative_playlist_paths = !self.config.relative_playlist_paths;
+            }
+        }
+        self.audio.set_crossfade(
+            std::time::Duration::from_secs(self.config.crossfade_secs as u64),
+            self.config.crossfade_curve,
+        );
+        self.apply_replaygain();
+        self.config.save();
+    }
+
+    /// Switches to the next (or previous) output device, with the system
+    /// default first in the list.
+    fn cycle_output_device(&mut self, forward: bool) {
+        let mut choices: Vec<Option<String>> = vec![None];
+        choices.extend(output::output_devices().into_iter().map(Some));
+        let current = choices.iter().position(|d| *d == self.config.output_device).unwrap_or(0);
+        let next = if forward {
+            (current + 1) % choices.len()
+        } else {
+            (current + choices.len() - 1) % choices.len()
+        };
+        let device = choices[next].clone();
+        match self.audio.set_output_device(device.as_deref()) {
+            Ok(()) => {
+                self.status = format!("Output: {}", device.as_deref().unwrap_or("System default"));
+                self.config.output_device = device;
+            }
+            Err(e) => self.status = format!("Error: {}", e),
+        }
     }
 
-    fn get_filename(path: &str) -> &str {
-        path.split('/').last().unwrap_or(path)
+    /// Speed to play `path` at: its own remembered speed if it is a long
+    /// file that has one, otherwise the general playback speed.
+    fn speed_for(&self, path: &str) -> f32 {
+        self.config.track_speeds.get(path).copied().unwrap_or(self.config.playback_speed)
     }
 
-    fn get_metadata(path: &str) -> (String, String, String, String) {
+    /// Steps the playback speed, or resets it to 1.0x when `delta` is zero.
+    /// Long files remember their speed separately from everything else.
+    fn change_speed(&mut self, delta: f32) {
+        let speed = if delta == 0.0 {
+            1.0
+        } else {
+            // Round to the step so repeated presses don't accumulate error
+            ((self.audio.get_speed() + delta) * 10.0).round() / 10.0
+        };
+        let speed = speed.clamp(stretch::MIN_SPEED, stretch::MAX_SPEED);
+        self.audio.set_speed(speed, !self.config.speed_shifts_pitch);
+
+        let is_long = self.audio.get_duration().is_some_and(|d| d >= LONG_TRACK);
+        match self.current_track_path.clone() {
+            Some(path) if is_long => {
+                if speed == self.config.playback_speed {
+                    self.config.track_speeds.remove(&path);
+                } else {
+                    self.config.track_speeds.insert(path, speed);
+                }
+            }
+            _ => self.config.playback_speed = speed,
+        }
+        self.config.save();
+        self.status = format!("Speed: {:.1}x", speed);
+    }
+
+    /// Pushes the ReplayGain mode to the engine; `Auto` follows shuffle.
+    fn apply_replaygain(&self) {
+        self.audio.set_replaygain(self.config.replaygain_mode.resolve(self.playlist.is_shuffle()));
+    }
+
+    /// Built-in presets followed by the user's own.
+    fn eq_presets(&self) -> Vec<eq::EqPreset> {
+        let mut presets = eq::builtin_presets();
+        presets.extend(self.config.eq_presets.iter().cloned());
+        presets
+    }
+
+    fn apply_equalizer(&mut self) {
+        self.audio.set_equalizer(self.config.eq_enabled, &self.config.eq_gains);
+        self.config.save();
+    }
+
+    fn adjust_eq_band(&mut self, delta: f32) {
+        if self.config.eq_gains.is_empty() {
+            self.config.eq_gains = vec![0.0; eq::DEFAULT_BANDS];
+        }
+        let band = self.eq_selected_band.min(self.config.eq_gains.len() - 1);
+        let gain = &mut self.config.eq_gains[band];
+        *gain = (*gain + delta).clamp(-eq::MAX_GAIN_DB, eq::MAX_GAIN_DB);
+        self.config.eq_preset = None;
+        self.config.eq_enabled = true;
+        self.apply_equalizer();
+    }
+
+    /// Loads the preset after (or before) the current one.
+    fn cycle_eq_preset(&mut self, forward: bool) {
+        let presets = self.eq_presets();
+        let current = self.config.eq_preset.as_ref()
+            .and_then(|name| presets.iter().position(|p| &p.name == name));
+        let next = match (current, forward) {
+            (Some(i), true) => (i + 1) % presets.len(),
+            (Some(i), false) => (i + presets.len() - 1) % presets.len(),
+            (None, _) => 0,
+        };
+        let preset = &presets[next];
+        self.config.eq_gains = preset.gains.clone();
+        self.config.eq_preset = Some(preset.name.clone());
+        self.config.eq_enabled = true;
+        self.eq_selected_band = self.eq_selected_band.min(preset.gains.len().saturating_sub(1));
+        self.apply_equalizer();
+        self.status = format!("EQ preset: {}", preset.name);
+    }
+
+    /// Saves the current bands as a new user preset named "Custom N".
+    fn save_eq_preset(&mut self) {
+        let presets = self.eq_presets();
+        let name = (1..)
+            .map(|n| format!("Custom {}", n))
+            .find(|name| !presets.iter().any(|p| &p.name == name))
+            .unwrap();
+        self.config.eq_presets.push(eq::EqPreset {
+            name: name.clone(),
+            gains: self.config.eq_gains.clone(),
+        });
+        self.config.eq_preset = Some(name.clone());
+        self.config.save();
+        self.status = format!("Saved EQ preset: {}", name);
+    }
+
+    /// Deletes the selected preset if it is a user preset.
+    fn delete_eq_preset(&mut self) {
+        let Some(name) = self.config.eq_preset.clone() else {
+            return;
+        };
+        let before = self.config.eq_presets.len();
+        self.config.eq_presets.retain(|p| p.name != name);
+        if self.config.eq_presets.len() < before {
+            self.config.eq_preset = None;
+            self.config.save();
+            self.status = format!("Deleted EQ preset: {}", name);
+        } else {
+            self.status = "Built-in presets cannot be deleted".to_string();
+        }
+    }
+
+    /// Hands the upcoming playlist entry to the audio engine so it can start
+    /// without a gap. Call whenever the playlist order or repeat mode changes.
+    fn queue_next_track(&mut self) {
+        if self.current_track_path.is_none() {
+            return;
+        }
+        match self.playlist.peek_next() {
+            Some(next) => {
+                if let Err(e) = self.audio.queue_next(next) {
+                    self.status = format!("Error: {}", e);
+                }
+            }
+            None => self.audio.clear_next(),
+        }
+    }
+
+    /// Called once the engine has moved on to the queued track by itself.
+    /// The playlist follows `path`, the track that actually started, since
+    /// the queue may have changed after the engine took it.
+    fn on_track_advanced(&mut self, path: &str, listened: std::time::Duration) {
+        self.add_to_history_if_played_enough(listened);
+        self.loop_a = None;
+        // The previous track played to the end, so there is nothing to resume
+        if let Some(finished) = &self.current_track_path {
+            if self.bookmarks.forget_position(finished) {
+                self.bookmarks.save();
+            }
+        }
+        self.playlist.advance_to(path);
+        let sleep_ran_out = self.count_sleep_track();
+
+        if let Some(track) = self.playlist.current() {
+            self.status = format!("Playing: {}", Self::get_filename(track));
+            self.current_track_path = Some(track.to_string());
+            self.audio.set_speed(self.speed_for(track), !self.config.speed_shifts_pitch);
+            self.offer_resume();
+        }
+        self.queue_next_track();
+        if sleep_ran_out {
+            self.sleep_pause();
+        }
+    }
+
+    /// Saves where the playing track was left off, if it is long enough to
+    /// be worth resuming. Positions right at the start or end are dropped.
+    fn remember_position(&mut self) {
+        let Some(path) = self.current_track_path.clone() else {
+            return;
+        };
+        let threshold = std::time::Duration::from_secs(self.config.resume_threshold_mins as u64 * 60);
+        let Some(duration) = self.audio.get_duration().filter(|d| *d >= threshold) else {
+            return;
+        };
+        if self.config.resume_mode == ResumeMode::Off {
+            return;
+        }
+
+        let position = self.audio.get_position();
+        if position < RESUME_MARGIN || duration.saturating_sub(position) < RESUME_MARGIN {
+            self.bookmarks.forget_position(&path);
+        } else {
+            self.bookmarks.set_position(&path, position);
+        }
+        self.bookmarks.save();
+    }
+
+    /// Resumes the track that just started from its saved position, or asks
+    /// first, depending on the resume setting.
+    fn offer_resume(&mut self) {
+        let Some(path) = self.current_track_path.as_deref() else {
+            return;
+        };
+        let Some(position) = self.bookmarks.position(path) else {
+            return;
+        };
+        match self.config.resume_mode {
+            ResumeMode::Off => {}
+            ResumeMode::Ask => self.modal = Modal::Resume(position),
+            ResumeMode::Auto => {
+                self.audio.seek_to(position);
+                self.status = format!("Resumed at {}", Self::format_duration(position.as_secs()));
+            }
+        }
+    }
+
+    /// Also turns off a track sleep timer, as both decide where playback
+    /// ends.
+    fn toggle_stop_after_current(&mut self) {
+        let stop = !self.playlist.stop_after_current();
+        self.playlist.set_stop_after_current(stop);
+        self.queue_next_track();
+        self.status = if stop { "Stopping after this track" } else { "Playing on after this track" }.to_string();
+        if matches!(self.sleep, Some(SleepTimer::Tracks { .. })) {
+            self.sleep = None;
+            self.restore_volume();
+            self.status += ", sleep timer off";
+        }
+    }
+
+    /// Steps through the sleep timer's time settings, or its track counts
+    /// when `by_tracks` is set. Each step starts the timer over.
+    fn cycle_sleep_timer(&mut self, by_tracks: bool) {
+        self.sleep = if by_tracks {
+            SleepTimer::cycle_tracks(self.sleep)
+        } else {
+            SleepTimer::cycle_minutes(self.sleep)
+        };
+        self.restore_volume();
+        self.status = match self.sleep {
+            Some(SleepTimer::At { minutes, .. }) => format!("Sleep timer: {} min", minutes),
+            Some(SleepTimer::Tracks { total: 1, .. }) => "Sleep timer: after this track".to_string(),
+            Some(SleepTimer::Tracks { total, .. }) => format!("Sleep timer: after {} tracks", total),
+            None => "Sleep timer off".to_string(),
+        };
+    }
+
+    /// A track finished by itself; counts down a track sleep timer. True
+    /// once it has run out, for the caller to pause with `sleep_pause`.
+    fn count_sleep_track(&mut self) -> bool {
+        let Some(SleepTimer::Tracks { left, total }) = self.sleep else {
+            return false;
+        };
+        let left = left.saturating_sub(1);
+        self.sleep = (left > 0).then_some(SleepTimer::Tracks { left, total });
+        left == 0
+    }
+
+    /// Fades the volume over the sleep timer's last stretch, and pauses once
+    /// a time-based timer runs out; a track timer runs out as its last track
+    /// ends. Called on every pass of the main loop.
+    fn update_sleep_timer(&mut self) {
+        let remaining = match self.sleep {
+            None => {
+                if self.sleep_faded && self.is_playing {
+                    self.sleep_faded = false;
+                    self.restore_volume();
+                }
+                return;
+            }
+            Some(SleepTimer::At { ends, .. }) => ends.saturating_duration_since(std::time::Instant::now()),
+            Some(SleepTimer::Tracks { left: 1, .. }) => match self.audio.get_duration() {
+                Some(duration) if self.is_playing => {
+                    duration.saturating_sub(self.audio.get_position()).div_f32(self.audio.get_speed())
+                }
+                _ => return,
+            },
+            Some(SleepTimer::Tracks { .. }) => return,
+        };
+
+        if remaining.is_zero() && matches!(self.sleep, Some(SleepTimer::At { .. })) {
+            self.sleep = None;
+            self.sleep_pause();
+            return;
+        }
+        if !self.is_muted {
+            self.audio.set_volume(self.volume * sleep::fade_factor(remaining));
+        }
+    }
+
+    /// Pauses once the sleep timer has run out. The volume stays down until
+    /// playback resumes, so the pause fade stays silent.
+    fn sleep_pause(&mut self) {
+        if self.is_playing {
+            self.audio.pause();
+            self.is_playing = false;
+            self.status = "Sleep timer: paused".to_string();
+        }
+        self.sleep_faded = true;
+    }
+
+    /// Puts the volume back after a sleep timer fade.
+    fn restore_volume(&self) {
+        if !self.is_muted {
+            self.audio.set_volume(self.volume);
+        }
+    }
+
+    /// Opens the bookmark naming prompt for the current position, suggesting
+    /// the chapter name when there is one.
+    fn open_add_bookmark(&mut self) {
+        if self.current_track_path.is_none() {
+            self.status = "Nothing playing to bookmark".to_string();
+            return;
+        }
+        let position = self.audio.get_position();
+        let chapters = self.audio.chapters();
+        self.bookmark_name_input = match self.audio.current_chapter().and_then(|i| chapters.get(i)) {
+            Some(chapter) => chapter.title.clone(),
+            None => format!("Bookmark at {}", Self::format_duration(position.as_secs())),
+        };
+        self.modal = Modal::AddBookmark(position);
+    }
+
+    fn add_bookmark(&mut self, position: std::time::Duration) {
+        let Some(path) = self.current_track_path.clone() else {
+            return;
+        };
+        let name = self.bookmark_name_input.trim().to_string();
+        self.bookmarks.add(&path, name.clone(), position);
+        self.bookmarks.save();
+        self.status = format!("Bookmarked: {}", name);
+    }
+
+    /// Queues a track to play after the current one, behind anything
+    /// queued already.
+    fn play_next(&mut self, path: String) {
+        let name = Self::get_filename(&path);
+        self.playlist.enqueue(path);
+        self.status = match self.playlist.queue().len() {
+            1 => format!("Playing next: {}", name),
+            n => format!("Queued ({}): {}", n, name),
+        };
+        self.queue_next_track();
+    }
+
+    /// Opens the rebase dialog with the folder the whole playlist is in.
+    fn open_rebase(&mut self) {
+        if self.playlist.tracks().is_empty() {
+            self.status = "Playlist is empty".to_string();
+            return;
+        }
+        self.rebase_input = self.playlist.common_dir()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.modal = Modal::RebasePlaylist(None);
+    }
+
+    /// Moves every entry under `from` to the same place under `to`.
+    fn rebase_playlist(&mut self, from: &str, to: &str) {
+        let (from, to) = (std::path::Path::new(from), std::path::Path::new(to));
+        let changed = self.playlist.rebase(from, to);
+        if let Some(current) = &self.current_track_path {
+            if let Ok(rest) = std::path::Path::new(current).strip_prefix(from) {
+                self.current_track_path = Some(to.join(rest).to_string_lossy().to_string());
+            }
+        }
+        self.queue_next_track();
+        self.status = format!("Rebased {} of {} entries", changed, self.playlist.tracks().len());
+    }
+
+    /// Plays the file a bookmark belongs to, if it isn't already playing,
+    /// and jumps to the bookmark.
+    fn jump_to_bookmark(&mut self, index: usize) {
+        let Some((path, bookmark)) = self.bookmarks.list(self.current_track_path.as_deref()).get(index).cloned() else {
+            return;
+        };
+        if self.current_track_path.as_deref() != Some(path.as_str()) || self.audio.is_finished() {
+            match self.playlist.tracks().iter().position(|t| *t == path) {
+                Some(pos) => self.playlist.select_index(pos),
+                None => {
+                    self.playlist.add_track(path.clone());
+                    self.playlist.select_index(self.playlist.tracks().len() - 1);
+                }
+            }
+            self.playlist.play_selected();
+            self.play_current();
+        }
+        self.modal = Modal::None;
+        self.audio.seek_to(bookmark.position());
+        self.status = format!("Bookmark: {}", bookmark.name);
+    }
+
+    fn delete_bookmark(&mut self, index: usize) {
+        let list = self.bookmarks.list(self.current_track_path.as_deref());
+        if let Some((path, bookmark)) = list.get(index) {
+            self.bookmarks.remove(path, bookmark.position_ms);
+            self.bookmarks.save();
+            self.bookmark_selected = self.bookmark_selected.min(list.len().saturating_sub(2));
+        }
+    }
+
+    /// First press marks A, the second marks B and starts looping, the
+    /// third clears the loop.
+    fn cycle_ab_loop(&mut self) {
+        if !self.is_playing {
+            return;
+        }
+        if self.audio.ab_loop().is_some() {
+            self.audio.clear_ab_loop();
+            self.status = "Loop cleared".to_string();
+            return;
+        }
+        let position = self.audio.get_position();
+        match self.loop_a.take() {
+            None => {
+                self.loop_a = Some(position);
+                self.status = format!("Loop A: {}", Self::format_duration(position.as_secs()));
+            }
+            Some(a) => match self.audio.set_ab_loop(a, position) {
+                Ok(()) => {
+                    if let Some((a, b)) = self.audio.ab_loop() {
+                        self.status = format!(
+                            "Looping {} - {}",
+                            Self::format_duration(a.as_secs()),
+                            Self::format_duration(b.as_secs())
+                        );
+                    }
+                }
+                Err(e) => {
+                    self.loop_a = Some(a);
+                    self.status = format!("Error: {}", e);
+                }
+            },
+        }
+    }
+
+    fn toggle_visualizer(&mut self) {
+        self.show_visualizer = !self.show_visualizer;
+        self.visualizer.set_enabled(self.show_visualizer);
+    }
+
+    /// Moves to the next or previous chapter of the playing track.
+    fn jump_chapter(&mut self, forward: bool) {
+        let moved = if forward {
+            self.audio.next_chapter()
+        } else {
+            self.audio.previous_chapter()
+        };
+        if !moved {
+            self.status = if self.audio.chapters().is_empty() {
+                "No chapters in this track".to_string()
+            } else {
+                "No more chapters".to_string()
+            };
+            return;
+        }
+        let chapters = self.audio.chapters();
+        if let Some(chapter) = self.audio.current_chapter().and_then(|i| chapters.get(i)) {
+            self.status = format!("Chapter: {}", chapter.title);
+        }
+    }
+
+    /// File name, or "03. Performer - Title" for CUE sheet entries.
+    fn get_filename(path: &str) -> String {
+        cue::display_name(path)
+            .unwrap_or_else(|| path.split('/').next_back().unwrap_or(path).to_string())
+    }
+
+    /// Title, artist, album and year from the tags, falling back to what
+    /// the playlist file said.
+    fn get_metadata(path: &str, info: Option<&EntryInfo>) -> (String, String, String, String) {
+        if let Some((sheet, track)) = cue::lookup(path) {
+            return (
+                track.title.unwrap_or_else(|| Self::get_filename(path)),
+                track.performer.unwrap_or_else(|| "Unknown Artist".to_string()),
+                sheet.title.clone().unwrap_or_else(|| "Unknown Album".to_string()),
+                sheet.date.clone().unwrap_or_else(|| "Unknown".to_string()),
+            );
+        }
+        let info = info.cloned().unwrap_or_default();
+        let (mut title, mut artist, mut album, mut year) = (info.title, info.artist, info.album, None);
         if let Ok(tagged_file) = Probe::open(path).and_then(|p| p.read()) {
-            let tag = tagged_file.primary_tag().or_else(|| tagged_file.first_tag());
-            if let Some(tag) = tag {
-                let artist = tag.artist().as_deref().unwrap_or("Unknown Artist").to_string();
-                let album = tag.album().as_deref().unwrap_or("Unknown Album").to_string();
-                let title = tag.title().as_deref().unwrap_or(Self::get_filename(path)).to_string();
-                let year = tag.year().map(|y| y.to_string()).unwrap_or_else(|| "Unknown".to_string());
-                return (title, artist, album, year);
+            if let Some(tag) = tagged_file.primary_tag().or_else(|| tagged_file.first_tag()) {
+                title = tag.title().map(|t| t.to_string()).or(title);
+                artist = tag.artist().map(|a| a.to_string()).or(artist);
+                album = tag.album().map(|a| a.to_string()).or(album);
+                year = tag.year().map(|y| y.to_string());
             }
         }
-        (Self::get_filename(path).to_string(), "Unknown Artist".to_string(), "Unknown Album".to_string(), "Unknown".to_string())
+        (
+            title.unwrap_or_else(|| Self::get_filename(path)),
+            artist.unwrap_or_else(|| "Unknown Artist".to_string()),
+            album.unwrap_or_else(|| "Unknown Album".to_string()),
+            year.unwrap_or_else(|| "Unknown".to_string()),
+        )
     }
 
     fn format_duration(secs: u64) -> String {
@@ -150,19 +921,40 @@ impl App {
         format!("{:02}:{:02}", mins, secs)
     }
 
-    fn save_playlist_m3u(&self, path: &str) -> Result<(), String> {
+    /// Writes the playlist as M3U, PLS or XSPF going by the extension, with
+    /// relative paths if so set. Entries the playlist has no details for
+    /// are probed for their tags and length on another thread, which sends
+    /// back the path and how the save went.
+    fn save_playlist(&self, path: &str) -> Result<Receiver<SaveOutcome>, String> {
         let tracks = self.playlist.tracks();
         if tracks.is_empty() {
             return Err("Playlist is empty".to_string());
         }
-        
-        let mut content = String::from("#EXTM3U\n");
-        for track in tracks {
-            content.push_str(track);
-            content.push('\n');
-        }
-        
-        std::fs::write(path, content).map_err(|e| format!("Failed to save: {}", e))
+
+        let entries: Vec<(String, EntryInfo)> = tracks
+            .iter()
+            .map(|track| (track.clone(), self.playlist.info(track).cloned().unwrap_or_default()))
+            .collect();
+        let name = self.playlist.name().map(str::to_string);
+        let relative = self.config.relative_playlist_paths;
+        let path = path.to_string();
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let entries = entries
+                .into_iter()
+                .map(|(track, info)| {
+                    if info.title.is_some() || info.duration.is_some() {
+                        return (track, info);
+                    }
+                    // Keep the album and group the entry sat under
+                    let probed = EntryInfo::probe(&track);
+                    (track, EntryInfo { album: info.album.or(probed.album), group: info.group, ..probed })
+                })
+                .collect();
+            let result = write_playlist(&path, &PlaylistFile { name, entries }, relative);
+            let _ = sender.send((path, result));
+        });
+        Ok(receiver)
     }
 
     fn get_default_playlist_path(&self) -> String {
@@ -179,6 +971,57 @@ impl App {
     }
 }
 
+/// Writes `playlist` to `path` in the format its extension names, M3U
+/// when it names none.
+fn write_playlist(path: &str, playlist: &PlaylistFile, relative: bool) -> Result<(), String> {
+    let file = std::path::Path::new(path);
+    let relative_to = file.parent().filter(|_| relative);
+    let content = if pls::is_pls_file(file) {
+        pls::write(playlist, relative_to)
+    } else if xspf::is_xspf_file(file) {
+        xspf::write(playlist, relative_to)
+    } else {
+        m3u::write(playlist, relative_to)
+    };
+    std::fs::write(path, content).map_err(|e| format!("Failed to save: {}", e))
+}
+
+/// One channel of the level meters: RMS as a bar, peak as a marker, and the
+/// peak in dB at the end.
+fn meter_line(label: &str, peak: f32, rms: f32, width: usize) -> Line<'static> {
+    let db = |level: f32| 20.0 * level.max(1e-6).log10();
+    let cells = width.saturating_sub(10);
+    let position = |level: f32| {
+        (((db(level) - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0) * cells as f32) as usize
+    };
+    let (rms_cells, peak_cell) = (position(rms), position(peak));
+    let zone = |cell: usize| {
+        let cell_db = METER_FLOOR_DB * (1.0 - (cell + 1) as f32 / cells.max(1) as f32);
+        if cell_db > -3.0 {
+            Color::Red
+        } else if cell_db > -12.0 {
+            Color::Yellow
+        } else {
+            Color::Green
+        }
+    };
+    let mut spans = vec![Span::raw(format!("{} ", label))];
+    for cell in 0..cells {
+        let symbol = if cell < rms_cells {
+            "█"
+        } else if cell + 1 == peak_cell {
+            "▌"
+        } else {
+            "·"
+        };
+        let color = if symbol == "·" { Color::DarkGray } else { zone(cell) };
+        spans.push(Span::styled(symbol, Style::default().fg(color)));
+    }
+    let peak_text = if peak < 1e-6 { "-inf".to_string() } else { format!("{:.1}", db(peak)) };
+    spans.push(Span::raw(format!(" {:>5} dB", peak_text)));
+    Line::from(spans)
+}
+
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
     let popup_layout = Layout::default()
         .direction(Direction::Vertical)
@@ -200,15 +1043,55 @@ fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut app = App::new()?;
+    let args: Vec<String> = std::env::args().collect();
+
+    // Subcommands run without the TUI
+    if args.get(1).map(String::as_str) == Some("replaygain") {
+        if let Err(e) = replaygain::run_command(&args[2..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut device = None;
+    let mut output_arg = None;
+    let mut playlist_arg = None;
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--list-devices" => {
+                for name in output::output_devices() {
+                    println!("{}", name);
+                }
+                return Ok(());
+            }
+            "--device" => match rest.next() {
+                Some(name) => device = Some(name.clone()),
+                None => {
+                    eprintln!("--device needs a device name (see --list-devices)");
+                    std::process::exit(1);
+                }
+            },
+            "--output" => match rest.next() {
+                Some(target) => output_arg = Some(target.clone()),
+                None => {
+                    eprintln!("--output needs \"null\" or a .wav file path");
+                    std::process::exit(1);
+                }
+            },
+            _ => playlist_arg = Some(arg.clone()),
+        }
+    }
+
+    let mut app = App::new(device.as_deref(), output_arg.as_deref())?;
     
     // Load example playlist if provided as argument
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 {
-        if let Err(e) = app.playlist.load_m3u(&args[1]) {
+    if let Some(playlist_path) = playlist_arg {
+        if let Err(e) = app.playlist.load(&playlist_path) {
             eprintln!("Failed to load playlist: {}", e);
         } else {
-            app.config.last_playlist = Some(args[1].clone());
+            app.config.last_playlist = Some(playlist_path);
         }
     } else if !app.config.current_playlist_tracks.is_empty() {
         // Restore last session's playlist
@@ -229,24 +1112,47 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut terminal = Terminal::new(backend)?;
 
     let mut last_progress_update = std::time::Instant::now();
+    let mut last_visualizer_frame = std::time::Instant::now();
     let mut needs_redraw = true;
     let mut scan_receiver: Option<Receiver<std::path::PathBuf>> = None;
     let mut scan_count = 0;
+    let mut save_receiver: Option<Receiver<SaveOutcome>> = None;
 
     loop {
+        // The engine switches to the queued track on its own; catch up with it
+        if let Some((path, listened)) = app.audio.take_track_change() {
+            app.on_track_advanced(&path, listened);
+            needs_redraw = true;
+        }
+
+        app.update_sleep_timer();
+
         // Check if track finished and auto-play next
-        if app.is_playing && app.audio.is_finished() && app.playlist.tracks().len() > 0 {
+        let has_next = !app.playlist.tracks().is_empty()
+            || !app.playlist.queue().is_empty()
+            || app.playlist.playing_from_queue();
+        if app.is_playing && app.audio.is_finished() && has_next {
+            let stop_here = app.playlist.stop_after_current();
             let old_index = app.playlist.current_index();
             app.playlist.next();
+            let sleep_ran_out = app.count_sleep_track();
             let new_index = app.playlist.current_index();
+            let moved = new_index != old_index || app.playlist.playing_from_queue();
             
-            // Only play if we actually moved to a different track
-            if new_index != old_index || app.playlist.repeat_mode() != RepeatMode::Off {
+            if stop_here {
+                // Stopped after the track, with the next one lined up
+                app.is_playing = false;
+                app.status = "Stopped after track".to_string();
+            } else if moved || (app.playlist.repeat_mode() != RepeatMode::Off && app.playlist.current().is_some()) {
+                // Only play if we actually moved to a different track
                 app.play_current();
             } else {
                 // Reached end with repeat off - stop playing
                 app.is_playing = false;
             }
+            if sleep_ran_out {
+                app.sleep_pause();
+            }
             needs_redraw = true;
         }
 
@@ -263,17 +1169,35 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             
             if !batch.is_empty() {
                 app.playlist.add_tracks(batch);
+                app.queue_next_track();
                 app.status = format!("⟳ Scanning... (added {} files)", scan_count);
                 needs_redraw = true;
             }
         }
 
+        // A playlist save finishing in the background
+        if let Some((path, result)) = save_receiver.as_ref().and_then(|r| r.try_recv().ok()) {
+            save_receiver = None;
+            app.status = match result {
+                Ok(_) => format!("Playlist saved: {}", path),
+                Err(e) => format!("Error: {}", e),
+            };
+            needs_redraw = true;
+        }
+
         // Update progress bar once per second
         if app.is_playing && last_progress_update.elapsed() >= std::time::Duration::from_secs(1) {
             last_progress_update = std::time::Instant::now();
             needs_redraw = true;
         }
 
+        // The visualizer animates at its own rate, and only while shown
+        let animating = (app.show_visualizer || app.show_meters) && app.is_playing;
+        if animating && last_visualizer_frame.elapsed() >= visualizer::FRAME_INTERVAL {
+            last_visualizer_frame = std::time::Instant::now();
+            needs_redraw = true;
+        }
+
         if needs_redraw {
             terminal.draw(|f| {
                 let main_chunks = if app.show_browser {
@@ -340,13 +1264,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // Right side - split into playlist and player controls
                 let content_area = if app.show_browser { main_chunks[1] } else { main_chunks[0] };
                 
+                let mut main_constraints = vec![Constraint::Min(5)]; // Top area (playlist + history)
+                if app.show_visualizer {
+                    main_constraints.push(Constraint::Length(10)); // Visualizer
+                }
+                // Player at bottom (minimal), with room for the meters if shown
+                main_constraints.push(Constraint::Length(if app.show_meters { 7 } else { 5 }));
                 let main_layout = Layout::default()
                     .direction(Direction::Vertical)
-                    .constraints([
-                        Constraint::Min(5),      // Top area (playlist + history)
-                        Constraint::Length(5),   // Player at bottom (minimal)
-                    ])
+                    .constraints(main_constraints)
                     .split(content_area);
+                let player_area = main_layout[main_layout.len() - 1];
 
                 // Top area: Playlist and History side by side
                 let top_layout = Layout::default()
@@ -357,14 +1285,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     ])
                     .split(main_layout[0]);
 
-                // Left: Playlist with menu
+                // Left: Playlist with menu, and the queue above it when there is one
+                let show_queue = !app.playlist.queue().is_empty() || matches!(app.focus, FocusPane::Queue);
+                let mut left_constraints = vec![Constraint::Length(3)]; // Menu
+                if show_queue {
+                    left_constraints.push(Constraint::Length(app.playlist.queue().len().clamp(1, 6) as u16 + 2));
+                }
+                left_constraints.push(Constraint::Min(5)); // Playlist
                 let playlist_chunks = Layout::default()
                     .direction(Direction::Vertical)
-                    .constraints([
-                        Constraint::Length(3),  // Menu
-                        Constraint::Min(5),     // Playlist
-                    ])
+                    .constraints(left_constraints)
                     .split(top_layout[0]);
+                let playlist_area = playlist_chunks[playlist_chunks.len() - 1];
 
                 // Menu bar
                 let menu = Paragraph::new("TAP - Terminal Audio Player | Tab: Browser | F1: Help | F2: Settings | Q: Quit")
@@ -373,23 +1305,74 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .block(Block::default().borders(Borders::ALL));
                 f.render_widget(menu, playlist_chunks[0]);
 
+                // Up next
+                if show_queue {
+                    let focused = matches!(app.focus, FocusPane::Queue);
+                    let items: Vec<ListItem> = app.playlist.queue()
+                        .iter()
+                        .enumerate()
+                        .map(|(i, track)| {
+                            let name = app.playlist.info(track)
+                                .and_then(EntryInfo::display_name)
+                                .unwrap_or_else(|| App::get_filename(track));
+                            ListItem::new(format!("{}. {}", i + 1, name))
+                        })
+                        .collect();
+                    let mut queue_state = ListState::default();
+                    if focused && !items.is_empty() {
+                        queue_state.select(Some(app.queue_selected.min(items.len() - 1)));
+                    }
+                    let (title, style) = if focused {
+                        ("Up Next [Shift+↑/↓: Move | Enter: Play | Del: Remove | Esc: Back]", Style::default().fg(Color::Yellow))
+                    } else {
+                        ("Up Next [Shift+N: Focus]", Style::default())
+                    };
+                    let queue_list = List::new(items)
+                        .block(Block::default().borders(Borders::ALL).title(title).border_style(style))
+                        .highlight_style(Style::default().bg(Color::DarkGray));
+                    f.render_stateful_widget(queue_list, playlist_chunks[1], &mut queue_state);
+                }
+
                 // Playlist
                 let items: Vec<ListItem> = app.playlist.tracks()
                     .iter()
                     .enumerate()
                     .map(|(i, track)| {
-                        let filename = App::get_filename(track);
+                        let info = app.playlist.info(track);
+                        let filename = info
+                            .and_then(EntryInfo::display_name)
+                            .unwrap_or_else(|| App::get_filename(track));
                         let mut style = Style::default();
                         
-                        if i == app.playlist.current_index() {
+                        // A queued track playing isn't in the playlist's order
+                        let is_current = i == app.playlist.current_index() && !app.playlist.playing_from_queue();
+                        if is_current {
                             style = style.fg(Color::Yellow).add_modifier(Modifier::BOLD);
                         }
                         if matches!(app.focus, FocusPane::Playlist) && i == app.playlist.selected_index() {
                             style = style.bg(Color::DarkGray);
                         }
                         
-                        let prefix = if i == app.playlist.current_index() { "▶ " } else { "  " };
-                        ListItem::new(format!("{}{}", prefix, filename)).style(style)
+                        let prefix = if is_current { "▶ " } else { "  " };
+                        let mut spans = Vec::new();
+                        spans.push(Span::raw(prefix));
+                        // Mark where each #EXTGRP group starts
+                        let previous_group = i.checked_sub(1)
+                            .and_then(|p| app.playlist.info(&app.playlist.tracks()[p]))
+                            .and_then(|info| info.group.as_deref());
+                        if let Some(group) = info.and_then(|info| info.group.as_deref()) {
+                            if Some(group) != previous_group {
+                                spans.push(Span::styled(format!("[{}] ", group), Style::default().fg(Color::Cyan)));
+                            }
+                        }
+                        spans.push(Span::raw(filename));
+                        if let Some(duration) = info.and_then(|info| info.duration) {
+                            spans.push(Span::styled(
+                                format!("  {}", App::format_duration(duration.as_secs())),
+                                Style::default().fg(Color::DarkGray),
+                            ));
+                        }
+                        ListItem::new(Line::from(spans)).style(style)
                     })
                     .collect();
                 
@@ -397,10 +1380,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     app.playlist_state.select(Some(app.playlist.selected_index()));
                 }
                 
-                let playlist_title = if matches!(app.focus, FocusPane::Playlist) {
-                    "Playlist [Tab: Next]"
-                } else {
-                    "Playlist"
+                let playlist_title = match (app.playlist.name(), matches!(app.focus, FocusPane::Playlist)) {
+                    (Some(name), true) => format!("Playlist: {} [Tab: Next]", name),
+                    (Some(name), false) => format!("Playlist: {}", name),
+                    (None, true) => "Playlist [Tab: Next]".to_string(),
+                    (None, false) => "Playlist".to_string(),
                 };
                 
                 let playlist_style = if matches!(app.focus, FocusPane::Playlist) {
@@ -412,14 +1396,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let list = List::new(items)
                     .block(Block::default().borders(Borders::ALL).title(playlist_title).border_style(playlist_style))
                     .highlight_style(Style::default().bg(Color::DarkGray));
-                f.render_stateful_widget(list, playlist_chunks[1], &mut app.playlist_state);
+                f.render_stateful_widget(list, playlist_area, &mut app.playlist_state);
 
                 // Right: History and Controls
                 let right_chunks = Layout::default()
                     .direction(Direction::Vertical)
                     .constraints([
                         Constraint::Min(5),      // History
-                        Constraint::Length(11),  // Keybinds
+                        Constraint::Length(12),  // Keybinds
                     ])
                     .split(top_layout[1]);
 
@@ -432,10 +1416,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     })
                     .collect();
                 
-                if matches!(app.focus, FocusPane::History) && !app.history.is_empty() {
-                    if app.history_state.selected().is_none() {
-                        app.history_state.select(Some(0));
-                    }
+                if matches!(app.focus, FocusPane::History) && !app.history.is_empty() && app.history_state.selected().is_none() {
+                    app.history_state.select(Some(0));
                 }
                 
                 let history_title = if matches!(app.focus, FocusPane::History) {
@@ -462,7 +1444,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let info_widget = if app.show_info {
                     // Show track info
                     let info_text = if let Some(track_path) = app.playlist.current() {
-                        let (title, artist, album, year) = App::get_metadata(track_path);
+                        let (title, artist, album, year) = App::get_metadata(track_path, app.playlist.info(track_path));
                         format!("Title:  {}\nArtist: {}\nAlbum:  {}\nYear:   {}", title, artist, album, year)
                     } else {
                         "No track playing".to_string()
@@ -476,11 +1458,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let keybinds_text = 
                         "Space   Play/Pause\n\
                          , .     Prev/Next\n\
+                         < >     Chapters\n\
+                         L       A-B loop\n\
                          ← →     Seek ±5s\n\
                          + -     Volume\n\
                          M       Mute\n\
                          S       Shuffle\n\
-                         R       Repeat";
+                         R       Repeat\n\
+                         V       Visualizer";
                     
                     Paragraph::new(keybinds_text)
                         .style(Style::default().fg(Color::Gray))
@@ -490,8 +1475,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 // Player at bottom (full width)
                 let current_track = app.playlist.current()
-                    .map(|t| App::get_filename(t))
-                    .unwrap_or("No track");
+                    .map(App::get_filename)
+                    .unwrap_or_else(|| "No track".to_string());
                 
                 let position = app.audio.get_position();
                 let duration = app.audio.get_duration();
@@ -511,13 +1496,76 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     (0.0, "-- / --".to_string())
                 };
 
-                // Build progress bar
-                let progress_width = main_layout[1].width.saturating_sub(4) as usize;
+                // Seek bar: the track's waveform once it has been worked out,
+                // a plain line until then, with a tick at each chapter start
+                let progress_width = player_area.width.saturating_sub(4) as usize;
                 let filled = (progress_width as f64 * progress_ratio) as usize;
-                let progress_bar = format!("{}{}",
-                    "━".repeat(filled),
-                    "─".repeat(progress_width.saturating_sub(filled))
-                );
+                let peaks = app.current_track_path.as_deref().and_then(|path| app.waveforms.get(path));
+                let mut progress_cells: Vec<char> = (0..progress_width)
+                    .map(|i| match &peaks {
+                        Some(peaks) => {
+                            let first = i * peaks.len() / progress_width;
+                            let last = ((i + 1) * peaks.len() / progress_width).clamp(first + 1, peaks.len());
+                            let peak = peaks[first.min(last - 1)..last].iter().copied().fold(0.0, f32::max);
+                            WAVEFORM_LEVELS[((peak * 7.0).round() as usize).min(7)]
+                        }
+                        None if i < filled => '━',
+                        None => '─',
+                    })
+                    .collect();
+                let chapters = app.audio.chapters();
+                if let Some(dur) = duration.filter(|d| !d.is_zero()) {
+                    for chapter in chapters.iter().skip(1) {
+                        let at = (chapter.start.as_secs_f64() / dur.as_secs_f64() * progress_width as f64) as usize;
+                        if let Some(cell) = progress_cells.get_mut(at) {
+                            *cell = match (&peaks, at < filled) {
+                                (Some(_), _) => '┃',
+                                (None, true) => '╋',
+                                (None, false) => '┼',
+                            };
+                        }
+                    }
+                }
+                // Played cells in colour, and the A-B loop region (or just A
+                // while B is pending) in its own
+                let loop_region = app.audio.ab_loop()
+                    .or_else(|| app.loop_a.map(|a| (a, a)));
+                let (loop_start, loop_end) = match (loop_region, duration.filter(|d| !d.is_zero())) {
+                    (Some((a, b)), Some(dur)) => {
+                        let cell = |t: std::time::Duration| {
+                            ((t.as_secs_f64() / dur.as_secs_f64() * progress_width as f64) as usize).min(progress_width)
+                        };
+                        (cell(a), (cell(b) + 1).min(progress_width))
+                    }
+                    _ => (0, 0),
+                };
+                let loop_style = Style::default().fg(Color::Cyan);
+                let cell_style = |i: usize| {
+                    let in_loop = (loop_start..loop_end).contains(&i);
+                    match (i < filled, in_loop) {
+                        (true, true) => loop_style,
+                        (true, false) => Style::default().fg(Color::Yellow),
+                        (false, true) => Style::default().fg(Color::Rgb(0, 110, 110)),
+                        (false, false) => Style::default().fg(Color::DarkGray),
+                    }
+                };
+                let mut progress_spans: Vec<Span> = Vec::new();
+                let mut run = String::new();
+                for (i, cell) in progress_cells.iter().enumerate() {
+                    run.push(*cell);
+                    if i + 1 == progress_width || cell_style(i + 1) != cell_style(i) {
+                        progress_spans.push(Span::styled(std::mem::take(&mut run), cell_style(i)));
+                    }
+                }
+                let progress_bar = Line::from(progress_spans);
+                let loop_label = match (app.audio.ab_loop(), app.loop_a) {
+                    (Some(_), _) => "A-B",
+                    (None, Some(_)) => "A-",
+                    (None, None) => "",
+                };
+                let chapter_label = app.audio.current_chapter()
+                    .and_then(|i| chapters.get(i).map(|c| format!(" | {}/{} {}", i + 1, chapters.len(), c.title)))
+                    .unwrap_or_default();
 
                 // Control buttons with state
                 let play_btn = if app.is_playing && !app.audio.is_paused() {
@@ -548,11 +1596,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 } else {
                     &format!("Vol: {}%", (app.volume * 100.0) as u32)
                 };
+                let levels = app.audio.levels();
+                let (clip_text, clip_style) = if levels.clipped_recently() {
+                    (" CLIP", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD))
+                } else if levels.limited_recently() {
+                    (" LIM", Style::default().fg(Color::Yellow))
+                } else {
+                    ("", Style::default())
+                };
+                let speed = app.audio.get_speed();
+                let speed_style = if speed == 1.0 {
+                    Style::default().fg(Color::Gray)
+                } else {
+                    Style::default().fg(Color::Rgb(255, 165, 0)) // Orange
+                };
 
                 // Build player display with styled components
-                let player_lines = vec![
-                    Line::from(vec![Span::styled(format!("♪ {} | {}", current_track, time_label), Style::default())]),
-                    Line::from(progress_bar.clone()),
+                let mut player_lines = vec![
+                    Line::from(vec![Span::styled(format!("♪ {}{} | {}", current_track, chapter_label, time_label), Style::default())]),
+                    progress_bar,
                     Line::from(vec![
                         Span::raw("  "),
                         Span::raw(play_btn),
@@ -561,13 +1623,100 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         Span::raw("  "),
                         Span::styled(repeat_text, repeat_style),
                         Span::raw(format!("  {}", vol_display)),
+                        Span::styled(clip_text, clip_style),
+                        Span::raw("  "),
+                        Span::styled(format!("{:.1}x", speed), speed_style),
+                        Span::styled(
+                            if loop_label.is_empty() { String::new() } else { format!("  {}", loop_label) },
+                            loop_style,
+                        ),
                     ]),
                 ];
+                if app.show_meters {
+                    // Levels stop updating while paused, so show silence then
+                    let channels = if app.is_playing && !app.audio.is_paused() {
+                        levels.levels()
+                    } else {
+                        [(0.0, 0.0); 2]
+                    };
+                    for (label, (peak, rms)) in ["L", "R"].iter().zip(channels) {
+                        player_lines.push(meter_line(label, peak, rms, progress_width));
+                    }
+                }
                 
+                // Sleep timer and stop-after-current, opposite the status
+                let mut pending_stop = Vec::new();
+                if let Some(timer) = &app.sleep {
+                    pending_stop.push(timer.label());
+                }
+                if app.playlist.stop_after_current() {
+                    pending_stop.push("Stop after this track".to_string());
+                }
                 let player = Paragraph::new(player_lines)
                     .alignment(Alignment::Center)
-                    .block(Block::default().borders(Borders::ALL).title("Player"));
-                f.render_widget(player, main_layout[1]);
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title("Player")
+                            .title_bottom(Line::from(Span::styled(
+                                pending_stop.iter().map(|s| format!(" {} ", s)).collect::<String>(),
+                                Style::default().fg(Color::Rgb(255, 165, 0)),
+                            )).left_aligned())
+                            .title_bottom(Line::from(Span::styled(
+                                format!(" {} ", app.status),
+                                Style::default().fg(Color::DarkGray),
+                            )).right_aligned()),
+                    );
+                f.render_widget(player, player_area);
+
+                // Visualizer
+                if app.show_visualizer {
+                    let area = main_layout[1];
+                    let mode = app.config.visualizer_mode;
+                    let block = Block::default()
+                        .borders(Borders::ALL)
+                        .title(format!("{} [V: {}]", mode.label(), mode.cycle().label()));
+                    let inner_width = area.width.saturating_sub(2) as usize;
+                    match mode {
+                        visualizer::VisualizerMode::Spectrum => {
+                            let levels: Vec<u64> = app.visualizer.spectrum(inner_width)
+                                .iter()
+                                .map(|level| (level * 100.0) as u64)
+                                .collect();
+                            let spectrum = Sparkline::default()
+                                .block(block)
+                                .data(&levels)
+                                .max(100)
+                                .style(Style::default().fg(Color::Green));
+                            f.render_widget(spectrum, area);
+                        }
+                        visualizer::VisualizerMode::Oscilloscope => {
+                            let samples = app.visualizer.waveform();
+                            // Two Braille dots per cell across
+                            let points = (inner_width * 2).min(samples.len());
+                            let trace: Vec<(f64, f64)> = (0..points)
+                                .map(|i| (i as f64, samples[i * samples.len() / points] as f64))
+                                .collect();
+                            let scope = Canvas::default()
+                                .block(block)
+                                .marker(Marker::Braille)
+                                .x_bounds([0.0, points.saturating_sub(1).max(1) as f64])
+                                .y_bounds([-1.0, 1.0])
+                                .paint(|ctx| {
+                                    for pair in trace.windows(2) {
+                                        ctx.draw(&CanvasLine {
+                                            x1: pair[0].0,
+                                            y1: pair[0].1,
+                                            x2: pair[1].0,
+                                            y2: pair[1].1,
+                                            color: Color::Green,
+                                        });
+                                    }
+                                });
+                            f.render_widget(scope, area);
+                        }
+                    }
+                }
                 
                 // Render modals on top
                 match app.modal {
@@ -580,24 +1729,39 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             "Global Controls:",
                             "  Space     - Play/Pause",
                             "  , / .     - Previous/Next track",
+                            "  < / >     - Previous/Next chapter",
+                            "  L         - Set loop A, then B; again to clear",
+                            "  b         - Bookmark current position",
+                            "  Shift+B   - Bookmark list",
                             "  ← / →     - Seek ±5 seconds",
                             "  + / -     - Volume up/down",
+                            "  [ / ]     - Speed down/up (\\ resets to 1.0x)",
                             "  M         - Mute/Unmute",
                             "  Tab       - Toggle file browser",
                             "  H         - Toggle history",
                             "  I         - Toggle track info",
+                            "  V         - Toggle visualizer",
+                            "  Shift+V   - Spectrum / oscilloscope",
+                            "  U         - Toggle level meters",
                             "  F1        - Show this help",
                             "  F2        - Settings",
+                            "  E         - Equalizer",
                             "  Q         - Quit",
                             "",
                             "Playlist:",
                             "  ↑ / ↓     - Navigate playlist",
                             "  Enter     - Play selected track",
                             "  Delete    - Remove selected track",
+                            "  n         - Play next (also in browser and history)",
+                            "  Shift+N   - Up Next queue: Shift+↑/↓ move, Del remove",
                             "  C         - Clear entire playlist",
                             "  S         - Toggle shuffle",
                             "  R         - Cycle repeat mode",
-                            "  Ctrl+S    - Save playlist as M3U",
+                            "  X         - Stop after current track",
+                            "  z         - Sleep timer: 15/30/45/60/90/120 min, off",
+                            "  Shift+Z   - Sleep timer: after 1/2/3/5/10 tracks, off",
+                            "  Ctrl+S    - Save playlist (M3U, PLS, XSPF)",
+                            "  Ctrl+R    - Rebase paths (music moved)",
                             "",
                             "File Browser (when visible):",
                             "  ↑ / ↓     - Navigate files",
@@ -616,26 +1780,49 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         f.render_widget(help, area);
                     }
                     Modal::Settings => {
-                        let area = centered_rect(60, 50, f.size());
+                        let area = centered_rect(60, 70, f.size());
                         f.render_widget(Clear, area);
                         
                         let default_dir = app.config.default_music_dir.as_deref().unwrap_or("Not set");
                         let playlist_dir = app.config.default_playlist_dir.as_deref().unwrap_or("~/Music (default)");
                         let last_dir = app.config.last_directory.as_deref().unwrap_or("Not set");
                         
-                        let settings_text = format!(
-                            "TAP - Terminal Audio Player - Settings\n\n\
-                            Default Music Directory:\n  {}\n\n\
-                            Default Playlist Save Directory:\n  {}\n\n\
-                            Last Directory:\n  {}\n\n\
-                            Note: Settings are automatically saved.\n\
-                            To set default music dir, navigate to it\n\
-                            in the browser and press Ctrl+D.\n\n\
-                            Press ESC or F2 to close",
-                            default_dir, playlist_dir, last_dir
-                        );
+                        let mut settings_lines = vec![
+                            Line::from("TAP - Terminal Audio Player - Settings"),
+                            Line::from(""),
+                            Line::from("Default Music Directory:"),
+                            Line::from(format!("  {}", default_dir)),
+                            Line::from(""),
+                            Line::from("Default Playlist Save Directory:"),
+                            Line::from(format!("  {}", playlist_dir)),
+                            Line::from(""),
+                            Line::from("Last Directory:"),
+                            Line::from(format!("  {}", last_dir)),
+                            Line::from(""),
+                            Line::from("Playback:"),
+                        ];
+                        for (i, item) in SettingsItem::ALL.iter().enumerate() {
+                            let style = if i == app.settings_selected {
+                                Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+                            } else {
+                                Style::default()
+                            };
+                            let marker = if i == app.settings_selected { "▸ " } else { "  " };
+                            settings_lines.push(Line::from(Span::styled(
+                                format!("{}{:<20} {}", marker, App::settings_label(*item), app.settings_value(*item)),
+                                style,
+                            )));
+                        }
+                        settings_lines.extend([
+                            Line::from(""),
+                            Line::from("Note: Settings are automatically saved."),
+                            Line::from("To set default music dir, navigate to it"),
+                            Line::from("in the browser and press Ctrl+D."),
+                            Line::from(""),
+                            Line::from("↑/↓ select, ←/→ or Enter change, ESC or F2 to close"),
+                        ]);
                         
-                        let settings = Paragraph::new(settings_text)
+                        let settings = Paragraph::new(settings_lines)
                             .block(Block::default().borders(Borders::ALL).title("Settings"))
                             .style(Style::default().bg(Color::Black))
                             .wrap(Wrap { trim: false });
@@ -649,7 +1836,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         let cursor = if (std::time::SystemTime::now()
                             .duration_since(std::time::UNIX_EPOCH)
                             .unwrap()
-                            .as_millis() / 500) % 2 == 0 {
+                            .as_millis() / 500).is_multiple_of(2) {
                             "█"
                         } else {
                             " "
@@ -666,17 +1853,160 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         let save_text = format!(
                             "Save Playlist\n\n\
                             Path:\n{}\n\n\
+                            The extension picks the format: .m3u, .m3u8, .pls or .xspf\n\n\
                             Press Enter to save, ESC to cancel\n\
                             Use ← → to move cursor, Backspace/Delete to edit",
                             display_path
                         );
                         
                         let save_dialog = Paragraph::new(save_text)
-                            .block(Block::default().borders(Borders::ALL).title("Save Playlist as M3U"))
+                            .block(Block::default().borders(Borders::ALL).title("Save Playlist"))
                             .style(Style::default().bg(Color::Black))
                             .wrap(Wrap { trim: false });
                         f.render_widget(save_dialog, area);
                     }
+                    Modal::Equalizer => {
+                        let area = centered_rect(60, 60, f.size());
+                        f.render_widget(Clear, area);
+
+                        let gains = if app.config.eq_gains.is_empty() {
+                            vec![0.0; eq::DEFAULT_BANDS]
+                        } else {
+                            app.config.eq_gains.clone()
+                        };
+                        let preset = app.config.eq_preset.as_deref().unwrap_or("Custom (unsaved)");
+                        let mut eq_lines = vec![
+                            Line::from(vec![
+                                Span::raw("Equalizer: "),
+                                if app.config.eq_enabled {
+                                    Span::styled("On", Style::default().fg(Color::Green))
+                                } else {
+                                    Span::styled("Off", Style::default().fg(Color::Gray))
+                                },
+                                Span::raw(format!("   Preset: {}", preset)),
+                            ]),
+                            Line::from(""),
+                        ];
+
+                        // Each band is a bar growing left (cut) or right (boost) from 0 dB
+                        let half = 12usize;
+                        for (i, (freq, gain)) in eq::band_frequencies(gains.len()).iter().zip(&gains).enumerate() {
+                            let cells = ((gain.abs() / eq::MAX_GAIN_DB) * half as f32).round() as usize;
+                            let (left, right) = if *gain < 0.0 {
+                                (format!("{}{}", " ".repeat(half - cells), "█".repeat(cells)), " ".repeat(half))
+                            } else {
+                                (" ".repeat(half), format!("{}{}", "█".repeat(cells), " ".repeat(half - cells)))
+                            };
+                            let style = if i == app.eq_selected_band {
+                                Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+                            } else if app.config.eq_enabled {
+                                Style::default().fg(Color::Cyan)
+                            } else {
+                                Style::default().fg(Color::Gray)
+                            };
+                            let marker = if i == app.eq_selected_band { "▸" } else { " " };
+                            eq_lines.push(Line::from(Span::styled(
+                                format!("{}{:>5} Hz {}│{} {:+5.1} dB", marker, eq::format_frequency(*freq), left, right, gain),
+                                style,
+                            )));
+                        }
+                        eq_lines.extend([
+                            Line::from(""),
+                            Line::from("←/→ band  ↑/↓ gain  Space on/off  P/Shift+P preset"),
+                            Line::from("S save as preset  Delete remove preset  ESC or E close"),
+                        ]);
+
+                        let eq_widget = Paragraph::new(eq_lines)
+                            .block(Block::default().borders(Borders::ALL).title("Equalizer"))
+                            .style(Style::default().bg(Color::Black));
+                        f.render_widget(eq_widget, area);
+                    }
+                    Modal::Resume(position) => {
+                        let area = centered_rect(50, 20, f.size());
+                        f.render_widget(Clear, area);
+                        let resume_text = format!(
+                            "Continue from {} where you left off?\n\n\
+                            Enter: Resume   ESC: Start from the beginning",
+                            App::format_duration(position.as_secs())
+                        );
+                        let resume_dialog = Paragraph::new(resume_text)
+                            .block(Block::default().borders(Borders::ALL).title("Resume"))
+                            .style(Style::default().bg(Color::Black))
+                            .wrap(Wrap { trim: false });
+                        f.render_widget(resume_dialog, area);
+                    }
+                    Modal::AddBookmark(position) => {
+                        let area = centered_rect(60, 25, f.size());
+                        f.render_widget(Clear, area);
+                        let bookmark_text = format!(
+                            "Bookmark at {}\n\n\
+                            Name:\n{}█\n\n\
+                            Press Enter to save, ESC to cancel",
+                            App::format_duration(position.as_secs()),
+                            app.bookmark_name_input
+                        );
+                        let bookmark_dialog = Paragraph::new(bookmark_text)
+                            .block(Block::default().borders(Borders::ALL).title("Add Bookmark"))
+                            .style(Style::default().bg(Color::Black))
+                            .wrap(Wrap { trim: false });
+                        f.render_widget(bookmark_dialog, area);
+                    }
+                    Modal::RebasePlaylist(ref from) => {
+                        let area = centered_rect(70, 30, f.size());
+                        f.render_widget(Clear, area);
+                        let rebase_text = match from {
+                            None => format!(
+                                "Rewrite the start of every path under a folder, for music that has moved.\n\n\
+                                Old folder:\n{}█\n\n\
+                                Press Enter to continue, ESC to cancel",
+                                app.rebase_input
+                            ),
+                            Some(from) => format!(
+                                "Old folder:\n{}\n\n\
+                                New folder:\n{}█\n\n\
+                                Press Enter to rebase, ESC to cancel",
+                                from, app.rebase_input
+                            ),
+                        };
+                        let rebase_dialog = Paragraph::new(rebase_text)
+                            .block(Block::default().borders(Borders::ALL).title("Rebase Playlist Paths"))
+                            .style(Style::default().bg(Color::Black))
+                            .wrap(Wrap { trim: false });
+                        f.render_widget(rebase_dialog, area);
+                    }
+                    Modal::Bookmarks => {
+                        let area = centered_rect(70, 60, f.size());
+                        f.render_widget(Clear, area);
+                        let list = app.bookmarks.list(app.current_track_path.as_deref());
+                        let items: Vec<ListItem> = if list.is_empty() {
+                            vec![ListItem::new("No bookmarks yet. Press b while playing to add one.")]
+                        } else {
+                            list.iter()
+                                .enumerate()
+                                .map(|(i, (path, bookmark))| {
+                                    let style = if i == app.bookmark_selected {
+                                        Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+                                    } else if app.current_track_path.as_deref() == Some(path.as_str()) {
+                                        Style::default().fg(Color::Cyan)
+                                    } else {
+                                        Style::default()
+                                    };
+                                    ListItem::new(format!(
+                                        "{}  {}  {}",
+                                        App::format_duration(bookmark.position().as_secs()),
+                                        bookmark.name,
+                                        App::get_filename(path)
+                                    )).style(style)
+                                })
+                                .collect()
+                        };
+                        let bookmark_list = List::new(items)
+                            .block(Block::default()
+                                .borders(Borders::ALL)
+                                .title("Bookmarks [Enter: Jump | Delete: Remove | ESC: Close]"))
+                            .style(Style::default().bg(Color::Black));
+                        f.render_widget(bookmark_list, area);
+                    }
                     Modal::None => {}
                 }
             })?;
@@ -684,7 +2014,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             needs_redraw = matches!(app.modal, Modal::SavePlaylist);
         }
 
-        if event::poll(std::time::Duration::from_millis(250))? {
+        let poll_timeout = if animating {
+            visualizer::FRAME_INTERVAL
+        } else {
+            std::time::Duration::from_millis(250)
+        };
+        if event::poll(poll_timeout)? {
             if let Event::Key(key) = event::read()? {
                 needs_redraw = true;
                 
@@ -699,8 +2034,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             }
                             KeyCode::Enter => {
                                 let path = app.save_path_input.clone();
-                                match app.save_playlist_m3u(&path) {
-                                    Ok(_) => app.status = format!("Playlist saved: {}", path),
+                                match app.save_playlist(&path) {
+                                    Ok(receiver) => {
+                                        save_receiver = Some(receiver);
+                                        app.status = "⟳ Saving playlist...".to_string();
+                                    }
                                     Err(e) => app.status = format!("Error: {}", e),
                                 }
                                 app.modal = Modal::None;
@@ -710,10 +2048,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             KeyCode::Left => {
                                 app.save_path_cursor = app.save_path_cursor.saturating_sub(1);
                             }
-                            KeyCode::Right => {
-                                if app.save_path_cursor < app.save_path_input.len() {
-                                    app.save_path_cursor += 1;
-                                }
+                            KeyCode::Right if app.save_path_cursor < app.save_path_input.len() => {
+                                app.save_path_cursor += 1;
                             }
                             KeyCode::Home => {
                                 app.save_path_cursor = 0;
@@ -721,16 +2057,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             KeyCode::End => {
                                 app.save_path_cursor = app.save_path_input.len();
                             }
-                            KeyCode::Backspace => {
-                                if app.save_path_cursor > 0 {
-                                    app.save_path_cursor -= 1;
-                                    app.save_path_input.remove(app.save_path_cursor);
-                                }
+                            KeyCode::Backspace if app.save_path_cursor > 0 => {
+                                app.save_path_cursor -= 1;
+                                app.save_path_input.remove(app.save_path_cursor);
                             }
-                            KeyCode::Delete => {
-                                if app.save_path_cursor < app.save_path_input.len() {
-                                    app.save_path_input.remove(app.save_path_cursor);
-                                }
+                            KeyCode::Delete if app.save_path_cursor < app.save_path_input.len() => {
+                                app.save_path_input.remove(app.save_path_cursor);
                             }
                             KeyCode::Char(c) => {
                                 app.save_path_input.insert(app.save_path_cursor, c);
@@ -740,6 +2072,98 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         }
                         continue;
                     }
+                    Modal::Resume(position) => {
+                        match key.code {
+                            KeyCode::Enter | KeyCode::Char('y') | KeyCode::Char('Y') => {
+                                app.audio.seek_to(position);
+                                app.status = format!("Resumed at {}", App::format_duration(position.as_secs()));
+                                app.modal = Modal::None;
+                            }
+                            KeyCode::Esc | KeyCode::Char('n') | KeyCode::Char('N') => {
+                                app.modal = Modal::None;
+                            }
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    Modal::AddBookmark(position) => {
+                        match key.code {
+                            KeyCode::Esc => app.modal = Modal::None,
+                            KeyCode::Enter if !app.bookmark_name_input.trim().is_empty() => {
+                                app.add_bookmark(position);
+                                app.modal = Modal::None;
+                            }
+                            KeyCode::Backspace => {
+                                app.bookmark_name_input.pop();
+                            }
+                            KeyCode::Char(c) => app.bookmark_name_input.push(c),
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    Modal::RebasePlaylist(ref from) => {
+                        let from = from.clone();
+                        match key.code {
+                            KeyCode::Esc => app.modal = Modal::None,
+                            KeyCode::Enter if !app.rebase_input.trim().is_empty() => {
+                                let input = app.rebase_input.trim().to_string();
+                                match from {
+                                    // Start the new folder off as the old one, to edit
+                                    None => app.modal = Modal::RebasePlaylist(Some(input)),
+                                    Some(from) => {
+                                        app.rebase_playlist(&from, &input);
+                                        app.modal = Modal::None;
+                                    }
+                                }
+                            }
+                            KeyCode::Backspace => {
+                                app.rebase_input.pop();
+                            }
+                            KeyCode::Char(c) => app.rebase_input.push(c),
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    Modal::Bookmarks => {
+                        let count = app.bookmarks.list(app.current_track_path.as_deref()).len();
+                        match key.code {
+                            KeyCode::Esc | KeyCode::Char('B') => app.modal = Modal::None,
+                            KeyCode::Up => app.bookmark_selected = app.bookmark_selected.saturating_sub(1),
+                            KeyCode::Down => {
+                                app.bookmark_selected = (app.bookmark_selected + 1).min(count.saturating_sub(1));
+                            }
+                            KeyCode::Enter => app.jump_to_bookmark(app.bookmark_selected),
+                            KeyCode::Delete => app.delete_bookmark(app.bookmark_selected),
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    Modal::Equalizer => {
+                        match key.code {
+                            KeyCode::Esc | KeyCode::Char('e') | KeyCode::Char('E') => {
+                                app.modal = Modal::None;
+                            }
+                            KeyCode::Left => {
+                                app.eq_selected_band = app.eq_selected_band.saturating_sub(1);
+                            }
+                            KeyCode::Right => {
+                                let bands = app.config.eq_gains.len().max(eq::DEFAULT_BANDS);
+                                app.eq_selected_band = (app.eq_selected_band + 1).min(bands - 1);
+                            }
+                            KeyCode::Up => app.adjust_eq_band(1.0),
+                            KeyCode::Down => app.adjust_eq_band(-1.0),
+                            KeyCode::Char(' ') | KeyCode::Enter => {
+                                app.config.eq_enabled = !app.config.eq_enabled;
+                                app.apply_equalizer();
+                            }
+                            KeyCode::Char('p') => app.cycle_eq_preset(true),
+                            KeyCode::Char('P') => app.cycle_eq_preset(false),
+                            KeyCode::Char('s') | KeyCode::Char('S') => app.save_eq_preset(),
+                            KeyCode::Delete => app.delete_eq_preset(),
+                            _ => {}
+                        }
+                        continue;
+                    }
                     Modal::Help | Modal::Settings => {
                         match key.code {
                             KeyCode::Esc | KeyCode::F(1) if matches!(app.modal, Modal::Help) => {
@@ -749,6 +2173,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             KeyCode::Esc | KeyCode::F(2) if matches!(app.modal, Modal::Settings) => {
                                 app.modal = Modal::None;
                             }
+                            KeyCode::Up if matches!(app.modal, Modal::Settings) => {
+                                app.settings_selected = app.settings_selected.saturating_sub(1);
+                            }
+                            KeyCode::Down if matches!(app.modal, Modal::Settings) => {
+                                app.settings_selected = (app.settings_selected + 1).min(SettingsItem::ALL.len() - 1);
+                            }
+                            KeyCode::Left if matches!(app.modal, Modal::Settings) => {
+                                app.adjust_setting(false);
+                            }
+                            KeyCode::Right | KeyCode::Enter if matches!(app.modal, Modal::Settings) => {
+                                app.adjust_setting(true);
+                            }
                             KeyCode::Up if matches!(app.modal, Modal::Help) => {
                                 app.help_scroll = app.help_scroll.saturating_sub(1);
                             }
@@ -766,6 +2202,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 match key.code {
                     KeyCode::Char('q') => {
                         // Save config before quitting
+                        app.remember_position();
                         app.config.last_directory = Some(app.browser.current_dir().to_string_lossy().to_string());
                         app.config.current_playlist_tracks = app.playlist.tracks().to_vec();
                         app.config.save();
@@ -777,6 +2214,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     KeyCode::F(2) => {
                         app.modal = Modal::Settings;
                     }
+                    KeyCode::Char('e') | KeyCode::Char('E') => {
+                        app.modal = Modal::Equalizer;
+                    }
+                    KeyCode::Char('N') => {
+                        // Toggle between Playlist and the play-next queue
+                        app.focus = match app.focus {
+                            FocusPane::Queue => FocusPane::Playlist,
+                            _ => {
+                                app.queue_selected = 0;
+                                FocusPane::Queue
+                            }
+                        };
+                    }
                     KeyCode::Char('h') | KeyCode::Char('H') => {
                         // Toggle between Playlist and History
                         app.focus = match app.focus {
@@ -788,6 +2238,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         // Toggle info view
                         app.show_info = !app.show_info;
                     }
+                    KeyCode::Char('v') => app.toggle_visualizer(),
+                    KeyCode::Char('x') | KeyCode::Char('X') => app.toggle_stop_after_current(),
+                    KeyCode::Char('z') => app.cycle_sleep_timer(false),
+                    KeyCode::Char('Z') => app.cycle_sleep_timer(true),
+                    KeyCode::Char('u') | KeyCode::Char('U') => app.show_meters = !app.show_meters,
+                    KeyCode::Char('V') => {
+                        app.config.visualizer_mode = app.config.visualizer_mode.cycle();
+                        app.config.save();
+                        if !app.show_visualizer {
+                            app.toggle_visualizer();
+                        }
+                    }
                     KeyCode::Tab => {
                         // Tab toggles browser and switches focus
                         if app.show_browser {
@@ -829,18 +2291,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         if should_go_prev || app.audio.get_position().as_secs() < 3 {
                             // Go to previous track
                             app.playlist.previous();
-                            app.play_current();
+                            app.skip_to_current();
                             app.last_prev_press = None;
                         } else {
                             // Restart current track
-                            app.play_current();
+                            app.skip_to_current();
                             app.last_prev_press = Some(now);
                         }
                     }
                     KeyCode::Char('.') => {
                         app.playlist.next();
-                        app.play_current();
+                        app.skip_to_current();
                     }
+                    KeyCode::Char('b') => app.open_add_bookmark(),
+                    KeyCode::Char('B') => {
+                        app.bookmark_selected = 0;
+                        app.modal = Modal::Bookmarks;
+                    }
+                    KeyCode::Char('l') | KeyCode::Char('L') => app.cycle_ab_loop(),
+                    KeyCode::Char('<') => app.jump_chapter(false),
+                    KeyCode::Char('>') => app.jump_chapter(true),
                     KeyCode::Char('+') | KeyCode::Char('=') => {
                         if !app.is_muted {
                             app.volume = (app.volume + 0.1).min(2.0);
@@ -853,6 +2323,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             app.audio.set_volume(app.volume);
                         }
                     }
+                    KeyCode::Char('[') => app.change_speed(-0.1),
+                    KeyCode::Char(']') => app.change_speed(0.1),
+                    KeyCode::Char('\\') => app.change_speed(0.0),
                     KeyCode::Char('m') | KeyCode::Char('M') => {
                         if app.is_muted {
                             app.is_muted = false;
@@ -864,6 +2337,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             app.audio.set_volume(0.0);
                         }
                     }
+                    KeyCode::Char('r') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        app.open_rebase();
+                    }
                     KeyCode::Char('s') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
                         // Open save playlist modal
                         app.save_path_input = app.get_default_playlist_path();
@@ -872,15 +2348,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                     KeyCode::Char('s') | KeyCode::Char('S') => {
                         app.playlist.toggle_shuffle();
+                        app.apply_replaygain();
+                        app.queue_next_track();
                     }
                     KeyCode::Char('r') | KeyCode::Char('R') => {
                         app.playlist.cycle_repeat();
+                        app.queue_next_track();
                     }
                     KeyCode::Char('c') | KeyCode::Char('C') => {
                         // Clear playlist (works globally, even with modals open)
+                        app.remember_position();
                         app.playlist.clear();
                         app.audio.stop();
                         app.is_playing = false;
+                        app.current_track_path = None;
                         app.status = "Playlist cleared".to_string();
                     }
                     _ => {
@@ -902,7 +2383,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     KeyCode::Enter => {
                                         if let Some(entry) = app.browser.enter_selected() {
                                             if entry.is_playlist {
-                                                if let Err(e) = app.playlist.load_m3u(&entry.path.to_string_lossy()) {
+                                                if let Err(e) = app.playlist.load(&entry.path.to_string_lossy()) {
                                                     app.status = format!("Error loading playlist: {}", e);
                                                 } else {
                                                     app.status = format!("Loaded playlist: {}", entry.name);
@@ -913,10 +2394,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                                 app.playlist.add_track(entry.path.to_string_lossy().to_string());
                                                 app.status = format!("Added: {}", entry.name);
                                             }
+                                            app.queue_next_track();
                                         } else {
                                             app.config.last_directory = Some(app.browser.current_dir().to_string_lossy().to_string());
                                         }
                                     }
+                                    KeyCode::Char('n') => {
+                                        let entry = app.browser.entries().get(app.browser.selected_index()).cloned();
+                                        match entry {
+                                            Some(entry) if entry.is_audio => {
+                                                app.play_next(entry.path.to_string_lossy().to_string());
+                                            }
+                                            _ => app.status = "Only tracks can be played next".to_string(),
+                                        }
+                                    }
                                     KeyCode::Char('a') | KeyCode::Char('A') => {
                                         let scan_dir = app.browser.current_dir().to_path_buf();
                                         let (sender, receiver) = channel();
@@ -926,7 +2417,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                         app.status = "⟳ Starting scan...".to_string();
                                         
                                         thread::spawn(move || {
-                                            FileBrowser::scan_audio_files_streaming(scan_dir, sender);
+                                            FileBrowser::scan_audio_files_streaming(scan_dir, sender, true);
                                         });
                                     }
                                     _ => { needs_redraw = false; }
@@ -971,6 +2462,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                             }
                                         }
                                     }
+                                    KeyCode::Char('n') => {
+                                        let track = app.history_state.selected().and_then(|i| app.history.get(i)).cloned();
+                                        if let Some(track) = track {
+                                            app.play_next(track);
+                                        }
+                                    }
+                                    _ => { needs_redraw = false; }
+                                }
+                            }
+                            FocusPane::Queue => {
+                                let len = app.playlist.queue().len();
+                                let shift = key.modifiers.contains(event::KeyModifiers::SHIFT);
+                                match key.code {
+                                    KeyCode::Up if shift => {
+                                        if app.playlist.move_queued(app.queue_selected, true) {
+                                            app.queue_selected -= 1;
+                                            app.queue_next_track();
+                                        }
+                                    }
+                                    KeyCode::Down if shift => {
+                                        if app.playlist.move_queued(app.queue_selected, false) {
+                                            app.queue_selected += 1;
+                                            app.queue_next_track();
+                                        }
+                                    }
+                                    KeyCode::Up => app.queue_selected = app.queue_selected.saturating_sub(1),
+                                    KeyCode::Down => {
+                                        app.queue_selected = (app.queue_selected + 1).min(len.saturating_sub(1));
+                                    }
+                                    KeyCode::Enter if app.queue_selected < len => {
+                                        app.playlist.queue_to_front(app.queue_selected);
+                                        app.playlist.next();
+                                        app.skip_to_current();
+                                        app.queue_selected = 0;
+                                    }
+                                    KeyCode::Delete if app.queue_selected < len => {
+                                        app.playlist.remove_queued(app.queue_selected);
+                                        app.queue_selected = app.queue_selected.min(len.saturating_sub(2));
+                                        app.status = "Removed from queue".to_string();
+                                        app.queue_next_track();
+                                    }
+                                    KeyCode::Esc => app.focus = FocusPane::Playlist,
                                     _ => { needs_redraw = false; }
                                 }
                             }
@@ -987,9 +2520,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                         app.playlist.play_selected();
                                         app.play_current();
                                     }
+                                    KeyCode::Char('n') => {
+                                        if let Some(track) = app.playlist.tracks().get(app.playlist.selected_index()).cloned() {
+                                            app.play_next(track);
+                                        }
+                                    }
                                     KeyCode::Delete => {
                                         if app.playlist.remove_selected() {
                                             app.status = "Track removed".to_string();
+                                            app.queue_next_track();
                                         }
                                     }
                                     _ => { needs_redraw = false; }
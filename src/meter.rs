@@ -0,0 +1,291 @@
+use rodio::Source;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Frames measured between updates of the shared levels.
+const BLOCK_FRAMES: u32 = 1024;
+/// Per-block factor the displayed peak falls back by, about 20 dB a second.
+const PEAK_DECAY: f32 = 0.9;
+/// Weight of each new block in the RMS average, roughly a 300 ms VU window.
+const RMS_WEIGHT: f32 = 0.07;
+/// Volume changes glide over about this long so they don't click.
+const VOLUME_GLIDE_SECS: f32 = 0.01;
+/// The limiter keeps peaks under this.
+const LIMIT_CEILING: f32 = 0.98;
+/// How quickly the limiter lets go after a peak.
+const LIMIT_RELEASE_SECS: f32 = 0.15;
+/// The clip indicator stays lit this long after the last clipped block.
+const CLIP_HOLD: Duration = Duration::from_millis(1500);
+
+/// Output levels, written by the audio thread once a block and read by the
+/// UI. Levels are linear, 0.0 to 1.0 (full scale).
+pub struct MeterLevels {
+    peak: [AtomicU32; 2],
+    rms: [AtomicU32; 2],
+    started: Instant,
+    /// Milliseconds after `started` of the last clipped block, plus one;
+    /// zero if nothing has clipped.
+    clipped_at: AtomicU64,
+    /// Milliseconds after `started` the limiter last had to step in, plus one.
+    limited_at: AtomicU64,
+}
+
+impl Default for MeterLevels {
+    fn default() -> Self {
+        MeterLevels {
+            peak: Default::default(),
+            rms: Default::default(),
+            started: Instant::now(),
+            clipped_at: AtomicU64::new(0),
+            limited_at: AtomicU64::new(0),
+        }
+    }
+}
+
+impl MeterLevels {
+    /// Peak and RMS of the left and right channels.
+    pub fn levels(&self) -> [(f32, f32); 2] {
+        let read = |a: &AtomicU32| f32::from_bits(a.load(Ordering::Relaxed));
+        [
+            (read(&self.peak[0]), read(&self.rms[0])),
+            (read(&self.peak[1]), read(&self.rms[1])),
+        ]
+    }
+
+    pub fn clipped_recently(&self) -> bool {
+        self.recently(&self.clipped_at)
+    }
+
+    pub fn limited_recently(&self) -> bool {
+        self.recently(&self.limited_at)
+    }
+
+    fn recently(&self, at: &AtomicU64) -> bool {
+        match at.load(Ordering::Relaxed) {
+            0 => false,
+            at => {
+                let now = self.started.elapsed().as_millis() as u64 + 1;
+                now.saturating_sub(at) < CLIP_HOLD.as_millis() as u64
+            }
+        }
+    }
+
+    fn mark(&self, at: &AtomicU64) {
+        at.store(self.started.elapsed().as_millis() as u64 + 1, Ordering::Relaxed);
+    }
+}
+
+/// Applies the volume and, when enabled, a soft limiter, and measures the
+/// result: this is the last stage before the samples leave the engine.
+pub struct Meter<S> {
+    inner: S,
+    /// Volume as `f32` bits.
+    volume: Arc<AtomicU32>,
+    limiter: Arc<AtomicBool>,
+    levels: Arc<MeterLevels>,
+    /// The current, gliding volume.
+    gain: f32,
+    /// Limiter gain, 1.0 when it isn't doing anything.
+    limit_gain: f32,
+    limiting: bool,
+    /// The frame being handed out, and how much of it has been.
+    frame: Vec<f32>,
+    frame_pos: usize,
+    frames: u32,
+    block_peak: [f32; 2],
+    block_squares: [f32; 2],
+    block_clipped: bool,
+    block_limited: bool,
+}
+
+impl<S: Source<Item = f32>> Meter<S> {
+    pub fn new(inner: S, volume: Arc<AtomicU32>, limiter: Arc<AtomicBool>, levels: Arc<MeterLevels>) -> Self {
+        let gain = f32::from_bits(volume.load(Ordering::Relaxed));
+        Meter {
+            inner,
+            volume,
+            limiting: limiter.load(Ordering::Relaxed),
+            limiter,
+            levels,
+            gain,
+            limit_gain: 1.0,
+            frame: Vec::with_capacity(8),
+            frame_pos: 0,
+            frames: 0,
+            block_peak: [0.0; 2],
+            block_squares: [0.0; 2],
+            block_clipped: false,
+            block_limited: false,
+        }
+    }
+
+    /// Pulls, processes and measures the next frame.
+    fn next_frame(&mut self) -> bool {
+        let channels = self.inner.channels().max(1) as usize;
+        let rate = self.inner.sample_rate().max(1) as f32;
+        self.frame.clear();
+        self.frame.extend(self.inner.by_ref().take(channels));
+        if self.frame.is_empty() {
+            return false;
+        }
+
+        let target = f32::from_bits(self.volume.load(Ordering::Relaxed));
+        self.gain += (target - self.gain) / (VOLUME_GLIDE_SECS * rate);
+        let gain = self.gain;
+        self.frame.iter_mut().for_each(|s| *s *= gain);
+
+        if self.limiting {
+            // Instant attack, so nothing gets over the ceiling, then a slow
+            // release; all channels share one gain to keep the image steady
+            let peak = self.frame.iter().fold(0.0f32, |p, s| p.max(s.abs()));
+            if peak * self.limit_gain > LIMIT_CEILING {
+                self.limit_gain = LIMIT_CEILING / peak;
+            } else {
+                self.limit_gain += (1.0 - self.limit_gain) / (LIMIT_RELEASE_SECS * rate);
+            }
+            if self.limit_gain < 0.999 {
+                self.block_limited = true;
+            }
+            let limit_gain = self.limit_gain;
+            self.frame.iter_mut().for_each(|s| *s *= limit_gain);
+        }
+
+        for (channel, &sample) in self.frame.iter().enumerate() {
+            // Mono goes to both meters, and only the first two channels count
+            let meters = if channels == 1 { 0..2 } else { channel..channel + 1 };
+            for meter in meters.filter(|m| *m < 2) {
+                self.block_peak[meter] = self.block_peak[meter].max(sample.abs());
+                self.block_squares[meter] += sample * sample;
+            }
+            if sample.abs() > 1.0 {
+                self.block_clipped = true;
+            }
+        }
+        self.frames += 1;
+        if self.frames == BLOCK_FRAMES {
+            self.publish();
+        }
+        true
+    }
+
+    fn publish(&mut self) {
+        for meter in 0..2 {
+            let peak = f32::from_bits(self.levels.peak[meter].load(Ordering::Relaxed));
+            let peak = self.block_peak[meter].max(peak * PEAK_DECAY);
+            self.levels.peak[meter].store(peak.to_bits(), Ordering::Relaxed);
+
+            let rms = f32::from_bits(self.levels.rms[meter].load(Ordering::Relaxed));
+            let mean_square = self.block_squares[meter] / self.frames as f32;
+            let rms = (RMS_WEIGHT * mean_square + (1.0 - RMS_WEIGHT) * rms * rms).sqrt();
+            self.levels.rms[meter].store(rms.to_bits(), Ordering::Relaxed);
+        }
+        if self.block_clipped {
+            self.levels.mark(&self.levels.clipped_at);
+        }
+        if self.block_limited {
+            self.levels.mark(&self.levels.limited_at);
+        }
+
+        self.frames = 0;
+        self.block_peak = [0.0; 2];
+        self.block_squares = [0.0; 2];
+        self.block_clipped = false;
+        self.block_limited = false;
+        let limiting = self.limiter.load(Ordering::Relaxed);
+        if !limiting {
+            self.limit_gain = 1.0;
+        }
+        self.limiting = limiting;
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Meter<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.frame_pos >= self.frame.len() {
+            if !self.next_frame() {
+                return None;
+            }
+            self.frame_pos = 0;
+        }
+        let sample = self.frame[self.frame_pos];
+        self.frame_pos += 1;
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Meter<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        // Plus whatever is left of the frame already pulled
+        self.inner.current_frame_len().map(|len| len + self.frame.len() - self.frame_pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::source::SineWave;
+
+    /// A second of a sine at `amplitude` through a meter at `volume`.
+    fn metered(amplitude: f32, volume: f32, limiter: bool) -> (Vec<f32>, Arc<MeterLevels>) {
+        let levels = Arc::new(MeterLevels::default());
+        let source = SineWave::new(440.0).amplify(amplitude).take_duration(Duration::from_secs(1));
+        let meter = Meter::new(
+            source,
+            Arc::new(AtomicU32::new(volume.to_bits())),
+            Arc::new(AtomicBool::new(limiter)),
+            levels.clone(),
+        );
+        (meter.collect(), levels)
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |p, s| p.max(s.abs()))
+    }
+
+    #[test]
+    fn measures_the_level_after_the_volume() {
+        let (samples, levels) = metered(0.8, 0.5, false);
+        assert!((peak(&samples) - 0.4).abs() < 0.01);
+        for (peak, rms) in levels.levels() {
+            assert!((peak - 0.4).abs() < 0.01, "peak {}", peak);
+            assert!((rms - 0.4 / 2f32.sqrt()).abs() < 0.02, "rms {}", rms);
+        }
+        assert!(!levels.clipped_recently() && !levels.limited_recently());
+    }
+
+    #[test]
+    fn limiter_keeps_peaks_under_the_ceiling() {
+        let (samples, levels) = metered(0.9, 2.0, false);
+        assert!(peak(&samples) > 1.7);
+        assert!(levels.clipped_recently() && !levels.limited_recently());
+
+        let (samples, levels) = metered(0.9, 2.0, true);
+        // Bar rounding in the gain
+        assert!(peak(&samples) <= LIMIT_CEILING + 1e-6, "peak {}", peak(&samples));
+        // Held just under the ceiling rather than squashed
+        assert!(peak(&samples[samples.len() / 2..]) > 0.9);
+        assert!(levels.limited_recently() && !levels.clipped_recently());
+    }
+
+    #[test]
+    fn limiter_lets_quiet_audio_through() {
+        let (samples, levels) = metered(0.5, 1.0, true);
+        assert!((peak(&samples) - 0.5).abs() < 0.01);
+        assert!(!levels.limited_recently());
+    }
+}
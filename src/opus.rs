@@ -0,0 +1,129 @@
+use opus_decoder::OpusMultistreamDecoder;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Result};
+use symphonia::core::formats::Packet;
+
+/// Opus always decodes at 48 kHz.
+const OPUS_RATE: u32 = 48_000;
+/// Longest Opus packet: 120 ms.
+const MAX_FRAMES: usize = 5760;
+
+/// Symphonia decoder for Opus, backed by the pure-Rust `opus-decoder`
+/// crate. Symphonia already demuxes Ogg and Matroska Opus streams but has no
+/// Opus codec of its own.
+pub struct OpusCodec {
+    params: CodecParameters,
+    decoder: OpusMultistreamDecoder,
+    /// Output gain from the OpusHead header, as a linear factor.
+    gain: f32,
+    pcm: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+/// The parts of the OpusHead identification header the decoder needs.
+struct OpusHead {
+    channels: usize,
+    streams: usize,
+    coupled: usize,
+    mapping: Vec<u8>,
+    gain_db: f32,
+}
+
+impl OpusHead {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 19 || &data[..8] != b"OpusHead" {
+            return None;
+        }
+        let channels = data[9] as usize;
+        let gain_db = i16::from_le_bytes([data[16], data[17]]) as f32 / 256.0;
+        let (streams, coupled, mapping) = match data[18] {
+            // Family 0: one stream, coupled when stereo
+            0 => (1, channels.saturating_sub(1), (0..channels as u8).collect()),
+            _ => {
+                let table = data.get(21..21 + channels)?;
+                (data[19] as usize, data[20] as usize, table.to_vec())
+            }
+        };
+        Some(Self { channels, streams, coupled, mapping, gain_db })
+    }
+}
+
+impl Decoder for OpusCodec {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let Some(head) = params.extra_data.as_deref().and_then(OpusHead::parse) else {
+            return unsupported_error("opus: missing or invalid OpusHead");
+        };
+        let Some(channels) = params.channels else {
+            return unsupported_error("opus: unknown channel layout");
+        };
+        if head.channels == 0 || head.channels != channels.count() {
+            return unsupported_error("opus: channel count mismatch");
+        }
+
+        let decoder = match OpusMultistreamDecoder::new(
+            OPUS_RATE,
+            head.channels,
+            head.streams,
+            head.coupled,
+            &head.mapping,
+        ) {
+            Ok(decoder) => decoder,
+            Err(_) => return unsupported_error("opus: unsupported stream layout"),
+        };
+
+        Ok(Self {
+            params: params.clone(),
+            decoder,
+            gain: 10f32.powf(head.gain_db / 20.0),
+            pcm: vec![0.0; MAX_FRAMES * head.channels],
+            buf: AudioBuffer::new(MAX_FRAMES as u64, SignalSpec::new(OPUS_RATE, channels)),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[CodecDescriptor {
+            codec: CODEC_TYPE_OPUS,
+            short_name: "opus",
+            long_name: "Opus",
+            inst_func: |params, options| Ok(Box::new(OpusCodec::try_new(params, options)?)),
+        }]
+    }
+
+    fn reset(&mut self) {
+        self.decoder.reset();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+        let frames = match self.decoder.decode_float(&packet.data, &mut self.pcm, false) {
+            Ok(frames) => frames.min(MAX_FRAMES),
+            Err(_) => return decode_error("opus: invalid packet"),
+        };
+
+        let channels = self.buf.spec().channels.count();
+        self.buf.render_reserved(Some(frames));
+        for c in 0..channels {
+            let plane = self.buf.chan_mut(c);
+            for (i, sample) in plane.iter_mut().enumerate() {
+                *sample = self.pcm[i * channels + c] * self.gain;
+            }
+        }
+        self.buf.trim(packet.trim_start() as usize, packet.trim_end() as usize);
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
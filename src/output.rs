@@ -0,0 +1,194 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use rodio::source::UniformSourceIterator;
+use rodio::{OutputStream, Sink, Source};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Format the software outputs play at, like a typical sound card.
+const SOFT_RATE: u32 = 44100;
+const SOFT_CHANNELS: u16 = 2;
+
+/// How much audio the software outputs pull at a time.
+const SOFT_CHUNK: Duration = Duration::from_millis(10);
+
+pub type BoxedSource = Box<dyn Source<Item = f32> + Send>;
+
+/// Where the engine sends its audio. Mirrors the parts of rodio's `Sink`
+/// the engine needs, so playback works the same on a real device, with no
+/// device at all, or into a file. Pausing and volume are done in the
+/// engine's own pipeline, so they behave the same everywhere.
+pub trait Output {
+    /// Queues a source to play after whatever is already queued.
+    fn append(&self, source: BoxedSource);
+    /// True once every queued source has played out.
+    fn empty(&self) -> bool;
+}
+
+/// Names of the output devices the default audio host offers.
+pub fn output_devices() -> Vec<String> {
+    cpal::default_host()
+        .output_devices()
+        .map(|devices| devices.filter_map(|d| d.name().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// A sound card, through rodio.
+pub struct RodioOutput {
+    _stream: OutputStream,
+    sink: Sink,
+}
+
+impl RodioOutput {
+    /// Opens `device` by name, or the system default when `None`.
+    pub fn open(device: Option<&str>) -> Result<Self, String> {
+        let (stream, handle) = match device {
+            None => OutputStream::try_default()
+                .map_err(|e| format!("Failed to create audio stream: {}", e))?,
+            Some(name) => {
+                let device = cpal::default_host()
+                    .output_devices()
+                    .map_err(|e| format!("Failed to list output devices: {}", e))?
+                    .find(|d| d.name().map(|n| n == name).unwrap_or(false))
+                    .ok_or_else(|| format!("Output device not found: {}", name))?;
+                OutputStream::try_from_device(&device)
+                    .map_err(|e| format!("Failed to create audio stream: {}", e))?
+            }
+        };
+        let sink = Sink::try_new(&handle)
+            .map_err(|e| format!("Failed to create sink: {}", e))?;
+        Ok(Self { _stream: stream, sink })
+    }
+}
+
+impl Output for RodioOutput {
+    fn append(&self, source: BoxedSource) {
+        self.sink.append(source);
+    }
+
+    fn empty(&self) -> bool {
+        self.sink.empty()
+    }
+}
+
+/// State shared between a software output and its playback thread.
+struct SoftState {
+    queue: Mutex<VecDeque<UniformSourceIterator<BoxedSource, f32>>>,
+    closed: AtomicBool,
+}
+
+/// An output without a sound card: a thread pulls samples at the pace a
+/// device would and hands them to `write`. See `null_output` and
+/// `wav_output`.
+pub struct SoftOutput {
+    state: Arc<SoftState>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SoftOutput {
+    fn spawn<W>(mut write: W) -> Self
+    where
+        W: FnMut(&[f32]) + Send + 'static,
+    {
+        let state = Arc::new(SoftState {
+            queue: Mutex::new(VecDeque::new()),
+            closed: AtomicBool::new(false),
+        });
+
+        let thread_state = state.clone();
+        let thread = thread::spawn(move || {
+            let state = thread_state;
+            let chunk_frames = (SOFT_RATE as u128 * SOFT_CHUNK.as_millis() / 1000) as usize;
+            let mut chunk = Vec::with_capacity(chunk_frames * SOFT_CHANNELS as usize);
+            // Deadlines are kept against a fixed start so sleeping doesn't drift
+            let mut started = Instant::now();
+            let mut frames_played: u64 = 0;
+
+            while !state.closed.load(Ordering::SeqCst) {
+                let idle = state.queue.lock().unwrap().is_empty();
+                if idle {
+                    thread::sleep(SOFT_CHUNK);
+                    started = Instant::now();
+                    frames_played = 0;
+                    continue;
+                }
+
+                chunk.clear();
+                {
+                    let mut queue = state.queue.lock().unwrap();
+                    while chunk.len() < chunk.capacity() {
+                        let Some(source) = queue.front_mut() else {
+                            break;
+                        };
+                        match source.next() {
+                            Some(sample) => chunk.push(sample),
+                            None => {
+                                queue.pop_front();
+                            }
+                        }
+                    }
+                }
+                write(&chunk);
+
+                frames_played += (chunk.len() / SOFT_CHANNELS as usize) as u64;
+                let due = started + Duration::from_secs_f64(frames_played as f64 / SOFT_RATE as f64);
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+        });
+
+        Self { state, thread: Some(thread) }
+    }
+}
+
+impl Output for SoftOutput {
+    fn append(&self, source: BoxedSource) {
+        let source = UniformSourceIterator::new(source, SOFT_CHANNELS, SOFT_RATE);
+        self.state.queue.lock().unwrap().push_back(source);
+    }
+
+    fn empty(&self) -> bool {
+        self.state.queue.lock().unwrap().is_empty()
+    }
+}
+
+impl Drop for SoftOutput {
+    fn drop(&mut self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Plays into nothing, in real time. Used when there is no sound card, and
+/// for exercising the player headless.
+pub fn null_output() -> SoftOutput {
+    SoftOutput::spawn(|_| {})
+}
+
+/// Records everything played, in real time, to a 16-bit stereo WAV file.
+pub fn wav_output(path: &str) -> Result<SoftOutput, String> {
+    let spec = hound::WavSpec {
+        channels: SOFT_CHANNELS,
+        sample_rate: SOFT_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)
+        .map_err(|e| format!("Failed to create WAV file: {}", e))?;
+    // Flushing rewrites the header, so the file stays playable even if the
+    // player is killed
+    Ok(SoftOutput::spawn(move |samples| {
+        for &sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            if writer.write_sample(sample).is_err() {
+                return;
+            }
+        }
+        writer.flush().ok();
+    }))
+}
@@ -1,6 +1,13 @@
-use std::fs;
-use std::path::Path;
+use crate::cue;
+use crate::m3u;
+use crate::pls;
+use crate::xspf;
+use lofty::{file::AudioFile, file::TaggedFileExt, prelude::Accessor, probe::Probe};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 use rand::seq::SliceRandom;
+use rand::Rng;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum RepeatMode {
@@ -14,7 +21,30 @@ pub struct Playlist {
     current: usize,
     selected: usize,
     shuffle: bool,
+    /// Play order while shuffled, as indices into `tracks`, which keep
+    /// their own order. Tracks before `position` have been played.
+    order: Vec<usize>,
+    /// Where the current track is in `order`.
+    position: usize,
     repeat: RepeatMode,
+    /// Stop when the current track ends instead of moving on.
+    stop_after_current: bool,
+    /// Name given in the last playlist file loaded.
+    name: Option<String>,
+    /// Titles and lengths from loaded playlist files, by entry.
+    info: HashMap<String, EntryInfo>,
+    /// Tracks to play next, ahead of the playlist order; each is taken off
+    /// as it starts.
+    queue: VecDeque<String>,
+    /// The track playing came from the queue. `current` still points at
+    /// the playlist entry the order carries on from.
+    from_queue: Option<String>,
+}
+
+impl Default for Playlist {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Playlist {
@@ -24,64 +54,172 @@ impl Playlist {
             current: 0,
             selected: 0,
             shuffle: false,
+            order: Vec::new(),
+            position: 0,
             repeat: RepeatMode::Off,
+            stop_after_current: false,
+            name: None,
+            info: HashMap::new(),
+            queue: VecDeque::new(),
+            from_queue: None,
         }
     }
 
-    pub fn load_m3u(&mut self, path: &str) -> Result<(), String> {
-        let content = fs::read_to_string(path)
-            .map_err(|e| format!("Failed to read M3U: {}", e))?;
-        
-        let base_dir = Path::new(path).parent().unwrap_or(Path::new("."));
-        
-        for line in content.lines() {
-            let line = line.trim();
-            if !line.is_empty() && !line.starts_with('#') {
-                let track_path = base_dir.join(line);
-                self.tracks.push(track_path.to_string_lossy().to_string());
+    /// Loads an M3U, PLS or XSPF playlist or a CUE sheet, going by the
+    /// extension.
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let file = Path::new(path);
+        if cue::is_cue_file(file) {
+            self.add_tracks(cue::expand(path)?);
+            return Ok(());
+        }
+        let playlist = if pls::is_pls_file(file) {
+            pls::parse(path)?
+        } else if xspf::is_xspf_file(file) {
+            xspf::parse(path)?
+        } else {
+            m3u::parse(path)?
+        };
+        if playlist.name.is_some() {
+            self.name = playlist.name;
+        }
+
+        let mut tracks = Vec::new();
+        for (track_path, info) in playlist.entries {
+            // A listed CUE sheet stands for all of its tracks
+            if cue::is_cue_file(Path::new(&track_path)) {
+                match cue::expand(&track_path) {
+                    Ok(expanded) => tracks.extend(expanded),
+                    Err(_) => tracks.push(track_path),
+                }
+            } else {
+                self.info.insert(track_path.clone(), info);
+                tracks.push(track_path);
             }
         }
+        self.add_tracks(tracks);
         Ok(())
     }
 
     pub fn current(&self) -> Option<&str> {
+        if let Some(queued) = &self.from_queue {
+            return Some(queued);
+        }
         self.tracks.get(self.current).map(|s| s.as_str())
     }
 
+    /// Moves to the next track: the first queued one if any, otherwise
+    /// the next in playlist order. Also clears stop-after-current, which
+    /// only ever applies to the track it was set on.
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<&str> {
+        self.stop_after_current = false;
+        self.from_queue = self.queue.pop_front();
+        if self.from_queue.is_some() {
+            return self.current();
+        }
         if self.tracks.is_empty() {
             return None;
         }
-        
-        match self.repeat {
-            RepeatMode::One => {},
-            RepeatMode::All => {
-                self.current = (self.current + 1) % self.tracks.len();
-            }
-            RepeatMode::Off => {
-                if self.current + 1 < self.tracks.len() {
-                    self.current += 1;
-                }
-            }
+
+        match self.following() {
+            Some(position) => self.move_to(position),
+            None => self.selected = self.current,
         }
-        self.selected = self.current;
         self.current()
     }
 
+    /// Moves to `path`, which playback has already moved on to by itself.
+    /// That is normally the track `next()` would pick, but the queue may
+    /// have changed since, so it is looked for in the queue, then the
+    /// playlist; a track in neither plays as if it had been queued.
+    pub fn advance_to(&mut self, path: &str) -> Option<&str> {
+        self.stop_after_current = false;
+        if let Some(index) = self.queue.iter().position(|queued| queued == path) {
+            self.from_queue = self.queue.remove(index);
+            return self.current();
+        }
+        self.from_queue = None;
+        let following = if self.tracks.is_empty() { None } else { self.following() };
+        let position = following.filter(|p| self.tracks[self.track_at(*p)] == path).or_else(|| {
+            let index = self.tracks.iter().position(|track| track == path)?;
+            if self.shuffle { self.order.iter().position(|i| *i == index) } else { Some(index) }
+        });
+        match position {
+            Some(position) => self.move_to(position),
+            None => self.from_queue = Some(path.to_string()),
+        }
+        self.current()
+    }
+
+    /// The track `next()` would move to, without moving. `None` when playback
+    /// would stop at the end of the playlist, or after the current track.
+    pub fn peek_next(&self) -> Option<&str> {
+        if self.stop_after_current {
+            return None;
+        }
+        if let Some(queued) = self.queue.front() {
+            return Some(queued);
+        }
+        if self.tracks.is_empty() {
+            return None;
+        }
+
+        let index = self.track_at(self.following()?);
+        self.tracks.get(index).map(|s| s.as_str())
+    }
+
+    /// Moves to the previous track: back through the shuffle history when
+    /// shuffled, staying on the first track played once there, and after
+    /// a queued track to the playlist entry that played before it.
     pub fn previous(&mut self) -> Option<&str> {
+        self.stop_after_current = false;
+        if self.from_queue.take().is_some() {
+            return self.current();
+        }
         if self.tracks.is_empty() {
             return None;
         }
-        
-        self.current = if self.current == 0 {
-            self.tracks.len() - 1
-        } else {
-            self.current - 1
+
+        let position = match self.position() {
+            // Nothing was played before the start of the shuffle history
+            0 if self.shuffle => 0,
+            0 => self.tracks.len() - 1,
+            position => position - 1,
         };
-        self.selected = self.current;
+        self.move_to(position);
         self.current()
     }
 
+    /// Where the current track is in the play order.
+    fn position(&self) -> usize {
+        if self.shuffle { self.position } else { self.current }
+    }
+
+    /// The track at `position` in the play order.
+    fn track_at(&self, position: usize) -> usize {
+        if self.shuffle { self.order[position] } else { position }
+    }
+
+    /// The play order position `next()` moves to; `None` at the end with
+    /// repeat off.
+    fn following(&self) -> Option<usize> {
+        let position = self.position();
+        match self.repeat {
+            RepeatMode::One => Some(position),
+            RepeatMode::All => Some((position + 1) % self.tracks.len()),
+            RepeatMode::Off => Some(position + 1).filter(|p| *p < self.tracks.len()),
+        }
+    }
+
+    fn move_to(&mut self, position: usize) {
+        if self.shuffle {
+            self.position = position;
+        }
+        self.current = self.track_at(position);
+        self.selected = self.current;
+    }
+
     pub fn select_next(&mut self) {
         if !self.tracks.is_empty() {
             self.selected = (self.selected + 1) % self.tracks.len();
@@ -98,20 +236,88 @@ impl Playlist {
         }
     }
 
+    /// Makes the selected track current. When shuffled it is moved up to
+    /// play next in the shuffle order, so the history before it stays.
     pub fn play_selected(&mut self) {
+        self.stop_after_current = false;
+        self.from_queue = None;
+        if self.shuffle && self.selected != self.current {
+            if let Some(at) = self.order.iter().position(|&i| i == self.selected) {
+                self.order.remove(at);
+                if at < self.position {
+                    self.position -= 1;
+                }
+                self.position += 1;
+                self.order.insert(self.position, self.selected);
+            }
+        }
         self.current = self.selected;
     }
 
+    /// Adds a track to the end of the play-next queue.
+    pub fn enqueue(&mut self, path: String) {
+        self.queue.push_back(path);
+    }
+
+    pub fn queue(&self) -> &VecDeque<String> {
+        &self.queue
+    }
+
+    pub fn playing_from_queue(&self) -> bool {
+        self.from_queue.is_some()
+    }
+
+    /// Swaps a queued track with the one before (`up`) or after it.
+    /// Returns whether it moved.
+    pub fn move_queued(&mut self, index: usize, up: bool) -> bool {
+        let other = if up { index.checked_sub(1) } else { Some(index + 1) };
+        match other.filter(|o| *o < self.queue.len() && index < self.queue.len()) {
+            Some(other) => {
+                self.queue.swap(index, other);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove_queued(&mut self, index: usize) -> Option<String> {
+        self.queue.remove(index)
+    }
+
+    /// Moves a queued track to the front, so `next()` plays it.
+    pub fn queue_to_front(&mut self, index: usize) {
+        if let Some(track) = self.queue.remove(index) {
+            self.queue.push_front(track);
+        }
+    }
+
+    /// Turns shuffle on, with a fresh order starting from the current
+    /// track, or off, carrying on in playlist order from the current track.
     pub fn toggle_shuffle(&mut self) {
         self.shuffle = !self.shuffle;
+        self.position = 0;
+        self.order.clear();
         if self.shuffle && !self.tracks.is_empty() {
-            let current_track = self.tracks[self.current].clone();
-            let mut rng = rand::thread_rng();
-            self.tracks.shuffle(&mut rng);
-            // Keep current track at current position
-            if let Some(pos) = self.tracks.iter().position(|t| t == &current_track) {
-                self.tracks.swap(self.current, pos);
-            }
+            self.order = (0..self.tracks.len()).filter(|i| *i != self.current).collect();
+            self.order.shuffle(&mut rand::thread_rng());
+            self.order.insert(0, self.current);
+        }
+    }
+
+    /// Gives tracks added from `first` on random places in the shuffle
+    /// order, all still to come.
+    fn shuffle_in(&mut self, first: usize) {
+        if !self.shuffle {
+            return;
+        }
+        let mut rng = rand::thread_rng();
+        for index in first..self.tracks.len() {
+            let at = if self.order.is_empty() {
+                0
+            } else {
+                rng.gen_range(self.position + 1..=self.order.len())
+            };
+            self.order.insert(at, index);
         }
     }
 
@@ -123,6 +329,23 @@ impl Playlist {
         };
     }
 
+    pub fn set_stop_after_current(&mut self, stop: bool) {
+        self.stop_after_current = stop;
+    }
+
+    pub fn stop_after_current(&self) -> bool {
+        self.stop_after_current
+    }
+
+    /// What the playlist file it came from says about an entry, if anything.
+    pub fn info(&self, path: &str) -> Option<&EntryInfo> {
+        self.info.get(path)
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn tracks(&self) -> &[String] {
         &self.tracks
     }
@@ -149,10 +372,12 @@ impl Playlist {
             self.selected = 0;
             self.current = 0;
         }
+        self.shuffle_in(self.tracks.len() - 1);
     }
 
     pub fn add_tracks(&mut self, paths: Vec<String>) {
         let was_empty = self.tracks.is_empty();
+        let first = self.tracks.len();
         for path in paths {
             self.tracks.push(path);
         }
@@ -160,6 +385,44 @@ impl Playlist {
             self.selected = 0;
             self.current = 0;
         }
+        self.shuffle_in(first);
+    }
+
+    /// Replaces the leading `from` directory of every entry under it with
+    /// `to`, for music that has moved, in the queue too. Returns how many
+    /// playlist entries changed.
+    pub fn rebase(&mut self, from: &Path, to: &Path) -> usize {
+        let mut changed = 0;
+        for track in self.tracks.iter_mut() {
+            let Ok(rest) = Path::new(track.as_str()).strip_prefix(from) else {
+                continue;
+            };
+            let rebased = to.join(rest).to_string_lossy().to_string();
+            if let Some(info) = self.info.remove(track.as_str()) {
+                self.info.insert(rebased.clone(), info);
+            }
+            *track = rebased;
+            changed += 1;
+        }
+        for track in self.queue.iter_mut() {
+            if let Ok(rest) = Path::new(track.as_str()).strip_prefix(from) {
+                *track = to.join(rest).to_string_lossy().to_string();
+            }
+        }
+        changed
+    }
+
+    /// The deepest directory every entry is under, if they share one.
+    pub fn common_dir(&self) -> Option<PathBuf> {
+        let mut common = Path::new(self.tracks.first()?).parent()?.to_path_buf();
+        for track in &self.tracks[1..] {
+            while !Path::new(track).starts_with(&common) {
+                if !common.pop() {
+                    return None;
+                }
+            }
+        }
+        Some(common).filter(|dir| !dir.as_os_str().is_empty())
     }
 
     pub fn select_index(&mut self, index: usize) {
@@ -168,8 +431,16 @@ impl Playlist {
         }
     }
 
+    /// Empties the playlist, its play-next queue and stop-after-current.
     pub fn clear(&mut self) {
         self.tracks.clear();
+        self.info.clear();
+        self.name = None;
+        self.queue.clear();
+        self.from_queue = None;
+        self.stop_after_current = false;
+        self.order.clear();
+        self.position = 0;
         self.current = 0;
         self.selected = 0;
     }
@@ -177,6 +448,9 @@ impl Playlist {
     pub fn remove_selected(&mut self) -> bool {
         if self.selected < self.tracks.len() {
             self.tracks.remove(self.selected);
+            if self.shuffle {
+                self.remove_from_order(self.selected);
+            }
             
             // Adjust indices
             if self.tracks.is_empty() {
@@ -195,4 +469,461 @@ impl Playlist {
             false
         }
     }
+
+    /// Takes a removed track out of the shuffle order, which then carries
+    /// on from the same place.
+    fn remove_from_order(&mut self, removed: usize) {
+        if let Some(at) = self.order.iter().position(|&i| i == removed) {
+            self.order.remove(at);
+            if at < self.position {
+                self.position -= 1;
+            }
+        }
+        for index in self.order.iter_mut() {
+            if *index > removed {
+                *index -= 1;
+            }
+        }
+        self.position = self.position.min(self.order.len().saturating_sub(1));
+        if let Some(&index) = self.order.get(self.position) {
+            self.current = index;
+        }
+    }
+}
+
+/// What a playlist file says about an entry, so it can be shown without
+/// opening the file.
+#[derive(Clone, Default)]
+pub struct EntryInfo {
+    pub duration: Option<Duration>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub group: Option<String>,
+}
+
+impl EntryInfo {
+    /// Reads the same details from the file itself, or from its CUE sheet.
+    pub fn probe(path: &str) -> Self {
+        if let Some((sheet, track)) = cue::lookup(path) {
+            return EntryInfo {
+                duration: track.end.map(|end| end.saturating_sub(track.start)),
+                artist: track.performer,
+                title: track.title,
+                album: sheet.title.clone(),
+                group: None,
+            };
+        }
+        let Ok(tagged_file) = Probe::open(path).and_then(|p| p.read()) else {
+            return EntryInfo::default();
+        };
+        let duration = Some(tagged_file.properties().duration()).filter(|d| !d.is_zero());
+        let tag = tagged_file.primary_tag().or_else(|| tagged_file.first_tag());
+        EntryInfo {
+            duration,
+            artist: tag.and_then(|t| t.artist().map(|a| a.to_string())),
+            title: tag.and_then(|t| t.title().map(|t| t.to_string())),
+            album: tag.and_then(|t| t.album().map(|a| a.to_string())),
+            group: None,
+        }
+    }
+
+    /// Splits the "Artist - Title" that playlist formats store as one line.
+    pub fn from_text(duration: Option<Duration>, text: &str) -> Self {
+        let text = text.trim();
+        let (artist, title) = match text.split_once(" - ") {
+            Some((artist, title)) => (Some(artist.trim().to_string()), title.trim().to_string()),
+            None => (None, text.to_string()),
+        };
+        EntryInfo {
+            duration,
+            artist,
+            title: Some(title).filter(|t| !t.is_empty()),
+            ..Default::default()
+        }
+    }
+
+    /// "Artist - Title", or just the title.
+    pub fn display_name(&self) -> Option<String> {
+        let title = self.title.as_ref()?;
+        Some(match &self.artist {
+            Some(artist) => format!("{} - {}", artist, title),
+            None => title.clone(),
+        })
+    }
+}
+
+/// The contents of a playlist file, whatever its format.
+#[derive(Default)]
+pub struct PlaylistFile {
+    pub name: Option<String>,
+    pub entries: Vec<(String, EntryInfo)>,
+}
+
+pub fn is_playlist_file(path: &Path) -> bool {
+    m3u::is_m3u_file(path) || pls::is_pls_file(path) || xspf::is_xspf_file(path) || cue::is_cue_file(path)
+}
+
+/// Streams and other URLs in a playlist, which are kept as they are.
+pub fn is_url(entry: &str) -> bool {
+    entry.contains("://")
+}
+
+/// How `path` goes into a saved playlist: relative to `relative_to` if
+/// given and possible, as it is otherwise.
+pub fn written_path(path: &str, relative_to: Option<&Path>) -> String {
+    match relative_to.filter(|_| !is_url(path)).and_then(|dir| relative_path(Path::new(path), dir)) {
+        Some(relative) => relative.to_string_lossy().to_string(),
+        None => path.to_string(),
+    }
+}
+
+/// `path` as seen from `dir`, or `None` if they share no root (another
+/// drive on Windows).
+pub fn relative_path(path: &Path, dir: &Path) -> Option<PathBuf> {
+    let path = std::path::absolute(path).ok()?;
+    let dir = std::path::absolute(dir).ok()?;
+    let mut path_parts = path.components().peekable();
+    let mut dir_parts = dir.components().peekable();
+    if path_parts.peek() != dir_parts.peek() {
+        return None;
+    }
+    while path_parts.peek().is_some() && path_parts.peek() == dir_parts.peek() {
+        path_parts.next();
+        dir_parts.next();
+    }
+    let mut relative: PathBuf = dir_parts.map(|_| Component::ParentDir).collect();
+    relative.extend(path_parts);
+    Some(relative)
+}
+
+/// Resolves playlist entries against the playlist's directory, looking
+/// for files that have moved: each missing file is looked for by the end
+/// of its path, at least its folder and name, under the playlist's
+/// directory and the two above it. Once one turns up, the rest of the
+/// playlist is tried under the same move first.
+pub struct Locator {
+    base_dir: PathBuf,
+    /// Old and new prefixes of the moves found so far.
+    moves: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Locator {
+    pub fn new(base_dir: &Path) -> Self {
+        Locator { base_dir: base_dir.to_path_buf(), moves: Vec::new() }
+    }
+
+    pub fn resolve(&mut self, entry: &str) -> String {
+        if is_url(entry) {
+            return entry.to_string();
+        }
+        let path = self.base_dir.join(entry).to_string_lossy().to_string();
+        // For a CUE entry it is the sheet that has to be found
+        let (file, track) = match cue::split_ref(&path) {
+            Some((sheet, number)) => (PathBuf::from(sheet), format!("#{}", number)),
+            None => (PathBuf::from(&path), String::new()),
+        };
+        if file.exists() {
+            return path;
+        }
+        match self.find(&file) {
+            Some(found) => format!("{}{}", found.to_string_lossy(), track),
+            None => path,
+        }
+    }
+
+    fn find(&mut self, file: &Path) -> Option<PathBuf> {
+        let file = &normalize(file);
+        for (old, new) in &self.moves {
+            if let Ok(rest) = file.strip_prefix(old) {
+                let candidate = new.join(rest);
+                if candidate.exists() {
+                    return Some(candidate);
+                }
+            }
+        }
+
+        let parts: Vec<_> = file.components().filter(|c| matches!(c, Component::Normal(_))).collect();
+        for dir in self.base_dir.ancestors().take(3) {
+            // Longest tail first, down to the file and its folder; a file
+            // name alone is too likely to match some other file
+            for skip in 0..parts.len().saturating_sub(1) {
+                let tail: PathBuf = parts[skip..].iter().collect();
+                let candidate = dir.join(&tail);
+                if candidate.exists() {
+                    if let Some(old) = file.ancestors().nth(parts.len() - skip) {
+                        self.moves.push((old.to_path_buf(), dir.to_path_buf()));
+                    }
+                    return Some(candidate);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// `path` with `.` and `..` worked out, without looking at the disk.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normal.pop() {
+                    normal.push(component);
+                }
+            }
+            _ => normal.push(component),
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn playlist(tracks: &[&str]) -> Playlist {
+        let mut playlist = Playlist::new();
+        playlist.add_tracks(tracks.iter().map(|t| t.to_string()).collect());
+        playlist
+    }
+
+    #[test]
+    fn queued_tracks_play_before_the_playlist_carries_on() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+        playlist.enqueue("x".to_string());
+        playlist.enqueue("y".to_string());
+        assert_eq!(playlist.peek_next(), Some("x"));
+
+        assert_eq!(playlist.next(), Some("x"));
+        assert!(playlist.playing_from_queue());
+        assert_eq!(playlist.current_index(), 0);
+        assert_eq!(playlist.next(), Some("y"));
+        assert_eq!(playlist.peek_next(), Some("b"));
+        assert_eq!(playlist.next(), Some("b"));
+        assert!(!playlist.playing_from_queue());
+        assert!(playlist.queue().is_empty());
+    }
+
+    #[test]
+    fn previous_from_a_queued_track_goes_back_to_the_playlist() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+        playlist.next();
+        playlist.enqueue("x".to_string());
+        assert_eq!(playlist.next(), Some("x"));
+        assert_eq!(playlist.previous(), Some("b"));
+        assert_eq!(playlist.next(), Some("c"));
+    }
+
+    #[test]
+    fn advancing_follows_the_track_that_started() {
+        let mut playlist = playlist(&["a", "b", "c", "b"]);
+        // Queued after playback had already moved on to "b"
+        playlist.enqueue("x".to_string());
+        assert_eq!(playlist.advance_to("b"), Some("b"));
+        assert_eq!((playlist.current_index(), playlist.queue().len()), (1, 1));
+        assert_eq!(playlist.advance_to("x"), Some("x"));
+        assert!(playlist.playing_from_queue());
+
+        // Taken off the queue after playback had moved on to it
+        playlist.enqueue("y".to_string());
+        playlist.remove_queued(0);
+        assert_eq!(playlist.advance_to("y"), Some("y"));
+        assert!(playlist.playing_from_queue());
+        assert_eq!(playlist.next(), Some("c"));
+
+        // Picked from elsewhere in the playlist
+        assert_eq!(playlist.advance_to("a"), Some("a"));
+        assert_eq!(playlist.current_index(), 0);
+        assert_eq!(playlist.advance_to("b"), Some("b"));
+        assert_eq!(playlist.current_index(), 1);
+    }
+
+    #[test]
+    fn queue_can_be_reordered() {
+        let mut playlist = playlist(&["a"]);
+        for track in ["x", "y", "z"] {
+            playlist.enqueue(track.to_string());
+        }
+        assert!(playlist.move_queued(0, false));
+        assert_eq!(playlist.queue(), &["y", "x", "z"]);
+        assert!(!playlist.move_queued(0, true));
+        assert!(!playlist.move_queued(2, false));
+        assert!(!playlist.move_queued(5, true));
+
+        playlist.queue_to_front(2);
+        assert_eq!(playlist.queue(), &["z", "y", "x"]);
+        assert_eq!(playlist.remove_queued(1).as_deref(), Some("y"));
+        assert_eq!(playlist.remove_queued(5), None);
+        assert_eq!(playlist.queue(), &["z", "x"]);
+    }
+
+    #[test]
+    fn stop_after_current_holds_back_the_queue() {
+        let mut playlist = playlist(&["a", "b"]);
+        playlist.enqueue("x".to_string());
+        playlist.set_stop_after_current(true);
+        assert_eq!(playlist.peek_next(), None);
+        // It only ever applies to the track it was set on
+        assert_eq!(playlist.next(), Some("x"));
+        assert!(!playlist.stop_after_current());
+    }
+
+    #[test]
+    fn clear_empties_the_queue_too() {
+        let mut playlist = playlist(&["a", "b"]);
+        playlist.enqueue("x".to_string());
+        playlist.enqueue("y".to_string());
+        playlist.next();
+        playlist.set_stop_after_current(true);
+
+        playlist.clear();
+        assert!(playlist.queue().is_empty());
+        assert!(!playlist.playing_from_queue());
+        assert!(!playlist.stop_after_current());
+        assert_eq!(playlist.current(), None);
+        assert_eq!(playlist.peek_next(), None);
+        playlist.add_tracks(vec!["c".to_string()]);
+        assert_eq!(playlist.current(), Some("c"));
+    }
+
+    /// Every track `next()` plays from the current one on, until it stops.
+    fn play_through(playlist: &mut Playlist) -> Vec<String> {
+        let mut played = vec![playlist.current().unwrap().to_string()];
+        while let Some(next) = playlist.peek_next() {
+            let next = next.to_string();
+            assert_eq!(playlist.next(), Some(next.as_str()));
+            played.push(next);
+        }
+        played
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once_from_the_current_one() {
+        let tracks: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        let mut playlist = Playlist::new();
+        playlist.add_tracks(tracks.clone());
+        playlist.select_index(7);
+        playlist.play_selected();
+        playlist.toggle_shuffle();
+
+        let mut played = play_through(&mut playlist);
+        assert_eq!(played[0], "7");
+        played.sort_by_key(|t| t.parse::<u32>().unwrap());
+        assert_eq!(played, tracks);
+        // The playlist itself keeps its order
+        assert_eq!(playlist.tracks(), &tracks[..]);
+    }
+
+    #[test]
+    fn shuffle_goes_back_through_what_played() {
+        let mut playlist = playlist(&["a", "b", "c", "d", "e"]);
+        playlist.toggle_shuffle();
+        let played: Vec<String> = (0..3).map(|_| playlist.next().unwrap().to_string()).collect();
+
+        assert_eq!(playlist.previous(), Some(played[1].as_str()));
+        assert_eq!(playlist.previous(), Some(played[0].as_str()));
+        assert_eq!(playlist.previous(), Some("a"));
+        // The start of the history is as far back as it goes
+        assert_eq!(playlist.previous(), Some("a"));
+        assert_eq!(playlist.next(), Some(played[0].as_str()));
+    }
+
+    #[test]
+    fn shuffle_off_carries_on_in_playlist_order() {
+        let mut playlist = playlist(&["a", "b", "c", "d", "e"]);
+        playlist.toggle_shuffle();
+        playlist.next();
+        let current = playlist.current_index();
+
+        playlist.toggle_shuffle();
+        assert!(!playlist.is_shuffle());
+        assert_eq!(playlist.current_index(), current);
+        let rest: Vec<String> = playlist.tracks()[current..].to_vec();
+        assert_eq!(play_through(&mut playlist), rest);
+    }
+
+    #[test]
+    fn shuffle_history_survives_picking_and_adding_tracks() {
+        let mut playlist = playlist(&["a", "b", "c", "d", "e"]);
+        playlist.toggle_shuffle();
+        let first = playlist.next().unwrap().to_string();
+
+        // A picked track plays next in the order, after what has played
+        let picked = playlist.tracks().iter().position(|t| *t != "a" && *t != first).unwrap();
+        playlist.select_index(picked);
+        playlist.play_selected();
+        assert_eq!(playlist.previous(), Some(first.as_str()));
+        assert_eq!(playlist.previous(), Some("a"));
+        playlist.next();
+        playlist.next();
+
+        // Added and removed tracks leave the history alone
+        playlist.add_tracks(vec!["f".to_string(), "g".to_string()]);
+        let removed = playlist.tracks().iter().position(|t| *t == "f").unwrap();
+        playlist.select_index(removed);
+        playlist.remove_selected();
+        let mut played = vec!["a".to_string(), first];
+        played.extend(play_through(&mut playlist));
+        played.sort();
+        assert_eq!(played, ["a", "b", "c", "d", "e", "g"]);
+    }
+
+    #[test]
+    fn relative_paths() {
+        let rel = |path: &str, dir: &str| relative_path(Path::new(path), Path::new(dir)).unwrap();
+        assert_eq!(rel("/music/a/song.mp3", "/music/a"), Path::new("song.mp3"));
+        assert_eq!(rel("/music/a/b/song.mp3", "/music/a"), Path::new("b/song.mp3"));
+        assert_eq!(rel("/music/a/song.mp3", "/music/lists"), Path::new("../a/song.mp3"));
+        assert_eq!(rel("/other/song.mp3", "/music/lists"), Path::new("../../other/song.mp3"));
+        assert_eq!(written_path("/music/a/song.mp3", None), "/music/a/song.mp3");
+        assert_eq!(written_path("/music/a/song.mp3", Some(Path::new("/music"))), "a/song.mp3");
+    }
+
+    #[test]
+    fn locator_keeps_entries_that_exist() {
+        let dir = TempDir::new("playlist-exists");
+        let song = dir.write("a/song.mp3", b"");
+        dir.write("lists/list.m3u", b"");
+        let mut locator = Locator::new(&dir.path().join("lists"));
+        assert_eq!(Path::new(&locator.resolve("../a/song.mp3")), dir.path().join("lists/../a/song.mp3"));
+        assert_eq!(locator.resolve(&song), song);
+        // Missing and nowhere to be found: left as written
+        assert_eq!(Path::new(&locator.resolve("gone/song.mp3")), dir.path().join("lists/gone/song.mp3"));
+    }
+
+    #[test]
+    fn locator_finds_moved_files_by_folder_and_name() {
+        let dir = TempDir::new("playlist-moved");
+        let one = dir.write("Music/Artist/Album/01.mp3", b"");
+        let two = dir.write("Music/Artist/Album/02.mp3", b"");
+        let mut locator = Locator::new(&dir.path().join("Music"));
+        assert_eq!(locator.resolve("/old/home/Artist/Album/01.mp3"), one);
+        // The same move again, found from the first
+        assert_eq!(locator.moves.len(), 1);
+        assert_eq!(locator.resolve("/old/home/Artist/Album/02.mp3"), two);
+        assert_eq!(locator.moves.len(), 1);
+    }
+
+    #[test]
+    fn locator_needs_more_than_a_file_name() {
+        let dir = TempDir::new("playlist-name-only");
+        dir.write("Music/Other/01.mp3", b"");
+        let mut locator = Locator::new(&dir.path().join("Music"));
+        let entry = "/old/home/Album/01.mp3";
+        assert_eq!(locator.resolve(entry), entry);
+    }
+
+    #[test]
+    fn locator_works_out_parent_dirs_first() {
+        let dir = TempDir::new("playlist-parent");
+        let song = dir.write("Music/Album/song.mp3", b"");
+        let mut locator = Locator::new(&dir.path().join("Music"));
+        // "Gone" is not part of where the file was
+        assert_eq!(locator.resolve("/old/Album/Gone/../song.mp3"), song);
+        assert_eq!(normalize(Path::new("/a/./b/../c")), Path::new("/a/c"));
+        assert_eq!(normalize(Path::new("../a")), Path::new("../a"));
+    }
 }
@@ -0,0 +1,160 @@
+use crate::cue;
+use crate::playlist::{self, EntryInfo, Locator, PlaylistFile};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+pub fn is_pls_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pls"))
+}
+
+/// Reads a PLS playlist: numbered `FileN=`, `TitleN=` and `LengthN=`
+/// keys, in the order of their numbers.
+pub fn parse(path: &str) -> Result<PlaylistFile, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read PLS: {}", e))?;
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    let mut locator = Locator::new(base_dir);
+
+    // Entry number to file, title and length
+    let mut entries: BTreeMap<u32, (Option<String>, String, Option<Duration>)> = BTreeMap::new();
+    for line in cue::decode_text(&bytes).lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+        let Some((field, number)) = ["file", "title", "length"]
+            .into_iter()
+            .find_map(|field| Some((field, key.strip_prefix(field)?.parse::<u32>().ok()?)))
+        else {
+            continue;
+        };
+        let entry = entries.entry(number).or_default();
+        match field {
+            "file" => entry.0 = Some(value.to_string()),
+            "title" => entry.1 = value.to_string(),
+            _ => {
+                entry.2 = value.parse().ok().and_then(|s| Duration::try_from_secs_f64(s).ok());
+            }
+        }
+    }
+
+    let entries = entries
+        .into_values()
+        .filter_map(|(file, title, length)| {
+            Some((locator.resolve(&file?), EntryInfo::from_text(length, &title)))
+        })
+        .collect();
+    Ok(PlaylistFile { name: None, entries })
+}
+
+/// A version 2 PLS, with paths relative to `relative_to` where given.
+pub fn write(playlist: &PlaylistFile, relative_to: Option<&Path>) -> String {
+    let mut content = String::from("[playlist]\n");
+    for (i, (path, info)) in playlist.entries.iter().enumerate() {
+        let number = i + 1;
+        let seconds = info.duration.map(|d| d.as_secs_f64().round() as i64).unwrap_or(-1);
+        content.push_str(&format!("File{}={}\n", number, playlist::written_path(path, relative_to)));
+        // Left out rather than made up from the file name
+        if let Some(name) = info.display_name() {
+            content.push_str(&format!("Title{}={}\n", number, name));
+        }
+        content.push_str(&format!("Length{}={}\n", number, seconds));
+    }
+    content.push_str(&format!("NumberOfEntries={}\nVersion=2\n", playlist.entries.len()));
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn parses_numbered_entries_in_order() {
+        let dir = TempDir::new("pls-parse");
+        let path = dir.write(
+            "list.pls",
+            "[playlist]\n\
+             File2=/music/b.mp3\nTitle2=Only a title\nLength2=-1\n\
+             file1 = sub/a.mp3\nTITLE1=Artist - Song\nlength1=61.5\n\
+             Title3=No file\n\
+             File10=http://radio.example/stream\n\
+             NumberOfEntries=3\nVersion=2\n",
+        );
+        let pls = parse(&path).unwrap();
+        assert_eq!(pls.entries.len(), 3);
+
+        let (a, info) = &pls.entries[0];
+        assert_eq!(Path::new(a), dir.path().join("sub/a.mp3"));
+        assert_eq!(info.artist.as_deref(), Some("Artist"));
+        assert_eq!(info.title.as_deref(), Some("Song"));
+        assert_eq!(info.duration, Some(Duration::from_millis(61_500)));
+
+        let (b, info) = &pls.entries[1];
+        assert_eq!(b, "/music/b.mp3");
+        assert_eq!((info.artist.as_deref(), info.title.as_deref()), (None, Some("Only a title")));
+        assert_eq!(info.duration, None);
+
+        // URLs are not paths to resolve
+        assert_eq!(pls.entries[2].0, "http://radio.example/stream");
+        assert_eq!(pls.entries[2].1.title, None);
+    }
+
+    #[test]
+    fn ignores_lengths_out_of_range() {
+        let dir = TempDir::new("pls-lengths");
+        let path = dir.write(
+            "list.pls",
+            "[playlist]\nFile1=a.mp3\nLength1=inf\nFile2=b.mp3\nLength2=1e30\nFile3=c.mp3\nLength3=NaN\n",
+        );
+        let pls = parse(&path).unwrap();
+        assert_eq!(pls.entries.len(), 3);
+        assert!(pls.entries.iter().all(|(_, info)| info.duration.is_none()));
+    }
+
+    #[test]
+    fn writes_titles_only_where_known() {
+        let playlist = PlaylistFile {
+            name: None,
+            entries: vec![
+                (
+                    "/music/a.mp3".to_string(),
+                    EntryInfo::from_text(Some(Duration::from_secs(61)), "Artist - Song"),
+                ),
+                ("/music/01 - Song.mp3".to_string(), EntryInfo::default()),
+            ],
+        };
+        assert_eq!(
+            write(&playlist, None),
+            "[playlist]\nFile1=/music/a.mp3\nTitle1=Artist - Song\nLength1=61\n\
+             File2=/music/01 - Song.mp3\nLength2=-1\nNumberOfEntries=2\nVersion=2\n"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = TempDir::new("pls-round-trip");
+        let path = dir.write("list.pls", "");
+        let song = dir.path().join("sub/01 - Song.mp3").to_string_lossy().to_string();
+        let stream = "https://radio.example/live".to_string();
+        let playlist = PlaylistFile {
+            name: None,
+            entries: vec![
+                (song.clone(), EntryInfo::default()),
+                (stream.clone(), EntryInfo::from_text(None, "Radio")),
+            ],
+        };
+        let content = write(&playlist, Some(dir.path()));
+        assert!(content.contains("File1=sub/01 - Song.mp3\n"), "{}", content);
+        assert!(content.contains("File2=https://radio.example/live\n"), "{}", content);
+
+        fs::write(&path, content).unwrap();
+        let pls = parse(&path).unwrap();
+        assert_eq!(Path::new(&pls.entries[0].0), Path::new(&song));
+        assert_eq!((pls.entries[0].1.artist.as_deref(), pls.entries[0].1.title.as_deref()), (None, None));
+        assert_eq!(pls.entries[1].0, stream);
+        assert_eq!(pls.entries[1].1.title.as_deref(), Some("Radio"));
+    }
+}
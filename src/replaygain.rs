@@ -0,0 +1,421 @@
+use crate::browser::FileBrowser;
+use crate::cue;
+use crate::decoder::SymphoniaSource;
+use ebur128::{EbuR128, Mode};
+use lofty::config::WriteOptions;
+use lofty::prelude::{Accessor, ItemKey, TagExt};
+use lofty::{file::TaggedFileExt, probe::Probe, tag::Tag};
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+    /// Track gain while shuffling, album gain otherwise
+    Auto,
+}
+
+impl ReplayGainMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            ReplayGainMode::Off => "Off",
+            ReplayGainMode::Track => "Track",
+            ReplayGainMode::Album => "Album",
+            ReplayGainMode::Auto => "Auto (by shuffle)",
+        }
+    }
+
+    pub fn cycle(self, forward: bool) -> Self {
+        const ORDER: [ReplayGainMode; 4] = [
+            ReplayGainMode::Off,
+            ReplayGainMode::Track,
+            ReplayGainMode::Album,
+            ReplayGainMode::Auto,
+        ];
+        let i = ORDER.iter().position(|m| *m == self).unwrap_or(0);
+        let next = if forward { i + 1 } else { i + ORDER.len() - 1 };
+        ORDER[next % ORDER.len()]
+    }
+
+    /// Turns `Auto` into the concrete mode for the current shuffle state.
+    pub fn resolve(self, shuffle: bool) -> Self {
+        match self {
+            ReplayGainMode::Auto if shuffle => ReplayGainMode::Track,
+            ReplayGainMode::Auto => ReplayGainMode::Album,
+            mode => mode,
+        }
+    }
+}
+
+/// ReplayGain values read from a file's tags, in dB and linear peak.
+#[derive(Clone, Copy, Default)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    pub fn read(path: &str) -> Self {
+        // A CUE entry's own values come from the sheet; the file's tags
+        // describe the whole file, which for a single-file album is the album
+        if let Some((_, track)) = cue::lookup(path) {
+            let file = Self::read(&track.file);
+            return Self {
+                album_gain: track.replaygain.album_gain.or(file.album_gain).or(file.track_gain),
+                album_peak: track.replaygain.album_peak.or(file.album_peak).or(file.track_peak),
+                ..track.replaygain
+            };
+        }
+        let Ok(tagged_file) = Probe::open(path).and_then(|p| p.read()) else {
+            return Self::default();
+        };
+        let Some(tag) = tagged_file.primary_tag().or_else(|| tagged_file.first_tag()) else {
+            return Self::default();
+        };
+        Self {
+            track_gain: parse_value(tag, &ItemKey::ReplayGainTrackGain),
+            track_peak: parse_value(tag, &ItemKey::ReplayGainTrackPeak),
+            album_gain: parse_value(tag, &ItemKey::ReplayGainAlbumGain),
+            album_peak: parse_value(tag, &ItemKey::ReplayGainAlbumPeak),
+        }
+    }
+
+    /// Linear gain to apply for a resolved mode. Album values fall back to
+    /// track values when missing, and the gain is capped so the tagged peak
+    /// never goes past full scale.
+    pub fn factor(&self, mode: ReplayGainMode) -> f32 {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off | ReplayGainMode::Auto => return 1.0,
+            ReplayGainMode::Track => (self.track_gain, self.track_peak),
+            ReplayGainMode::Album => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+        };
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+
+        let factor = 10f32.powf(gain / 20.0);
+        match peak {
+            Some(peak) if peak > 0.0 && factor * peak > 1.0 => 1.0 / peak,
+            _ => factor,
+        }
+    }
+}
+
+/// Parses values like "-6.52 dB" or "0.988525".
+fn parse_value(tag: &Tag, key: &ItemKey) -> Option<f32> {
+    let text = tag.get_string(key)?;
+    let number = text.trim().trim_end_matches("dB").trim_end_matches("db").trim();
+    number.parse().ok()
+}
+
+/// ReplayGain 2.0 reference loudness, in LUFS.
+const REFERENCE_LUFS: f64 = -18.0;
+
+const USAGE: &str = "Usage: tap replaygain [--dry-run] [--by-album] [--jobs N] <dir>";
+
+/// Loudness measurement of one file, kept around for the album pass.
+struct TrackAnalysis {
+    path: PathBuf,
+    group: String,
+    meter: EbuR128,
+    loudness: f64,
+    peak: f64,
+}
+
+/// `tap replaygain`: measures EBU R128 loudness of every audio file under a
+/// directory and writes track and album ReplayGain tags. Albums are grouped
+/// by directory, or by album tag with `--by-album`.
+pub fn run_command(args: &[String]) -> Result<(), String> {
+    let mut dry_run = false;
+    let mut by_album = false;
+    let mut jobs = thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+    let mut dir = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" | "-n" => dry_run = true,
+            "--by-album" => by_album = true,
+            "--jobs" | "-j" => {
+                jobs = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| USAGE.to_string())?;
+            }
+            _ if dir.is_none() && !arg.starts_with('-') => dir = Some(PathBuf::from(arg)),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let dir = dir.ok_or_else(|| USAGE.to_string())?;
+    if !dir.is_dir() {
+        return Err(format!("Not a directory: {}", dir.display()));
+    }
+
+    let (sender, receiver) = channel();
+    FileBrowser::scan_audio_files_streaming(dir, sender, false);
+    let files: Vec<PathBuf> = receiver.iter().collect();
+    if files.is_empty() {
+        return Err("No audio files found".to_string());
+    }
+
+    let total = files.len();
+    println!("Analyzing {} files with {} threads...", total, jobs);
+    let mut analyses = analyze_files(files, jobs, by_album);
+    analyses.sort_by(|a, b| a.path.cmp(&b.path));
+
+    // Album loudness is measured over all of an album's audio at once
+    let mut albums: HashMap<&str, Vec<&TrackAnalysis>> = HashMap::new();
+    for analysis in &analyses {
+        albums.entry(analysis.group.as_str()).or_default().push(analysis);
+    }
+    let mut album_values: HashMap<&str, (f64, f64)> = HashMap::new();
+    for (group, tracks) in &albums {
+        let Ok(loudness) = EbuR128::loudness_global_multiple(tracks.iter().map(|t| &t.meter)) else {
+            continue;
+        };
+        let peak = tracks.iter().map(|t| t.peak).fold(0.0, f64::max);
+        album_values.insert(group, (REFERENCE_LUFS - loudness, peak));
+    }
+
+    if dry_run {
+        println!();
+        println!("{:<50} {:>11} {:>10} {:>11} {:>10}", "File", "Track gain", "Track peak", "Album gain", "Album peak");
+    }
+    let mut failed = 0;
+    for analysis in &analyses {
+        let track_gain = REFERENCE_LUFS - analysis.loudness;
+        let album = album_values.get(analysis.group.as_str()).copied();
+        if dry_run {
+            let (album_gain, album_peak) = match album {
+                Some((gain, peak)) => (format!("{:.2} dB", gain), format!("{:.6}", peak)),
+                None => ("--".to_string(), "--".to_string()),
+            };
+            println!(
+                "{:<50} {:>11} {:>10.6} {:>11} {:>10}",
+                display_name(&analysis.path),
+                format!("{:.2} dB", track_gain),
+                analysis.peak,
+                album_gain,
+                album_peak,
+            );
+        } else if let Err(e) = write_tags(&analysis.path, track_gain, analysis.peak, album) {
+            failed += 1;
+            println!("Failed to tag {}: {}", analysis.path.display(), e);
+        }
+    }
+
+    let skipped = total - analyses.len();
+    if dry_run {
+        println!("\nDry run: no files were modified.");
+    } else {
+        println!("Tagged {} of {} files.", analyses.len() - failed, total);
+    }
+    if skipped > 0 {
+        println!("{} files could not be decoded.", skipped);
+    }
+    Ok(())
+}
+
+/// Measures every file on `jobs` worker threads, printing progress as each
+/// one finishes. Files that fail to decode are reported and left out.
+fn analyze_files(files: Vec<PathBuf>, jobs: usize, by_album: bool) -> Vec<TrackAnalysis> {
+    let total = files.len();
+    let queue = Arc::new(Mutex::new(files));
+    let (sender, receiver) = channel();
+
+    for _ in 0..jobs.min(total) {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        thread::spawn(move || loop {
+            let Some(path) = queue.lock().unwrap().pop() else {
+                break;
+            };
+            let result = analyze_file(&path, by_album);
+            if sender.send((path, result)).is_err() {
+                break;
+            }
+        });
+    }
+    drop(sender);
+
+    let mut analyses = Vec::with_capacity(total);
+    for (done, (path, result)) in receiver.iter().enumerate() {
+        match result {
+            Ok(analysis) => {
+                println!(
+                    "[{}/{}] {:>7.2} LUFS  {}",
+                    done + 1,
+                    total,
+                    analysis.loudness,
+                    path.display()
+                );
+                analyses.push(analysis);
+            }
+            Err(e) => println!("[{}/{}] error: {}: {}", done + 1, total, path.display(), e),
+        }
+    }
+    analyses
+}
+
+fn analyze_file(path: &Path, by_album: bool) -> Result<TrackAnalysis, String> {
+    let path_str = path.to_string_lossy();
+    let mut source = SymphoniaSource::open(&path_str)?;
+    let channels = source.channels();
+    let mut meter = EbuR128::new(
+        channels as u32,
+        source.sample_rate(),
+        Mode::I | Mode::SAMPLE_PEAK | Mode::HISTOGRAM,
+    )
+    .map_err(|e| format!("{:?}", e))?;
+
+    let chunk_len = 4096 * channels as usize;
+    let mut chunk = Vec::with_capacity(chunk_len);
+    loop {
+        chunk.clear();
+        chunk.extend(source.by_ref().take(chunk_len));
+        // Only whole frames can be fed to the meter
+        chunk.truncate(chunk.len() - chunk.len() % channels.max(1) as usize);
+        if chunk.is_empty() {
+            break;
+        }
+        meter.add_frames_f32(&chunk).map_err(|e| format!("{:?}", e))?;
+    }
+
+    let loudness = meter.loudness_global().map_err(|e| format!("{:?}", e))?;
+    if !loudness.is_finite() {
+        return Err("file is silent".to_string());
+    }
+    let peak = (0..channels as u32)
+        .filter_map(|c| meter.sample_peak(c).ok())
+        .fold(0.0, f64::max);
+
+    let directory = path.parent().unwrap_or(Path::new(".")).to_string_lossy().to_string();
+    let group = if by_album {
+        read_album(path).unwrap_or(directory)
+    } else {
+        directory
+    };
+
+    Ok(TrackAnalysis {
+        path: path.to_path_buf(),
+        group,
+        meter,
+        loudness,
+        peak,
+    })
+}
+
+fn read_album(path: &Path) -> Option<String> {
+    let tagged_file = Probe::open(path).and_then(|p| p.read()).ok()?;
+    let tag = tagged_file.primary_tag().or_else(|| tagged_file.first_tag())?;
+    let album = tag.album()?.to_string();
+    let artist = tag.get_string(&ItemKey::AlbumArtist).or(tag.artist().as_deref()).map(str::to_string);
+    Some(match artist {
+        Some(artist) => format!("{} - {}", artist, album),
+        None => album,
+    })
+}
+
+fn write_tags(path: &Path, track_gain: f64, track_peak: f64, album: Option<(f64, f64)>) -> Result<(), String> {
+    let mut tagged_file = Probe::open(path)
+        .and_then(|p| p.read())
+        .map_err(|e| e.to_string())?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file.primary_tag_mut().ok_or("no writable tag")?;
+
+    let mut supported = tag.insert_text(ItemKey::ReplayGainTrackGain, format!("{:.2} dB", track_gain));
+    supported &= tag.insert_text(ItemKey::ReplayGainTrackPeak, format!("{:.6}", track_peak));
+    if let Some((album_gain, album_peak)) = album {
+        supported &= tag.insert_text(ItemKey::ReplayGainAlbumGain, format!("{:.2} dB", album_gain));
+        supported &= tag.insert_text(ItemKey::ReplayGainAlbumPeak, format!("{:.6}", album_peak));
+    }
+    if !supported {
+        return Err("tag format does not support ReplayGain".to_string());
+    }
+    tag.save_to_path(path, WriteOptions::default()).map_err(|e| e.to_string())
+}
+
+/// Shortens long paths from the left so the table stays aligned.
+fn display_name(path: &Path) -> String {
+    let name = path.to_string_lossy();
+    let chars: Vec<char> = name.chars().collect();
+    if chars.len() <= 50 {
+        name.to_string()
+    } else {
+        format!("…{}", chars[chars.len() - 49..].iter().collect::<String>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gains(track: (f32, f32), album: Option<(f32, f32)>) -> ReplayGain {
+        ReplayGain {
+            track_gain: Some(track.0),
+            track_peak: Some(track.1),
+            album_gain: album.map(|a| a.0),
+            album_peak: album.map(|a| a.1),
+        }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn off_and_untagged_leave_the_level_alone() {
+        let tagged = gains((-6.0, 0.5), Some((-8.0, 0.6)));
+        assert_eq!(tagged.factor(ReplayGainMode::Off), 1.0);
+        assert_eq!(tagged.factor(ReplayGainMode::Auto), 1.0);
+        assert_eq!(ReplayGain::default().factor(ReplayGainMode::Track), 1.0);
+        assert_eq!(ReplayGain::default().factor(ReplayGainMode::Album), 1.0);
+    }
+
+    #[test]
+    fn gain_turns_into_a_linear_factor() {
+        let tagged = gains((-6.0, 0.5), Some((-20.0, 0.6)));
+        assert!(close(tagged.factor(ReplayGainMode::Track), 0.501_187));
+        assert!(close(tagged.factor(ReplayGainMode::Album), 0.1));
+        // Album mode falls back to the track values
+        let track_only = gains((-6.0, 0.5), None);
+        assert!(close(track_only.factor(ReplayGainMode::Album), 0.501_187));
+    }
+
+    #[test]
+    fn peak_caps_the_gain_at_full_scale() {
+        // +6 dB would take a 0.8 peak to about 1.6
+        let loud = gains((6.0, 0.8), Some((6.0, 0.25)));
+        assert!(close(loud.factor(ReplayGainMode::Track), 1.25));
+        // With room to spare the gain applies in full
+        assert!(close(loud.factor(ReplayGainMode::Album), 1.995_262));
+        // A missing or zero peak cannot cap anything
+        let no_peak = ReplayGain { track_gain: Some(6.0), ..Default::default() };
+        assert!(close(no_peak.factor(ReplayGainMode::Track), 1.995_262));
+        let zero_peak = gains((6.0, 0.0), None);
+        assert!(close(zero_peak.factor(ReplayGainMode::Track), 1.995_262));
+    }
+
+    #[test]
+    fn auto_follows_the_shuffle_state() {
+        assert!(ReplayGainMode::Auto.resolve(true) == ReplayGainMode::Track);
+        assert!(ReplayGainMode::Auto.resolve(false) == ReplayGainMode::Album);
+        assert!(ReplayGainMode::Track.resolve(false) == ReplayGainMode::Track);
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+
+/// The volume fades out over this long before the timer pauses playback.
+pub const FADE: Duration = Duration::from_secs(30);
+
+const MINUTE_STEPS: [u32; 6] = [15, 30, 45, 60, 90, 120];
+const TRACK_STEPS: [u32; 5] = [1, 2, 3, 5, 10];
+
+#[derive(Clone, Copy)]
+pub enum SleepTimer {
+    /// Pause at a set time, `minutes` after the timer was set.
+    At { ends: Instant, minutes: u32 },
+    /// Pause once `left` more tracks, counting the playing one, have finished.
+    Tracks { left: u32, total: u32 },
+}
+
+impl SleepTimer {
+    /// The next longer time setting, starting over from now; `None` (off)
+    /// after the longest.
+    pub fn cycle_minutes(current: Option<SleepTimer>) -> Option<SleepTimer> {
+        let after = match current {
+            Some(SleepTimer::At { minutes, .. }) => minutes,
+            _ => 0,
+        };
+        let minutes = MINUTE_STEPS.into_iter().find(|m| *m > after)?;
+        Some(SleepTimer::At {
+            ends: Instant::now() + Duration::from_secs(minutes as u64 * 60),
+            minutes,
+        })
+    }
+
+    /// The next larger track count; `None` (off) after the largest.
+    pub fn cycle_tracks(current: Option<SleepTimer>) -> Option<SleepTimer> {
+        let after = match current {
+            Some(SleepTimer::Tracks { total, .. }) => total,
+            _ => 0,
+        };
+        let total = TRACK_STEPS.into_iter().find(|t| *t > after)?;
+        Some(SleepTimer::Tracks { left: total, total })
+    }
+
+    /// "Sleep 12:34" or "Sleep: 2 tracks".
+    pub fn label(&self) -> String {
+        match self {
+            SleepTimer::At { ends, .. } => {
+                let secs = ends.saturating_duration_since(Instant::now()).as_secs();
+                format!("Sleep {:02}:{:02}", secs / 60, secs % 60)
+            }
+            SleepTimer::Tracks { left: 1, .. } => "Sleep: this track".to_string(),
+            SleepTimer::Tracks { left, .. } => format!("Sleep: {} tracks", left),
+        }
+    }
+}
+
+/// Volume factor with `remaining` until the timer runs out.
+pub fn fade_factor(remaining: Duration) -> f32 {
+    (remaining.as_secs_f32() / FADE.as_secs_f32()).min(1.0)
+}
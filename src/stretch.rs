@@ -0,0 +1,364 @@
+use rodio::Source;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+
+/// Length of each WSOLA segment, in seconds. Consecutive segments overlap
+/// by half.
+const SEGMENT_SECS: f64 = 0.03;
+/// How far either side of its nominal position a segment may be moved to
+/// line its waveform up with the previous one.
+const TOLERANCE_SECS: f64 = 0.012;
+
+/// Changes the playback speed of one track. Without pitch preservation this
+/// is plain resampling (faster is higher); with it, WSOLA time-stretching
+/// keeps the pitch and repeats or drops short segments instead.
+///
+/// At 1.0x with nothing buffered, samples are passed straight through.
+pub struct Stretcher {
+    channels: usize,
+    sample_rate: u32,
+    /// Interleaved input frames not yet fully consumed.
+    input: Vec<f32>,
+    /// Read position in `input`, in frames.
+    pos: f64,
+    /// Output samples waiting to be handed out.
+    ready: Vec<f32>,
+    ready_pos: usize,
+    wsola: Option<Wsola>,
+    source_done: bool,
+}
+
+/// Overlap-add state for pitch-preserving stretching.
+struct Wsola {
+    segment: usize,
+    hop: usize,
+    tolerance: usize,
+    window: Vec<f32>,
+    /// Overlap-add accumulator, `segment` frames long.
+    accum: Vec<f32>,
+    /// Start of the last segment taken from `input`.
+    prev: Option<usize>,
+}
+
+impl Wsola {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let segment = ((sample_rate as f64 * SEGMENT_SECS) as usize).max(64) & !1;
+        // Periodic Hann windows at 50% overlap sum to exactly one
+        let window = (0..segment)
+            .map(|i| {
+                let phase = 2.0 * std::f64::consts::PI * i as f64 / segment as f64;
+                (0.5 - 0.5 * phase.cos()) as f32
+            })
+            .collect();
+        Self {
+            segment,
+            hop: segment / 2,
+            tolerance: (sample_rate as f64 * TOLERANCE_SECS) as usize,
+            window,
+            accum: vec![0.0; segment * channels],
+            prev: None,
+        }
+    }
+}
+
+impl Default for Stretcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stretcher {
+    pub fn new() -> Self {
+        Self {
+            channels: 0,
+            sample_rate: 0,
+            input: Vec::new(),
+            pos: 0.0,
+            ready: Vec::new(),
+            ready_pos: 0,
+            wsola: None,
+            source_done: false,
+        }
+    }
+
+    /// Forgets everything buffered, after a seek or format change.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Samples that are certain to come out before the stretcher next reads
+    /// its source, or `None` when it is passing samples straight through.
+    pub fn frame_len(&self) -> Option<usize> {
+        let ready = self.ready.len() - self.ready_pos;
+        if ready > 0 {
+            Some(ready)
+        } else if !self.input.is_empty() {
+            Some(self.channels.max(1))
+        } else {
+            None
+        }
+    }
+
+    /// Channel count and sample rate of what is buffered, while anything
+    /// is. The source may have moved on to another format by then.
+    pub fn format(&self) -> Option<(u16, u32)> {
+        self.frame_len().map(|_| (self.channels as u16, self.sample_rate))
+    }
+
+    pub fn next<S: Source<Item = f32>>(&mut self, source: &mut S, speed: f32, keep_pitch: bool) -> Option<f32> {
+        if self.ready_pos < self.ready.len() {
+            let sample = self.ready[self.ready_pos];
+            self.ready_pos += 1;
+            return Some(sample);
+        }
+
+        let unity = (speed - 1.0).abs() < 0.001;
+        if unity && self.input.is_empty() {
+            self.wsola = None;
+            self.pos = 0.0;
+            return source.next();
+        }
+        if self.input.is_empty() {
+            self.channels = source.channels().max(1) as usize;
+            self.sample_rate = source.sample_rate();
+        }
+
+        self.ready.clear();
+        self.ready_pos = 0;
+        if unity {
+            self.wsola = None;
+            self.pass_frame(source);
+        } else if keep_pitch {
+            if self.wsola.is_none() {
+                self.wsola = Some(Wsola::new(self.channels, source.sample_rate()));
+            }
+            self.stretch_hop(source, speed as f64);
+        } else {
+            self.wsola = None;
+            self.resample_frame(source, speed as f64);
+        }
+
+        if self.ready.is_empty() {
+            return None;
+        }
+        self.ready_pos = 1;
+        Some(self.ready[0])
+    }
+
+    fn frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    /// Reads from the source until `input` holds at least `frames` frames.
+    fn fill<S: Source<Item = f32>>(&mut self, source: &mut S, frames: usize) {
+        let wanted = frames * self.channels;
+        while !self.source_done && self.input.len() < wanted {
+            match source.next() {
+                Some(sample) => self.input.push(sample),
+                None => self.source_done = true,
+            }
+        }
+        // Never leave half a frame behind
+        let whole = self.frames() * self.channels;
+        self.input.truncate(whole);
+    }
+
+    /// Drops input frames before `frame`, shifting positions to match.
+    fn discard(&mut self, frame: usize) {
+        let frame = frame.min(self.frames());
+        if frame == 0 {
+            return;
+        }
+        self.input.drain(..frame * self.channels);
+        self.pos -= frame as f64;
+        if let Some(prev) = self.wsola.as_mut().and_then(|w| w.prev.as_mut()) {
+            *prev = prev.saturating_sub(frame);
+        }
+    }
+
+    /// Drains leftover input one frame at a time after returning to 1.0x.
+    fn pass_frame<S: Source<Item = f32>>(&mut self, source: &mut S) {
+        let start = self.pos.round() as usize;
+        self.discard(start);
+        self.pos = 0.0;
+        self.fill(source, 1);
+        if self.frames() == 0 {
+            return;
+        }
+        self.ready.extend(self.input.drain(..self.channels));
+    }
+
+    /// Linear-interpolating varispeed: one output frame per call.
+    fn resample_frame<S: Source<Item = f32>>(&mut self, source: &mut S, speed: f64) {
+        let index = self.pos as usize;
+        self.discard(index);
+        self.fill(source, 2);
+        if self.frames() == 0 {
+            return;
+        }
+        let t = self.pos.fract() as f32;
+        let next = if self.frames() > 1 { self.channels } else { 0 };
+        for c in 0..self.channels {
+            let a = self.input[c];
+            let b = self.input[next + c];
+            self.ready.push(a + (b - a) * t);
+        }
+        self.pos += speed;
+    }
+
+    /// Produces one hop of output by overlap-adding the best-matching segment
+    /// near the nominal read position.
+    fn stretch_hop<S: Source<Item = f32>>(&mut self, source: &mut S, speed: f64) {
+        let channels = self.channels;
+        let Some(wsola) = self.wsola.as_ref() else {
+            return;
+        };
+        let (segment, hop, tolerance) = (wsola.segment, wsola.hop, wsola.tolerance);
+
+        // Keep enough history for the search window and the previous segment,
+        // whose second half is what the next one has to line up with
+        let keep_from = (self.pos as usize).saturating_sub(tolerance);
+        let keep_from = match wsola.prev {
+            Some(prev) => keep_from.min(prev),
+            None => keep_from,
+        };
+        self.discard(keep_from);
+        let prev = self.wsola.as_ref().and_then(|w| w.prev);
+
+        let nominal = self.pos.round() as usize;
+        self.fill(source, nominal + tolerance + segment);
+        let available = self.frames();
+        if nominal >= available {
+            // Out of input: let the last overlap ring out once
+            let wsola = self.wsola.as_mut().unwrap();
+            if wsola.prev.take().is_some() {
+                self.ready.extend_from_slice(&wsola.accum[..hop * channels]);
+            }
+            return;
+        }
+
+        let start = match prev {
+            Some(prev) if available >= segment => {
+                self.best_match(prev + hop, nominal, tolerance, hop, available - segment)
+            }
+            _ => nominal.min(available.saturating_sub(1)),
+        };
+
+        let wsola = self.wsola.as_mut().unwrap();
+        for i in 0..segment {
+            let frame = start + i;
+            if frame >= available {
+                break;
+            }
+            let w = wsola.window[i];
+            for c in 0..channels {
+                wsola.accum[i * channels + c] += self.input[frame * channels + c] * w;
+            }
+        }
+        self.ready.extend_from_slice(&wsola.accum[..hop * channels]);
+        wsola.accum.copy_within(hop * channels.., 0);
+        let tail = wsola.accum.len() - hop * channels;
+        wsola.accum[tail..].fill(0.0);
+        wsola.prev = Some(start);
+        self.pos += hop as f64 * speed;
+    }
+
+    /// Finds the segment start within `tolerance` of `nominal` whose opening
+    /// best correlates with the frames at `target`. Only every other start
+    /// and every fourth sample are tried, which is plenty for picking a
+    /// splice point and keeps the search cheap.
+    fn best_match(&self, target: usize, nominal: usize, tolerance: usize, length: usize, last: usize) -> usize {
+        let channels = self.channels;
+        let mono = |frame: usize| -> f32 {
+            let base = frame * channels;
+            self.input[base..base + channels].iter().sum()
+        };
+        if target + length > self.frames() {
+            return nominal.min(last);
+        }
+
+        let low = nominal.saturating_sub(tolerance);
+        let high = (nominal + tolerance).min(last);
+        let mut best = nominal.min(last);
+        let mut best_score = f32::MIN;
+        for candidate in (low..=high).step_by(2) {
+            let mut corr = 0.0;
+            let mut energy = 1e-9;
+            for k in (0..length).step_by(4) {
+                let x = mono(candidate + k);
+                corr += x * mono(target + k);
+                energy += x * x;
+            }
+            let score = corr / energy.sqrt();
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const RATE: u32 = 44100;
+
+    /// A second of a stereo sine at `freq`.
+    fn sine(freq: f32) -> SamplesBuffer<f32> {
+        let samples = (0..RATE)
+            .flat_map(|i| {
+                let sample = (i as f32 / RATE as f32 * freq * std::f32::consts::TAU).sin() * 0.5;
+                [sample, sample]
+            })
+            .collect::<Vec<f32>>();
+        SamplesBuffer::new(2, RATE, samples)
+    }
+
+    /// Everything the stretcher makes of a second of a 440 Hz sine.
+    fn stretch(speed: f32, keep_pitch: bool) -> Vec<f32> {
+        let mut source = sine(440.0);
+        let mut stretcher = Stretcher::new();
+        std::iter::from_fn(|| stretcher.next(&mut source, speed, keep_pitch)).collect()
+    }
+
+    /// Length in seconds and, from the left channel's zero crossings,
+    /// roughly the frequency.
+    fn measure(samples: &[f32]) -> (f32, f32) {
+        assert_eq!(samples.len() % 2, 0, "half a frame");
+        let left: Vec<f32> = samples.iter().step_by(2).copied().collect();
+        let seconds = left.len() as f32 / RATE as f32;
+        let crossings = left.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count();
+        (seconds, crossings as f32 / 2.0 / seconds)
+    }
+
+    #[test]
+    fn unity_passes_samples_straight_through() {
+        let mut stretcher = Stretcher::new();
+        let mut source = sine(440.0);
+        let output: Vec<f32> = std::iter::from_fn(|| stretcher.next(&mut source, 1.0, true)).collect();
+        assert!(output.into_iter().eq(sine(440.0)));
+        assert!(stretcher.frame_len().is_none());
+    }
+
+    #[test]
+    fn stretching_changes_the_length_but_not_the_pitch() {
+        for (speed, expected) in [(0.5, 2.0), (2.0, 0.5)] {
+            let (length, pitch) = measure(&stretch(speed, true));
+            assert!((length - expected).abs() < 0.03, "{}s at {}x", length, speed);
+            assert!((pitch - 440.0).abs() < 10.0, "{} Hz at {}x", pitch, speed);
+        }
+    }
+
+    #[test]
+    fn varispeed_changes_both() {
+        for (speed, expected) in [(0.5, 2.0), (2.0, 0.5)] {
+            let (length, pitch) = measure(&stretch(speed, false));
+            assert!((length - expected).abs() < 0.01, "{}s at {}x", length, speed);
+            assert!((pitch - 440.0 * speed).abs() < 5.0, "{} Hz at {}x", pitch, speed);
+        }
+    }
+}
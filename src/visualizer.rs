@@ -0,0 +1,267 @@
+use realfft::RealFftPlanner;
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Samples kept for analysis, which is also the FFT size.
+const WINDOW: usize = 2048;
+/// Mono samples the audio thread collects before handing them over.
+const BLOCK: usize = 512;
+/// How often the analyzer runs and the pane is redrawn while visible.
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+const LOWEST_HZ: f32 = 30.0;
+const HIGHEST_HZ: f32 = 16000.0;
+/// Levels below this many dB show as empty bars.
+const FLOOR_DB: f32 = -70.0;
+/// How far a bar may drop per frame, as a fraction of full height.
+const FALLOFF: f32 = 0.04;
+
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum VisualizerMode {
+    #[default]
+    Spectrum,
+    Oscilloscope,
+}
+
+impl VisualizerMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            VisualizerMode::Spectrum => "Spectrum",
+            VisualizerMode::Oscilloscope => "Oscilloscope",
+        }
+    }
+
+    pub fn cycle(self) -> Self {
+        match self {
+            VisualizerMode::Spectrum => VisualizerMode::Oscilloscope,
+            VisualizerMode::Oscilloscope => VisualizerMode::Spectrum,
+        }
+    }
+}
+
+/// The most recent output, mixed to mono, shared between the audio thread
+/// and the analyzer. Nothing is collected while `enabled` is off.
+#[derive(Default)]
+pub struct SampleTap {
+    enabled: AtomicBool,
+    samples: Mutex<VecDeque<f32>>,
+    sample_rate: AtomicU32,
+    /// Bumped on every push, so the analyzer can tell when playback stops.
+    pushes: AtomicU64,
+}
+
+impl SampleTap {
+    fn push(&self, block: &[f32], sample_rate: u32) {
+        // Never make the audio thread wait; a dropped block only costs a frame
+        let Ok(mut samples) = self.samples.try_lock() else {
+            return;
+        };
+        for &sample in block {
+            if samples.len() == WINDOW {
+                samples.pop_front();
+            }
+            samples.push_back(sample);
+        }
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+        self.pushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Vec<f32> {
+        self.samples.lock().unwrap().iter().copied().collect()
+    }
+}
+
+/// Pass-through source that feeds what is played into a `SampleTap`.
+pub struct Tap<S> {
+    inner: S,
+    tap: Arc<SampleTap>,
+    active: bool,
+    /// Samples until `active` is next looked at.
+    countdown: usize,
+    channel: u16,
+    mix: f32,
+    pending: Vec<f32>,
+}
+
+impl<S: Source<Item = f32>> Tap<S> {
+    pub fn new(inner: S, tap: Arc<SampleTap>) -> Self {
+        Tap {
+            inner,
+            tap,
+            active: false,
+            countdown: 0,
+            channel: 0,
+            mix: 0.0,
+            pending: Vec::with_capacity(BLOCK),
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Tap<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.inner.next()?;
+        let channels = self.inner.channels().max(1);
+        if self.countdown == 0 {
+            self.active = self.tap.enabled.load(Ordering::Relaxed);
+            self.countdown = BLOCK * channels as usize;
+        }
+        self.countdown -= 1;
+
+        if self.active {
+            self.mix += sample;
+        }
+        self.channel += 1;
+        if self.channel >= channels {
+            if self.active {
+                self.pending.push(self.mix / channels as f32);
+                if self.pending.len() == BLOCK {
+                    self.tap.push(&self.pending, self.inner.sample_rate());
+                    self.pending.clear();
+                }
+            }
+            self.channel = 0;
+            self.mix = 0.0;
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Tap<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// Band levels worked out by the analyzer thread, 0.0 to 1.0.
+#[derive(Default)]
+struct Spectrum {
+    bands: AtomicUsize,
+    levels: Mutex<Vec<f32>>,
+}
+
+/// Runs an FFT over the tapped samples on its own thread while the pane is
+/// shown. The thread sleeps until `set_enabled(true)`.
+pub struct Visualizer {
+    tap: Arc<SampleTap>,
+    spectrum: Arc<Spectrum>,
+    thread: thread::Thread,
+}
+
+impl Visualizer {
+    pub fn new(tap: Arc<SampleTap>) -> Self {
+        let spectrum = Arc::new(Spectrum::default());
+        let handle = {
+            let tap = tap.clone();
+            let spectrum = spectrum.clone();
+            thread::spawn(move || analyze(&tap, &spectrum))
+        };
+        Visualizer { tap, spectrum, thread: handle.thread().clone() }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.tap.enabled.store(enabled, Ordering::Relaxed);
+        if enabled {
+            self.thread.unpark();
+        }
+    }
+
+    /// Levels for `bands` log-spaced bands from 30 Hz to 16 kHz. The count
+    /// applies from the next analyzer frame on.
+    pub fn spectrum(&self, bands: usize) -> Vec<f32> {
+        self.spectrum.bands.store(bands, Ordering::Relaxed);
+        let mut levels = self.spectrum.levels.lock().unwrap().clone();
+        levels.resize(bands, 0.0);
+        levels
+    }
+
+    /// The latest samples, starting at a rising zero crossing where there
+    /// is one so that steady tones stand still.
+    pub fn waveform(&self) -> Vec<f32> {
+        let samples = self.tap.snapshot();
+        let half = samples.len() / 2;
+        let start = (1..half)
+            .find(|&i| samples[i - 1] < 0.0 && samples[i] >= 0.0)
+            .unwrap_or(0);
+        samples[start..start + half].to_vec()
+    }
+}
+
+fn analyze(tap: &SampleTap, spectrum: &Spectrum) {
+    let fft = RealFftPlanner::<f32>::new().plan_fft_forward(WINDOW);
+    let window: Vec<f32> = (0..WINDOW)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / WINDOW as f32).cos())
+        .collect();
+    // A full-scale sine shows as 0 dB
+    let scale = 2.0 / window.iter().sum::<f32>();
+    let mut input = fft.make_input_vec();
+    let mut output = fft.make_output_vec();
+    let mut last_push = 0;
+
+    loop {
+        if !tap.enabled.load(Ordering::Relaxed) {
+            thread::park();
+            continue;
+        }
+
+        let bands = spectrum.bands.load(Ordering::Relaxed);
+        let pushes = tap.pushes.load(Ordering::Relaxed);
+        let mut target = vec![0.0; bands];
+        // With nothing new (paused or stopped) the bars fall back to zero
+        if pushes != last_push {
+            last_push = pushes;
+            let samples = tap.snapshot();
+            input.fill(0.0);
+            let offset = WINDOW - samples.len();
+            for (i, sample) in samples.iter().enumerate() {
+                input[offset + i] = sample * window[offset + i];
+            }
+            if fft.process(&mut input, &mut output).is_ok() {
+                let sample_rate = tap.sample_rate.load(Ordering::Relaxed).max(1) as f32;
+                let bin_hz = sample_rate / WINDOW as f32;
+                let highest = HIGHEST_HZ.min(sample_rate / 2.0);
+                let ratio = (highest / LOWEST_HZ).powf(1.0 / bands.max(1) as f32);
+                for (band, level) in target.iter_mut().enumerate() {
+                    let low = LOWEST_HZ * ratio.powi(band as i32);
+                    let high = low * ratio;
+                    // Low bands can be narrower than a bin; use the nearest one
+                    let first = (low / bin_hz).round() as usize;
+                    let last = ((high / bin_hz).round() as usize).max(first + 1).min(output.len());
+                    let magnitude = output[first.min(output.len() - 1)..last]
+                        .iter()
+                        .map(|c| c.norm())
+                        .fold(0.0, f32::max);
+                    let db = 20.0 * (magnitude * scale).max(1e-9).log10();
+                    *level = (1.0 - db / FLOOR_DB).clamp(0.0, 1.0);
+                }
+            }
+        }
+
+        {
+            let mut levels = spectrum.levels.lock().unwrap();
+            levels.resize(bands, 0.0);
+            for (level, new) in levels.iter_mut().zip(target) {
+                *level = new.max(*level - FALLOFF);
+            }
+        }
+        thread::sleep(FRAME_INTERVAL);
+    }
+}
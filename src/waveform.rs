@@ -0,0 +1,192 @@
+use crate::cue;
+use crate::decoder::SymphoniaSource;
+use rodio::Source;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Columns in a stored overview; the seek bar resamples to its own width.
+const POINTS: usize = 1024;
+/// Frames per peak while decoding, before resampling to `POINTS`.
+const BLOCK_FRAMES: usize = 1024;
+/// How much of each end of a file goes into its cache key.
+const HASH_SPAN: u64 = 1 << 20;
+
+/// A finished overview and the track it belongs to.
+type Ready = Mutex<Option<(String, Arc<Vec<f32>>)>>;
+
+/// Peak level overviews of whole tracks for the seek bar, worked out on a
+/// background thread and cached on disk. Only the track last asked for is
+/// worked on; skipping away abandons it.
+pub struct WaveformLoader {
+    wanted: Arc<Mutex<Option<String>>>,
+    ready: Arc<Ready>,
+    sender: Sender<String>,
+}
+
+impl WaveformLoader {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        let wanted = Arc::new(Mutex::new(None));
+        let ready = Arc::new(Mutex::new(None));
+        {
+            let wanted = wanted.clone();
+            let ready = ready.clone();
+            thread::spawn(move || work(receiver, &wanted, &ready));
+        }
+        WaveformLoader { wanted, ready, sender }
+    }
+
+    /// The overview of `path`, peaks from 0.0 to 1.0, or `None` while it is
+    /// still being worked out (or can't be).
+    pub fn get(&self, path: &str) -> Option<Arc<Vec<f32>>> {
+        if let Some((ready_path, peaks)) = &*self.ready.lock().unwrap() {
+            if ready_path == path {
+                return Some(peaks.clone());
+            }
+        }
+        let mut wanted = self.wanted.lock().unwrap();
+        if wanted.as_deref() != Some(path) {
+            *wanted = Some(path.to_string());
+            self.sender.send(path.to_string()).ok();
+        }
+        None
+    }
+}
+
+fn work(receiver: Receiver<String>, wanted: &Mutex<Option<String>>, ready: &Ready) {
+    while let Ok(mut path) = receiver.recv() {
+        // Only the latest request matters
+        while let Ok(newer) = receiver.try_recv() {
+            path = newer;
+        }
+        let still_wanted = || wanted.lock().unwrap().as_deref() == Some(path.as_str());
+        let Some(key) = cache_key(&path) else {
+            continue;
+        };
+        let peaks = match load_cached(&key) {
+            Some(peaks) => peaks,
+            None => match compute(&path, &still_wanted) {
+                Some(peaks) => {
+                    save_cached(&key, &peaks);
+                    peaks
+                }
+                None => continue,
+            },
+        };
+        if still_wanted() {
+            *ready.lock().unwrap() = Some((path, Arc::new(peaks)));
+        }
+    }
+}
+
+/// Decodes the whole track, giving up early if it stops being wanted.
+fn compute(path: &str, still_wanted: &dyn Fn() -> bool) -> Option<Vec<f32>> {
+    let mut source = SymphoniaSource::open(path).ok()?;
+    let block_len = BLOCK_FRAMES * source.channels().max(1) as usize;
+    let mut blocks = Vec::new();
+    loop {
+        if blocks.len() % 256 == 0 && !still_wanted() {
+            return None;
+        }
+        let mut count = 0;
+        let peak = source
+            .by_ref()
+            .take(block_len)
+            .inspect(|_| count += 1)
+            .fold(0.0f32, |peak, s| peak.max(s.abs()));
+        if count == 0 {
+            break;
+        }
+        blocks.push(peak);
+    }
+    if blocks.is_empty() {
+        return None;
+    }
+
+    let mut peaks: Vec<f32> = (0..POINTS)
+        .map(|i| {
+            let first = i * blocks.len() / POINTS;
+            let last = ((i + 1) * blocks.len() / POINTS).max(first + 1).min(blocks.len());
+            blocks[first..last].iter().copied().fold(0.0, f32::max)
+        })
+        .collect();
+    // Scale to the loudest point so quiet recordings still show their shape
+    let loudest = peaks.iter().copied().fold(0.0, f32::max);
+    if loudest > 0.0 {
+        peaks.iter_mut().for_each(|p| *p /= loudest);
+    }
+    Some(peaks)
+}
+
+/// A hash of the file's size and its first and last megabyte, which is
+/// quick even for long audiobooks and still finds a renamed file. CUE
+/// entries add their range within the file.
+fn cache_key(path: &str) -> Option<String> {
+    let (file, range) = match cue::lookup(path) {
+        Some((_, track)) => {
+            let end = track.end.map(|e| e.as_millis()).unwrap_or(0);
+            (track.file, format!("-{}-{}", track.start.as_millis(), end))
+        }
+        None => (path.to_string(), String::new()),
+    };
+    let mut file = File::open(file).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mut hash = Fnv::new();
+    hash.write(&size.to_le_bytes());
+
+    let mut buffer = Vec::new();
+    file.by_ref().take(HASH_SPAN).read_to_end(&mut buffer).ok()?;
+    if size > HASH_SPAN {
+        file.seek(SeekFrom::Start(size.saturating_sub(HASH_SPAN).max(HASH_SPAN))).ok()?;
+        file.read_to_end(&mut buffer).ok()?;
+    }
+    hash.write(&buffer);
+    Some(format!("{:016x}{}", hash.0, range))
+}
+
+fn cache_path(key: &str) -> PathBuf {
+    let mut path = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push("rustplayer");
+    path.push("waveforms");
+    path.push(format!("{}.json", key));
+    path
+}
+
+fn load_cached(key: &str) -> Option<Vec<f32>> {
+    let content = fs::read_to_string(cache_path(key)).ok()?;
+    let levels: Vec<u8> = serde_json::from_str(&content).ok()?;
+    Some(levels.into_iter().map(|l| l as f32 / 255.0).collect())
+}
+
+/// Stored as bytes; the seek bar only has eight heights anyway.
+fn save_cached(key: &str, peaks: &[f32]) {
+    let levels: Vec<u8> = peaks.iter().map(|p| (p.clamp(0.0, 1.0) * 255.0).round() as u8).collect();
+    if let Ok(content) = serde_json::to_string(&levels) {
+        let path = cache_path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).ok();
+        }
+        fs::write(path, content).ok();
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the std hasher is the same from one build
+/// to the next.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
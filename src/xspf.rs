@@ -0,0 +1,307 @@
+use crate::playlist::{self, EntryInfo, Locator, PlaylistFile};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+pub fn is_xspf_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("xspf"))
+}
+
+/// Reads an XSPF playlist: each `<track>`'s location, title, creator,
+/// album and duration (in milliseconds), and the playlist's own title.
+/// Only as much XML as XSPF needs is understood.
+pub fn parse(path: &str) -> Result<PlaylistFile, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read XSPF: {}", e))?;
+    let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&bytes);
+    let content = String::from_utf8_lossy(bytes);
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    let mut locator = Locator::new(base_dir);
+
+    // The playlist title comes before the track list, track titles in it
+    let (head, track_list) = content.split_once("<trackList").unwrap_or((&content, ""));
+    let mut xspf = PlaylistFile { name: element(head, "title"), entries: Vec::new() };
+    for track in elements(track_list, "track") {
+        let Some(location) = element(track, "location") else {
+            continue;
+        };
+        let Some(file) = location_to_path(&location) else {
+            continue;
+        };
+        let duration = element(track, "duration")
+            .and_then(|ms| ms.parse::<u64>().ok())
+            .map(Duration::from_millis);
+        let info = EntryInfo {
+            duration,
+            artist: element(track, "creator"),
+            title: element(track, "title"),
+            album: element(track, "album"),
+            group: None,
+        };
+        xspf.entries.push((locator.resolve(&file), info));
+    }
+    Ok(xspf)
+}
+
+/// An XSPF playlist, with locations relative to `relative_to` where given.
+pub fn write(playlist: &PlaylistFile, relative_to: Option<&Path>) -> String {
+    let mut content = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    if let Some(name) = &playlist.name {
+        content.push_str(&format!("  <title>{}</title>\n", escape(name)));
+    }
+    content.push_str("  <trackList>\n");
+    for (path, info) in &playlist.entries {
+        let written = playlist::written_path(path, relative_to);
+        let location = if playlist::is_url(&written) {
+            written
+        } else if Path::new(&written).is_absolute() {
+            format!("file://{}", percent_encode(&written))
+        } else {
+            percent_encode(&written)
+        };
+        content.push_str("    <track>\n");
+        content.push_str(&format!("      <location>{}</location>\n", escape(&location)));
+        let fields = [("title", &info.title), ("creator", &info.artist), ("album", &info.album)];
+        for (tag, value) in fields {
+            if let Some(value) = value {
+                content.push_str(&format!("      <{}>{}</{}>\n", tag, escape(value), tag));
+            }
+        }
+        if let Some(duration) = info.duration {
+            content.push_str(&format!("      <duration>{}</duration>\n", duration.as_millis()));
+        }
+        content.push_str("    </track>\n");
+    }
+    content.push_str("  </trackList>\n</playlist>\n");
+    content
+}
+
+/// The contents of every `<tag>` element in `xml`, still escaped.
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        // Longer names that start the same, like <trackList> for <track>
+        if !after.starts_with(['>', '/', ' ', '\t', '\r', '\n']) {
+            rest = after;
+            continue;
+        }
+        let Some(end_of_tag) = after.find('>') else {
+            break;
+        };
+        if after[..end_of_tag].ends_with('/') {
+            rest = &after[end_of_tag + 1..];
+            continue;
+        }
+        let body = &after[end_of_tag + 1..];
+        let Some(end) = body.find(&close) else {
+            break;
+        };
+        found.push(&body[..end]);
+        rest = &body[end + close.len()..];
+    }
+    found
+}
+
+/// The text of the first `<tag>` in `xml`, if there is any.
+fn element(xml: &str, tag: &str) -> Option<String> {
+    let text = unescape(elements(xml, tag).first()?.trim());
+    Some(text).filter(|t| !t.is_empty())
+}
+
+/// Local file locations as paths; other URLs are kept as they are.
+fn location_to_path(location: &str) -> Option<String> {
+    if let Some(rest) = location.strip_prefix("file://") {
+        // An optional host, normally empty or localhost
+        let path = &rest[rest.find('/')?..];
+        return Some(percent_decode(path));
+    }
+    if playlist::is_url(location) {
+        return Some(location.to_string());
+    }
+    Some(percent_decode(location))
+}
+
+fn unescape(text: &str) -> String {
+    if let Some(data) = text.strip_prefix("<![CDATA[").and_then(|t| t.strip_suffix("]]>")) {
+        return data.to_string();
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        result.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..semi];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(|code| char::from_u32(code.ok()?)),
+        };
+        match decoded {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Escapes everything but unreserved characters and `/`, byte by byte.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn parses_tracks_and_the_playlist_title() {
+        let dir = TempDir::new("xspf-parse");
+        let path = dir.write(
+            "list.xspf",
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <title>Rock &amp; Roll</title>
+  <trackList>
+    <track>
+      <location>file://localhost/music/My%20Song.mp3</location>
+      <title>Caf&#233; &lt;live&gt;</title>
+      <creator><![CDATA[Tom & Jerry]]></creator>
+      <album>Album</album>
+      <duration>61500</duration>
+    </track>
+    <track><location>sub/b%C3%A9.flac</location></track>
+    <track><title>No location</title></track>
+    <track><location/></track>
+    <track><location>http://radio.example/stream?a=1&amp;b=2</location></track>
+  </trackList>
+</playlist>
+"#,
+        );
+        let xspf = parse(&path).unwrap();
+        assert_eq!(xspf.name.as_deref(), Some("Rock & Roll"));
+        assert_eq!(xspf.entries.len(), 3);
+
+        let (song, info) = &xspf.entries[0];
+        assert_eq!(song, "/music/My Song.mp3");
+        assert_eq!(info.title.as_deref(), Some("Caf\u{e9} <live>"));
+        assert_eq!(info.artist.as_deref(), Some("Tom & Jerry"));
+        assert_eq!(info.album.as_deref(), Some("Album"));
+        assert_eq!(info.duration, Some(Duration::from_millis(61_500)));
+
+        let (relative, info) = &xspf.entries[1];
+        assert_eq!(Path::new(relative), dir.path().join("sub/b\u{e9}.flac"));
+        assert_eq!((info.title.as_deref(), info.duration), (None, None));
+
+        assert_eq!(xspf.entries[2].0, "http://radio.example/stream?a=1&b=2");
+    }
+
+    #[test]
+    fn writes_only_what_is_known() {
+        let info = EntryInfo {
+            duration: Some(Duration::from_millis(61_500)),
+            artist: Some("Tom & Jerry".to_string()),
+            title: Some("<Song>".to_string()),
+            ..Default::default()
+        };
+        let playlist = PlaylistFile {
+            name: None,
+            entries: vec![
+                ("/music/My Song.mp3".to_string(), info),
+                ("/music/01 - Song.mp3".to_string(), EntryInfo::default()),
+            ],
+        };
+        let content = write(&playlist, None);
+        assert!(content.contains(
+            "    <track>\n      <location>file:///music/My%20Song.mp3</location>\n      \
+             <title>&lt;Song&gt;</title>\n      <creator>Tom &amp; Jerry</creator>\n      \
+             <duration>61500</duration>\n    </track>\n"
+        ), "{}", content);
+        assert!(content.contains(
+            "    <track>\n      <location>file:///music/01%20-%20Song.mp3</location>\n    </track>\n"
+        ), "{}", content);
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = TempDir::new("xspf-round-trip");
+        let path = dir.write("list.xspf", "");
+        let song = dir.path().join("sub/Caf\u{e9} & co #1.mp3").to_string_lossy().to_string();
+        let stream = "https://radio.example/live?a=1&b=2".to_string();
+        let playlist = PlaylistFile {
+            name: Some("Mix <1>".to_string()),
+            entries: vec![
+                (song.clone(), EntryInfo::from_text(Some(Duration::from_secs(3)), "A - B")),
+                (stream.clone(), EntryInfo::default()),
+            ],
+        };
+        let content = write(&playlist, Some(dir.path()));
+        assert!(content.contains("<location>sub/Caf%C3%A9%20%26%20co%20%231.mp3</location>"), "{}", content);
+
+        fs::write(&path, content).unwrap();
+        let xspf = parse(&path).unwrap();
+        assert_eq!(xspf.name.as_deref(), Some("Mix <1>"));
+        assert_eq!(Path::new(&xspf.entries[0].0), Path::new(&song));
+        assert_eq!(xspf.entries[0].1.display_name().as_deref(), Some("A - B"));
+        assert_eq!(xspf.entries[0].1.duration, Some(Duration::from_secs(3)));
+        assert_eq!(xspf.entries[1].0, stream);
+        assert_eq!(xspf.entries[1].1.display_name(), None);
+    }
+}
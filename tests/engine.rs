@@ -0,0 +1,223 @@
+use rodio::Source;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use tap::audio::{AudioEngine, FadeCurve};
+use tap::decoder::SymphoniaSource;
+use tap::output::null_output;
+use testing::TempDir;
+
+// The unit tests' helpers; not all of them are needed here
+#[path = "../src/testing.rs"]
+#[allow(dead_code)]
+mod testing;
+
+/// A sine tone as a 16-bit WAV in `dir`.
+fn tone(dir: &TempDir, name: &str, secs: f32, rate: u32, channels: u16) -> String {
+    let path: PathBuf = dir.path().join(name);
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate: rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for i in 0..(secs * rate as f32) as u32 {
+        let t = i as f32 / rate as f32;
+        let sample = ((t * 440.0 * std::f32::consts::TAU).sin() * 8000.0) as i16;
+        for _ in 0..channels {
+            writer.write_sample(sample).unwrap();
+        }
+    }
+    writer.finalize().unwrap();
+    path.to_string_lossy().to_string()
+}
+
+fn engine() -> AudioEngine {
+    AudioEngine::with_output(Box::new(null_output()))
+}
+
+/// Polls `done` until it holds or `timeout` runs out.
+fn wait_for(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    done()
+}
+
+fn secs(d: Duration) -> f32 {
+    d.as_secs_f32()
+}
+
+#[test]
+fn advances_to_the_queued_track() {
+    let dir = TempDir::new("engine-advance");
+    let first = tone(&dir, "first.wav", 0.5, 44100, 2);
+    let second = tone(&dir, "second.wav", 0.5, 44100, 2);
+    let engine = engine();
+    engine.play(&first).unwrap();
+    engine.queue_next(&second).unwrap();
+
+    let mut change = None;
+    assert!(wait_for(Duration::from_secs(3), || {
+        change = engine.take_track_change();
+        change.is_some()
+    }));
+    let (path, listened) = change.unwrap();
+    assert_eq!(path, second);
+    assert!((secs(listened) - 0.5).abs() < 0.05, "first track listened for {:?}", listened);
+    assert!(!engine.is_finished());
+
+    assert!(wait_for(Duration::from_secs(3), || engine.is_finished()));
+    assert!(engine.take_track_change().is_none());
+}
+
+#[test]
+fn seeks_within_the_track() {
+    let dir = TempDir::new("engine-seek");
+    let path = tone(&dir, "long.wav", 4.0, 48000, 1);
+    let engine = engine();
+    engine.play(&path).unwrap();
+    engine.seek_to(Duration::from_secs(2));
+
+    assert!(wait_for(Duration::from_secs(1), || engine.get_position() >= Duration::from_secs(2)));
+    thread::sleep(Duration::from_millis(300));
+    let position = secs(engine.get_position());
+    assert!((2.0..2.8).contains(&position), "position {} after seeking to 2s", position);
+    assert_eq!(engine.get_duration(), Some(Duration::from_secs(4)));
+}
+
+#[test]
+fn pause_holds_the_position() {
+    let dir = TempDir::new("engine-pause");
+    let path = tone(&dir, "long.wav", 4.0, 44100, 2);
+    let engine = engine();
+    engine.play(&path).unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    engine.pause();
+    assert!(engine.is_paused());
+    thread::sleep(Duration::from_millis(100));
+    let paused_at = engine.get_position();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get_position(), paused_at);
+    assert!(paused_at > Duration::ZERO);
+    assert!(!engine.is_finished());
+
+    engine.resume();
+    assert!(!engine.is_paused());
+    assert!(wait_for(Duration::from_secs(1), || engine.get_position() > paused_at));
+}
+
+#[test]
+fn meters_follow_the_pause_fade() {
+    let dir = TempDir::new("engine-meters");
+    let path = tone(&dir, "long.wav", 4.0, 44100, 2);
+    let engine = engine();
+    let levels = engine.levels();
+    let peak = || levels.levels()[0].0;
+    engine.play(&path).unwrap();
+    assert!(wait_for(Duration::from_secs(1), || peak() > 0.2));
+
+    engine.pause();
+    assert!(wait_for(Duration::from_secs(2), || peak() < 0.01), "peak stuck at {}", peak());
+    engine.resume();
+    assert!(wait_for(Duration::from_secs(1), || peak() > 0.2));
+}
+
+#[test]
+fn stop_ends_playback() {
+    let dir = TempDir::new("engine-stop");
+    let path = tone(&dir, "long.wav", 4.0, 44100, 2);
+    let engine = engine();
+    engine.play(&path).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    engine.stop();
+    assert!(wait_for(Duration::from_secs(1), || engine.is_finished()));
+    assert_eq!(engine.get_position(), Duration::ZERO);
+    assert_eq!(engine.get_duration(), None);
+
+    // The engine plays again after a stop
+    engine.play(&path).unwrap();
+    assert!(wait_for(Duration::from_secs(1), || engine.get_position() > Duration::ZERO));
+    assert!(!engine.is_finished());
+}
+
+#[test]
+fn converted_source_returns_to_its_own_format() {
+    let dir = TempDir::new("engine-format");
+    let path = tone(&dir, "mono.wav", 1.0, 22050, 1);
+    let mut source = SymphoniaSource::open(&path).unwrap();
+    source.set_output_format(2, 44100);
+    assert_eq!((source.channels(), source.sample_rate()), (2, 44100));
+
+    // A quarter of a second, converted to 44.1 kHz stereo
+    for _ in 0..44100 / 4 * 2 {
+        source.next().unwrap();
+    }
+    let before = source.current_position();
+    assert!((secs(before) - 0.25).abs() < 0.001, "position {:?}", before);
+
+    // What is already converted plays out, then a single break
+    source.restore_format();
+    let converted = source.by_ref().count();
+    assert!(source.take_format_change());
+    assert_eq!((source.channels(), source.sample_rate()), (1, 22050));
+    let after = source.current_position();
+    let expected = secs(before) + converted as f32 / (44100.0 * 2.0);
+    assert!((secs(after) - expected).abs() < 0.001, "position {:?}, expected {}", after, expected);
+
+    let rest = source.by_ref().count();
+    assert!(!source.take_format_change());
+    assert!((secs(after) + rest as f32 / 22050.0 - 1.0).abs() < 0.001);
+}
+
+#[test]
+fn crossfade_into_another_format_keeps_time() {
+    let dir = TempDir::new("engine-crossfade");
+    let first = tone(&dir, "first.wav", 1.0, 44100, 2);
+    let second = tone(&dir, "second.wav", 2.0, 22050, 1);
+    let engine = engine();
+    engine.set_crossfade(Duration::from_millis(300), FadeCurve::Linear);
+    engine.play(&first).unwrap();
+    engine.queue_next(&second).unwrap();
+
+    let mut change = None;
+    assert!(wait_for(Duration::from_secs(3), || {
+        change = engine.take_track_change();
+        change.is_some()
+    }));
+    assert_eq!(change.unwrap().0, second);
+    let started = Instant::now();
+
+    // Well past the fade, by when the second track plays in its own format
+    thread::sleep(Duration::from_millis(800));
+    let position = secs(engine.get_position());
+    let elapsed = started.elapsed().as_secs_f32();
+    assert!((position - elapsed).abs() < 0.1, "position {} after {}s", position, elapsed);
+    assert!(wait_for(Duration::from_secs(3), || engine.is_finished()));
+    let played = started.elapsed().as_secs_f32();
+    assert!((played - 2.0).abs() < 0.2, "second track played for {}s", played);
+}
+
+#[test]
+fn switching_output_while_stopping_stays_stopped() {
+    let dir = TempDir::new("engine-switch");
+    let path = tone(&dir, "long.wav", 4.0, 44100, 2);
+    let mut engine = engine();
+    engine.set_transport_fade(Duration::from_millis(500));
+    engine.play(&path).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    // Still fading out when the output changes
+    engine.stop();
+    engine.set_output(Box::new(null_output())).unwrap();
+    assert!(engine.is_finished());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get_position(), Duration::ZERO);
+}